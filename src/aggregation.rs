@@ -0,0 +1,302 @@
+use std::cmp::Ordering::{self, Greater, Less};
+use std::collections::BTreeMap;
+
+use serde_json::{Number, Value};
+
+use index::Index;
+use object::Object;
+use query::{compare_sort_values, Filter};
+
+/// An aggregate over the objects in a group. `count` counts the objects and
+/// the others fold the values at a JSON Pointer into object data, skipping
+/// objects without one and null values. `sum` and `avg` only take numbers.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Count,
+    Sum(String),
+    Min(String),
+    Max(String),
+    Avg(String),
+}
+
+/// Named aggregations over the objects matching `filter`, grouped by the
+/// value at `group_by` if given or all together otherwise.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationQuery {
+    pub filter: Option<Filter>,
+    pub group_by: Option<String>,
+    pub aggregations: BTreeMap<String, Aggregation>,
+}
+
+/// The aggregations of the objects with the same `key`. Objects without a
+/// value to group by are grouped with the ones with a null value.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Group {
+    pub key: Value,
+    pub aggregations: BTreeMap<String, Value>,
+}
+
+// An aggregation's running state. Objects are dropped once they're added,
+// so only the values kept as extremes are copied.
+enum Accumulator {
+    Count(u64),
+    // The sum as an integer while every value is one, and as a float.
+    Sum(Option<i64>, f64),
+    Min(Option<Value>),
+    Max(Option<Value>),
+    Avg(f64, u64),
+}
+
+use self::Aggregation::*;
+
+impl Aggregation {
+    fn path(&self) -> Option<&str> {
+        match *self {
+            Count => None,
+            Sum(ref path) | Min(ref path) | Max(ref path) | Avg(ref path) => {
+                Some(path)
+            }
+        }
+    }
+
+    fn accumulator(&self) -> Accumulator {
+        match *self {
+            Count => Accumulator::Count(0),
+            Sum(_) => Accumulator::Sum(Some(0), 0.0),
+            Min(_) => Accumulator::Min(None),
+            Max(_) => Accumulator::Max(None),
+            Avg(_) => Accumulator::Avg(0.0, 0),
+        }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, aggregation: &Aggregation, object: &Object) {
+        let value = match aggregation.path() {
+            Some(path) => match object.pointer(path) {
+                Some(Value::Null) | None => return,
+                Some(value) => Some(value),
+            },
+            None => None,
+        };
+
+        match (self, value) {
+            (Accumulator::Count(count), _) => *count += 1,
+            (Accumulator::Sum(sum, float_sum), Some(Value::Number(number))) => {
+                *sum = match (*sum, number.as_i64()) {
+                    (Some(sum), Some(number)) => sum.checked_add(number),
+                    _ => None,
+                };
+                *float_sum += number.as_f64().unwrap_or(0.0);
+            }
+            (Accumulator::Min(min), Some(value)) => {
+                keep_extreme(min, value, Less)
+            }
+            (Accumulator::Max(max), Some(value)) => {
+                keep_extreme(max, value, Greater)
+            }
+            (Accumulator::Avg(sum, count), Some(Value::Number(number))) => {
+                *sum += number.as_f64().unwrap_or(0.0);
+                *count += 1;
+            }
+            _ => (),
+        }
+    }
+
+    fn result(&self) -> Value {
+        match *self {
+            Accumulator::Count(count) => json!(count),
+            Accumulator::Sum(Some(sum), _) => json!(sum),
+            Accumulator::Sum(None, float_sum) => float_value(float_sum),
+            Accumulator::Min(ref value) | Accumulator::Max(ref value) => {
+                value.to_owned().unwrap_or(Value::Null)
+            }
+            Accumulator::Avg(_, 0) => Value::Null,
+            Accumulator::Avg(sum, count) => float_value(sum / count as f64),
+        }
+    }
+}
+
+impl AggregationQuery {
+    /// Checks that every path in the aggregation is a JSON Pointer.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut paths: Vec<&str> = Vec::new();
+
+        if let Some(ref filter) = self.filter {
+            paths.extend(filter.paths());
+        }
+
+        if let Some(ref group_by) = self.group_by {
+            paths.push(group_by);
+        }
+
+        paths.extend(
+            self.aggregations
+                .values()
+                .filter_map(Aggregation::path),
+        );
+
+        match paths.iter().find(|path| !path.starts_with('/')) {
+            Some(path) => Err(format!("{} isn't a JSON Pointer", path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Folds `objects` into groups ordered by key, in a single pass. Without
+    /// `group_by` there's a single group, even if there are no objects.
+    pub fn aggregate<I>(&self, objects: I) -> Vec<Group>
+    where
+        I: Iterator<Item = Object>,
+    {
+        let mut groups = BTreeMap::new();
+
+        if self.group_by.is_none() {
+            groups.insert("null".to_owned(), (None, self.accumulators()));
+        }
+
+        for object in objects {
+            let key = match self.group_by {
+                Some(ref group_by) => object.pointer(group_by),
+                None => None,
+            };
+            let (_key, accumulators) = groups
+                .entry(key.map_or("null".to_owned(), Index::key))
+                .or_insert_with(|| (key.cloned(), self.accumulators()));
+
+            for (accumulator, aggregation) in
+                accumulators.iter_mut().zip(self.aggregations.values())
+            {
+                accumulator.add(aggregation, &object);
+            }
+        }
+
+        let mut groups: Vec<Group> = groups
+            .values()
+            .map(|(key, accumulators)| Group {
+                key: key.to_owned().unwrap_or(Value::Null),
+                aggregations: self
+                    .aggregations
+                    .keys()
+                    .cloned()
+                    .zip(accumulators.iter().map(Accumulator::result))
+                    .collect(),
+            })
+            .collect();
+
+        groups
+            .sort_by(|a, b| compare_sort_values(Some(&a.key), Some(&b.key)));
+
+        groups
+    }
+
+    fn accumulators(&self) -> Vec<Accumulator> {
+        self.aggregations
+            .values()
+            .map(Aggregation::accumulator)
+            .collect()
+    }
+}
+
+// Replaces `current` with `value` if it comes first in `ordering`, keeping
+// `current` on ties.
+fn keep_extreme(
+    current: &mut Option<Value>,
+    value: &Value,
+    ordering: Ordering,
+) {
+    let replaces = match *current {
+        Some(ref current) => {
+            compare_sort_values(Some(value), Some(current)) == ordering
+        }
+        None => true,
+    };
+
+    if replaces {
+        *current = Some(value.to_owned());
+    }
+}
+
+fn float_value(float: f64) -> Value {
+    Number::from_f64(float)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use aggregation::*;
+
+    fn aggregate(aggregation: Value) -> Vec<Group> {
+        let aggregation: AggregationQuery =
+            serde_json::from_value(aggregation).unwrap();
+        let objects: Vec<Object> = vec![
+            json!({ "city": "Porto", "age": 42, "score": 1.5 }),
+            json!({ "city": "Faro", "age": 17 }),
+            json!({ "city": "Porto", "age": 30, "score": 2 }),
+            json!({ "age": null, "score": "n/a" }),
+        ].into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let data = serde_json::from_value(data).unwrap();
+                Object::make(&index.to_string(), data)
+            })
+            .collect();
+
+        aggregation.aggregate(objects.into_iter())
+    }
+
+    #[test]
+    fn test_aggregate() {
+        assert_eq!(
+            aggregate(json!({
+                "aggregations": {
+                    "people": "count",
+                    "total_age": { "sum": "/age" },
+                    "total_score": { "sum": "/score" },
+                    "youngest": { "min": "/age" },
+                    "oldest": { "max": "/age" },
+                    "average_score": { "avg": "/score" },
+                    "average_height": { "avg": "/height" }
+                }
+            })),
+            vec![Group {
+                key: Value::Null,
+                aggregations: serde_json::from_value(json!({
+                    "people": 4,
+                    "total_age": 89,
+                    "total_score": 3.5,
+                    "youngest": 17,
+                    "oldest": 42,
+                    "average_score": 1.75,
+                    "average_height": null
+                })).unwrap(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_groups() {
+        let groups = aggregate(json!({
+            "filter": { "exists": { "path": "/age" } },
+            "group_by": "/city",
+            "aggregations": { "people": "count", "age": { "avg": "/age" } }
+        }));
+
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.key.to_owned(), json!(group.aggregations)))
+                .collect::<Vec<(Value, Value)>>(),
+            vec![
+                (json!(null), json!({ "people": 1, "age": null })),
+                (json!("Faro"), json!({ "people": 1, "age": 17.0 })),
+                (json!("Porto"), json!({ "people": 2, "age": 36.0 })),
+            ]
+        );
+        assert_eq!(aggregate(json!({ "group_by": "/city" })).len(), 3);
+    }
+}
@@ -22,11 +22,25 @@ pub struct Configuration {
     pub cluster_peers: Vec<String>,
     pub http_resources_port: u32,
     pub http_resources_workers: usize,
+    pub http_resources_default_page_size: usize,
+    pub http_resources_max_page_size: usize,
+    pub http_resources_max_bulk_size: usize,
+    pub http_resources_max_bulk_body_size: usize,
     pub http_transport_port: u32,
     pub http_transport_workers: usize,
     pub http_transport_pinger_connect_timeout: u64,
     pub http_transport_pinger_schedule: u64,
     pub log_config_file: String,
+    pub storage_directory: String,
+    pub storage_engine: String,
+    pub storage_engine_memory_budget: usize,
+    pub storage_memory_limit: usize,
+    pub storage_wal_fsync: String,
+    pub storage_wal_fsync_interval: u64,
+    pub storage_snapshot_schedule: u64,
+    pub storage_reaper_schedule: u64,
+    pub storage_compaction_schedule: u64,
+    pub storage_compaction_throughput: u64,
 }
 
 impl Configuration {
@@ -1,19 +1,37 @@
+use std::cmp;
 use std::net::SocketAddr;
 use std::str;
 use std::thread::{self, JoinHandle};
 
 use actix_web::{
-    http::{self, StatusCode},
-    server, App, Error, HttpRequest, HttpResponse,
+    error::{self, ParseError},
+    http::{
+        self,
+        header::{self, Header},
+        StatusCode,
+    },
+    server, App, Error, HttpMessage, HttpRequest, HttpResponse,
 };
+use bytes::Bytes;
+use futures::{stream, Stream};
 use serde_json;
 
+use aggregation::AggregationQuery;
 use components::configuration::Configuration;
-use http_utils::{json_body, make_handler_for_request_with_body};
-use keyspace::Keyspace;
+use http_utils::{
+    json_body, json_error, json_error_message,
+    make_handler_for_request_with_body, make_handler_for_request_with_raw_body,
+};
+use index::Index;
+use keyspace::{AsOf, Keyspace, KeyspaceSettings};
 use object::{Object, ObjectData};
-use storage::Outcome::*;
-use utils::make_id_string;
+use patch::{Patch, PatchOperation};
+use query::Query;
+use range::Range;
+use storage::Outcome::{self, *};
+use storage::Precondition::{self, *};
+use storage::{self, Storage, TransactionOperation};
+use utils::{make_id_string, now_millis};
 
 use cluster::Cluster;
 
@@ -27,6 +45,398 @@ fn cluster_information(cluster: &Cluster) -> serde_json::Value {
     })
 }
 
+static TTL_HEADER: &str = "x-ttl";
+
+// TTLs are given in seconds, either in the `ttl` query parameter or in the
+// `X-TTL` header.
+fn ttl(request: &HttpRequest) -> Result<Option<u64>, String> {
+    let ttl = match request.query().get("ttl") {
+        Some(ttl) => Some(ttl.to_owned()),
+        None => match request.headers().get(TTL_HEADER) {
+            Some(ttl) => match ttl.to_str() {
+                Ok(ttl) => Some(ttl.to_owned()),
+                Err(_error) => return Err("invalid ttl".to_owned()),
+            },
+            None => None,
+        },
+    };
+
+    match ttl {
+        Some(ttl) => match ttl.trim().parse::<u64>() {
+            Ok(ttl) if is_valid_ttl(ttl, now_millis()) => Ok(Some(ttl)),
+            Ok(_ttl) => Err(TTL_TOO_LARGE.to_owned()),
+            Err(_error) => {
+                Err("ttl must be a non-negative integer of seconds".to_owned())
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+static TTL_TOO_LARGE: &str = "ttl is too large";
+
+// TTLs are valid as long as the time objects expire at, in milliseconds,
+// doesn't overflow.
+fn is_valid_ttl(ttl: u64, now: u64) -> bool {
+    ttl.checked_mul(1000)
+        .and_then(|ttl| now.checked_add(ttl))
+        .is_some()
+}
+
+fn expires_at(ttl: u64) -> u64 {
+    now_millis().saturating_add(ttl.saturating_mul(1000))
+}
+
+// Objects are read as of a timestamp in milliseconds since the Unix epoch or,
+// prefixed with `seq:`, the sequence of an operation.
+fn as_of(request: &HttpRequest) -> Result<Option<AsOf>, String> {
+    let as_of = match request.query().get("as_of") {
+        Some(as_of) => as_of.trim().to_owned(),
+        None => return Ok(None),
+    };
+    let parsed = match as_of.split(':').collect::<Vec<&str>>()[..] {
+        ["seq", sequence] => sequence.parse().map(AsOf::Sequence),
+        _ => as_of.parse().map(AsOf::Timestamp),
+    };
+
+    match parsed {
+        Ok(as_of) => Ok(Some(as_of)),
+        Err(_error) => Err(
+            "as_of must be a timestamp in milliseconds or seq:<sequence>"
+                .to_owned(),
+        ),
+    }
+}
+
+static RESERVED_NAME: &str = "names starting with an underscore are reserved";
+
+// Routes like `_objects` or `_query` sit next to keyspaces and objects, so
+// keyspaces and objects named like them couldn't be read back.
+fn is_reserved_name(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+static CLIENT_IDS_REQUIRED: &str = "keyspace requires client-supplied IDs";
+
+fn requires_client_ids(storage: &Storage, keyspace_name: &str) -> bool {
+    match storage.read_keyspace(keyspace_name.to_owned()) {
+        Ok(KeyspaceFound(keyspace)) => keyspace.settings().require_client_ids,
+        _ => false,
+    }
+}
+
+fn object_etag(object: &Object) -> header::ETag {
+    header::ETag(header::EntityTag::strong(object.version().to_string()))
+}
+
+fn versions(tags: &[header::EntityTag], weak: bool) -> Vec<u64> {
+    tags.iter()
+        .filter(|tag| weak || !tag.weak)
+        .filter_map(|tag| tag.tag().parse().ok())
+        .collect()
+}
+
+// If-Match uses the strong comparison function and If-None-Match the weak one
+// (RFC 7232, section 3).
+fn preconditions(
+    request: &HttpRequest,
+) -> Result<Vec<Precondition>, ParseError> {
+    let mut preconditions = Vec::new();
+
+    if request.headers().contains_key(header::IF_MATCH) {
+        preconditions.push(match header::IfMatch::parse(request)? {
+            header::IfMatch::Any => Exists,
+            header::IfMatch::Items(tags) => {
+                VersionMatches(versions(&tags, false))
+            }
+        });
+    }
+
+    if request.headers().contains_key(header::IF_NONE_MATCH) {
+        preconditions.push(match header::IfNoneMatch::parse(request)? {
+            header::IfNoneMatch::Any => DoesNotExist,
+            header::IfNoneMatch::Items(tags) => {
+                VersionDoesNotMatch(versions(&tags, true))
+            }
+        });
+    }
+
+    Ok(preconditions)
+}
+
+/// A transaction operation as sent to `POST /_txn`. `exists` and `version`
+/// are the body counterparts of the `If-Match` and `If-None-Match` headers.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TransactionRequestOperation {
+    Check {
+        keyspace: String,
+        id: String,
+        exists: Option<bool>,
+        version: Option<u64>,
+    },
+    Put {
+        keyspace: String,
+        id: String,
+        data: ObjectData,
+        exists: Option<bool>,
+        version: Option<u64>,
+    },
+    Delete {
+        keyspace: String,
+        id: String,
+        exists: Option<bool>,
+        version: Option<u64>,
+    },
+}
+
+/// An index definition as sent to `PUT /{keyspace}/_index/{index}`. `path`
+/// is a JSON Pointer into object data.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndexRequest {
+    path: String,
+    #[serde(default)]
+    unique: bool,
+}
+
+/// An increment as sent to `POST /{keyspace}/{object_id}/_incr`. `path` is a
+/// JSON Pointer into object data.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncrementRequest {
+    path: String,
+    delta: serde_json::Number,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultiGetRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionRequest {
+    operations: Vec<TransactionRequestOperation>,
+}
+
+fn body_preconditions(
+    exists: Option<bool>,
+    version: Option<u64>,
+) -> Vec<Precondition> {
+    let mut preconditions = Vec::new();
+
+    match exists {
+        Some(true) => preconditions.push(Exists),
+        Some(false) => preconditions.push(DoesNotExist),
+        None => (),
+    }
+
+    if let Some(version) = version {
+        preconditions.push(VersionMatches(vec![version]));
+    }
+
+    preconditions
+}
+
+impl From<TransactionRequestOperation> for TransactionOperation {
+    fn from(operation: TransactionRequestOperation) -> TransactionOperation {
+        match operation {
+            TransactionRequestOperation::Check {
+                keyspace,
+                id,
+                exists,
+                version,
+            } => TransactionOperation::Check {
+                keyspace_name: keyspace,
+                object_id: id,
+                preconditions: body_preconditions(exists, version),
+            },
+            TransactionRequestOperation::Put {
+                keyspace,
+                id,
+                data,
+                exists,
+                version,
+            } => TransactionOperation::CreateOrUpdate {
+                keyspace_name: keyspace,
+                object: Object::make(&id, data),
+                preconditions: body_preconditions(exists, version),
+            },
+            TransactionRequestOperation::Delete {
+                keyspace,
+                id,
+                exists,
+                version,
+            } => TransactionOperation::Delete {
+                keyspace_name: keyspace,
+                object_id: id,
+                preconditions: body_preconditions(exists, version),
+            },
+        }
+    }
+}
+
+fn transaction_result(outcome: &Outcome) -> serde_json::Value {
+    match *outcome {
+        KeyspaceObjectFound(ref object)
+        | KeyspaceObjectCreated(ref object)
+        | KeyspaceObjectUpdated(ref object)
+        | KeyspaceObjectDeleted(ref object) => json!({
+            "object": object.data,
+            "version": object.version(),
+        }),
+        _ => json!({ "object": null, "version": null }),
+    }
+}
+
+/// An item of a `POST /{keyspace}/_bulk` request, either an element of a JSON
+/// array or a line of NDJSON.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BulkRequestOperation {
+    Put {
+        id: Option<String>,
+        data: ObjectData,
+        ttl: Option<u64>,
+    },
+    Delete { id: String },
+}
+
+// Parses the operations of a bulk request, returning the response to give
+// if they can't be parsed, there are too many of them or any has a TTL that
+// is too large or puts an object with a reserved ID.
+fn bulk_operations(
+    content_type: &str,
+    body: &[u8],
+    max_bulk_size: usize,
+) -> Result<Vec<BulkRequestOperation>, HttpResponse> {
+    let operations: Result<Vec<BulkRequestOperation>, serde_json::Error> =
+        if content_type == "application/x-ndjson" {
+            body.split(|byte| *byte == b'\n')
+                .filter(|line| {
+                    line.iter().any(|byte| !byte.is_ascii_whitespace())
+                })
+                .map(serde_json::from_slice)
+                .collect()
+        } else {
+            serde_json::from_slice(body)
+        };
+
+    let now = now_millis();
+
+    match operations {
+        Ok(ref operations) if operations.len() > max_bulk_size => {
+            Err(HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                .content_type("application/json")
+                .body(json_error_message(&format!(
+                    "bulk requests can have at most {} operations",
+                    max_bulk_size
+                ))))
+        }
+        Ok(ref operations)
+            if operations.iter().any(|operation| match *operation {
+                BulkRequestOperation::Put { ttl: Some(ttl), .. } => {
+                    !is_valid_ttl(ttl, now)
+                }
+                _ => false,
+            }) =>
+        {
+            Err(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message(TTL_TOO_LARGE)))
+        }
+        Ok(ref operations)
+            if operations.iter().any(|operation| match *operation {
+                BulkRequestOperation::Put { id: Some(ref id), .. } => {
+                    is_reserved_name(id)
+                }
+                _ => false,
+            }) =>
+        {
+            Err(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message(RESERVED_NAME)))
+        }
+        Ok(operations) => Ok(operations),
+        Err(error) => Err(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error(error))),
+    }
+}
+
+// The result of each operation of a bulk request, in order. Operations
+// without an object ID (`None`) weren't applied, and the others get the
+// status of their outcome in the batch.
+fn bulk_results(
+    object_ids: Vec<Option<String>>,
+    outcome: &Result<Outcome, storage::Error>,
+) -> Vec<serde_json::Value> {
+    let status_codes: Vec<StatusCode> = match *outcome {
+        Ok(BatchApplied(ref outcomes)) => {
+            outcomes.iter().map(outcome_status_code).collect()
+        }
+        Ok(ref outcome) => vec![outcome_status_code(outcome); object_ids.len()],
+        Err(_) => vec![StatusCode::INTERNAL_SERVER_ERROR; object_ids.len()],
+    };
+    let mut status_codes = status_codes.into_iter();
+
+    object_ids
+        .into_iter()
+        .map(|object_id| match object_id {
+            Some(object_id) => {
+                let status_code = status_codes
+                    .next()
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+                json!({
+                    "id": object_id,
+                    "status": status_code.as_u16(),
+                })
+            }
+            None => json!({
+                "id": null,
+                "status": StatusCode::BAD_REQUEST.as_u16(),
+                "error": CLIENT_IDS_REQUIRED,
+            }),
+        })
+        .collect()
+}
+
+fn index_conflict_message(index_name: &str) -> String {
+    json_error_message(&format!(
+        "another object has the same value for unique index {}",
+        index_name
+    ))
+}
+
+fn index_information(index_name: &str, index: &Index) -> serde_json::Value {
+    json!({
+        "name": index_name,
+        "path": index.path,
+        "unique": index.unique,
+    })
+}
+
+fn outcome_status_code(outcome: &Outcome) -> StatusCode {
+    match *outcome {
+        KeyspaceObjectCreated(_) => StatusCode::CREATED,
+        KeyspaceObjectUpdated(_)
+        | KeyspaceObjectFound(_)
+        | KeyspaceObjectDeleted(_) => StatusCode::OK,
+        KeyspaceNotFound(_)
+        | KeyspaceObjectNotFound(_)
+        | KeyspaceObjectExpired(_) => StatusCode::NOT_FOUND,
+        KeyspaceObjectPreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+        KeyspaceObjectPatchFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        KeyspaceObjectTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        KeyspaceFull(_) | MemoryLimitReached(_) => {
+            StatusCode::INSUFFICIENT_STORAGE
+        }
+        KeyspaceIndexConflict(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 fn handle_cluster_information(
     _request: &HttpRequest,
 ) -> Result<HttpResponse, Error> {
@@ -51,15 +461,85 @@ fn handle_cluster_state(_request: &HttpRequest) -> Result<HttpResponse, Error> {
     }
 }
 
+fn handle_list_keyspaces(
+    _request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let storage = components::storage::STATE.read().unwrap();
+
+    match storage.list_keyspaces() {
+        Ok(outcome) => match outcome {
+            KeyspacesListed(keyspaces) => {
+                let keyspaces: Vec<serde_json::Value> = keyspaces
+                    .iter()
+                    .map(|(metadata, stats)| {
+                        let mut keyspace = json!(metadata);
+                        keyspace["stats"] = json!(stats);
+                        keyspace
+                    })
+                    .collect();
+                let response_body = json!({ "keyspaces": keyspaces });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+// Keyspaces can be created with a settings body. Settings of existing
+// keyspaces are replaced by the ones in the body, if any.
 fn handle_create_or_update_keyspace(
     request: &HttpRequest,
+    body: serde_json::Value,
 ) -> Result<HttpResponse, Error> {
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+
+    if is_reserved_name(&keyspace_name) {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message(RESERVED_NAME)));
+    }
+
+    let settings: Option<KeyspaceSettings> = if body.is_null() {
+        None
+    } else {
+        match serde_json::from_value(body) {
+            Ok(settings) => Some(settings),
+            Err(error) => {
+                return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                    .content_type("application/json")
+                    .body(json_error(error)))
+            }
+        }
+    };
+
     let mut storage = components::storage::STATE.write().unwrap();
 
-    let keyspace_name: String = request.match_info().query("keyspace")?;
-    let keyspace = Keyspace::make(&keyspace_name);
+    let existing_keyspace = storage.read_keyspace(keyspace_name.clone());
 
-    match storage.create_or_update_keyspace(keyspace) {
+    let result = match (settings, existing_keyspace) {
+        (Some(settings), Ok(KeyspaceFound(_keyspace))) => {
+            storage.update_keyspace_settings(keyspace_name, settings)
+        }
+        (Some(settings), _) => {
+            let mut keyspace = Keyspace::make(&keyspace_name);
+            keyspace.set_settings(&settings);
+            storage.create_or_update_keyspace(keyspace)
+        }
+        (None, _) => {
+            storage.create_or_update_keyspace(Keyspace::make(&keyspace_name))
+        }
+    };
+
+    match result {
         Ok(outcome) => match outcome {
             KeyspaceCreated(keyspace) => {
                 let response_body = json!(keyspace.metadata);
@@ -87,7 +567,7 @@ fn handle_create_or_update_keyspace(
 }
 
 fn handle_get_keyspace(request: &HttpRequest) -> Result<HttpResponse, Error> {
-    let mut storage = components::storage::STATE.write().unwrap();
+    let storage = components::storage::STATE.read().unwrap();
 
     let keyspace_name: String = request.match_info().query("keyspace")?;
 
@@ -120,25 +600,72 @@ fn handle_create_or_update_keyspace_object(
     request: &HttpRequest,
     body: serde_json::Value,
 ) -> Result<HttpResponse, Error> {
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let object_id = match request.match_info().get("object_id") {
+        Some(object_id) if is_reserved_name(object_id) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message(RESERVED_NAME)))
+        }
+        object_id => object_id.map(str::to_owned),
+    };
+    let object_data: ObjectData = match serde_json::from_value(body) {
+        Ok(object_data) => object_data,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+    let ttl = match ttl(request) {
+        Ok(ttl) => ttl,
+        Err(message) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message(&message)))
+        }
+    };
+    let preconditions = match preconditions(request) {
+        Ok(preconditions) => preconditions,
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("invalid precondition headers")))
+        }
+    };
+
     let mut storage = components::storage::STATE.write().unwrap();
 
-    let keyspace_name: String = request.match_info().query("keyspace")?;
-    let object_id: String = request
-        .match_info()
-        .get("object_id")
-        .map(str::to_string)
-        .or(Some(make_id_string()))
-        .unwrap();
-    let object_data: ObjectData = serde_json::from_value(body).unwrap();
-    let object = Object::make(&object_id, object_data);
+    let object_id = match object_id {
+        Some(object_id) => object_id,
+        None => {
+            if requires_client_ids(&storage, &keyspace_name) {
+                return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                    .content_type("application/json")
+                    .body(json_error_message(CLIENT_IDS_REQUIRED)));
+            }
+
+            make_id_string()
+        }
+    };
+    let mut object = Object::make(&object_id, object_data);
 
-    match storage.create_or_update_keyspace_object(keyspace_name, object) {
+    if let Some(ttl) = ttl {
+        object.set_expires_at(expires_at(ttl));
+    }
+
+    match storage.create_or_update_keyspace_object(
+        keyspace_name,
+        object,
+        preconditions,
+    ) {
         Ok(outcome) => match outcome {
             KeyspaceObjectCreated(object) => {
                 let response_body = json!(object.data);
 
                 Ok(HttpResponse::build(StatusCode::CREATED)
                     .content_type("application/json")
+                    .set(object_etag(&object))
                     .body(json_body(&response_body)))
             }
             KeyspaceObjectUpdated(object) => {
@@ -146,6 +673,7 @@ fn handle_create_or_update_keyspace_object(
 
                 Ok(HttpResponse::build(StatusCode::OK)
                     .content_type("application/json")
+                    .set(object_etag(&object))
                     .body(json_body(&response_body)))
             }
             KeyspaceNotFound(_keyspace_name) => {
@@ -153,6 +681,31 @@ fn handle_create_or_update_keyspace_object(
                     .content_type("application/json")
                     .finish())
             }
+            KeyspaceObjectPreconditionFailed(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PRECONDITION_FAILED)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectTooLarge(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                    .content_type("application/json")
+                    .body(json_error_message("object is too large")))
+            }
+            KeyspaceFull(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("keyspace is full")))
+            }
+            MemoryLimitReached(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("memory limit reached")))
+            }
+            KeyspaceIndexConflict(index_name) => {
+                Ok(HttpResponse::build(StatusCode::CONFLICT)
+                    .content_type("application/json")
+                    .body(index_conflict_message(&index_name)))
+            }
             _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
                 .content_type("application/json")
                 .finish()),
@@ -164,11 +717,123 @@ fn handle_create_or_update_keyspace_object(
     }
 }
 
-fn handle_get_keyspace_object(
+fn handle_patch_keyspace_object(
     request: &HttpRequest,
+    body: serde_json::Value,
 ) -> Result<HttpResponse, Error> {
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let object_id: String = request.match_info().query("object_id")?;
+    let patch = match request.content_type() {
+        "application/merge-patch+json" if body.is_object() => {
+            Patch::MergePatch(body)
+        }
+        "application/merge-patch+json" => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("merge patch must be an object")))
+        }
+        "application/json-patch+json" => {
+            match serde_json::from_value::<Vec<PatchOperation>>(body) {
+                Ok(operations) => Patch::JsonPatch(operations),
+                Err(error) => {
+                    return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                        .content_type("application/json")
+                        .body(json_error(error)))
+                }
+            }
+        }
+        _ => {
+            return Ok(HttpResponse::build(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ).content_type("application/json")
+                .body(json_error_message(
+                    "content type must be application/merge-patch+json or \
+                     application/json-patch+json",
+                )))
+        }
+    };
+    let preconditions = match preconditions(request) {
+        Ok(preconditions) => preconditions,
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("invalid precondition headers")))
+        }
+    };
+
     let mut storage = components::storage::STATE.write().unwrap();
 
+    match storage.patch_keyspace_object(
+        keyspace_name,
+        object_id,
+        patch,
+        preconditions,
+    ) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectUpdated(object) => {
+                let response_body = json!(object.data);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .set(object_etag(&object))
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectNotFound(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectPreconditionFailed(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PRECONDITION_FAILED)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectTooLarge(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                    .content_type("application/json")
+                    .body(json_error_message("object is too large")))
+            }
+            KeyspaceFull(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("keyspace is full")))
+            }
+            MemoryLimitReached(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("memory limit reached")))
+            }
+            KeyspaceIndexConflict(index_name) => {
+                Ok(HttpResponse::build(StatusCode::CONFLICT)
+                    .content_type("application/json")
+                    .body(index_conflict_message(&index_name)))
+            }
+            KeyspaceObjectPatchFailed(error) => {
+                Ok(HttpResponse::build(StatusCode::UNPROCESSABLE_ENTITY)
+                    .content_type("application/json")
+                    .body(json_error_message(&format!("{:?}", error))))
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_get_keyspace_object(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let storage = components::storage::STATE.read().unwrap();
+
     let keyspace_name: String = request.match_info().query("keyspace")?;
     let object_id: String = request
         .match_info()
@@ -176,14 +841,26 @@ fn handle_get_keyspace_object(
         .map(str::to_string)
         .or(Some(make_id_string()))
         .unwrap();
+    let outcome = match as_of(request) {
+        Ok(Some(as_of)) => {
+            storage.read_keyspace_object_as_of(keyspace_name, object_id, as_of)
+        }
+        Ok(None) => storage.read_keyspace_object(keyspace_name, object_id),
+        Err(message) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message(&message)))
+        }
+    };
 
-    match storage.read_keyspace_object(keyspace_name, object_id) {
+    match outcome {
         Ok(outcome) => match outcome {
             KeyspaceObjectFound(object) => {
                 let response_body = json!(object.data);
 
                 Ok(HttpResponse::build(StatusCode::OK)
                     .content_type("application/json")
+                    .set(object_etag(&object))
                     .body(json_body(&response_body)))
             }
             KeyspaceNotFound(_keyspace_name) => {
@@ -207,53 +884,1189 @@ fn handle_get_keyspace_object(
     }
 }
 
-pub fn application() -> App {
-    App::new()
-        .resource("/", |r| {
-            r.method(http::Method::GET).f(handle_cluster_information)
-        })
-        .scope("/_cluster/", |s| {
-            s.resource("state", |r| {
-                r.method(http::Method::GET).f(handle_cluster_state)
-            })
-        })
-        .resource("/{keyspace}", |r| {
-            r.method(http::Method::PUT)
-                .f(handle_create_or_update_keyspace);
-            r.method(http::Method::GET).f(handle_get_keyspace);
-            r.method(http::Method::POST).with(|request: HttpRequest| {
-                make_handler_for_request_with_body(
-                    &handle_create_or_update_keyspace_object,
-                )(request)
-            });
-        })
-        .resource("/{keyspace}/{object_id}", |r| {
-            r.method(http::Method::PUT).with(|request: HttpRequest| {
-                make_handler_for_request_with_body(
-                    &handle_create_or_update_keyspace_object,
-                )(request)
-            });
-            r.method(http::Method::GET).f(handle_get_keyspace_object);
-        })
-}
+fn handle_increment_keyspace_object(
+    request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let object_id: String = request.match_info().query("object_id")?;
 
-pub fn start() -> () {
-    let c = Configuration::read();
+    if is_reserved_name(&object_id) {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message(RESERVED_NAME)));
+    }
 
-    let http_resources_address: SocketAddr =
-        format!("{}:{}", c.bind_host, c.http_resources_port)
-            .parse()
-            .unwrap();
+    let increment: IncrementRequest = match serde_json::from_value(body) {
+        Ok(increment) => increment,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+    let preconditions = match preconditions(request) {
+        Ok(preconditions) => preconditions,
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("invalid precondition headers")))
+        }
+    };
 
-    let http_resources_server = server::new(application)
-        .workers(c.http_resources_workers)
-        .bind(http_resources_address)
-        .unwrap();
+    let mut storage = components::storage::STATE.write().unwrap();
 
-    info!("Starting resources server");
-    http_resources_server.run()
-}
+    match storage.increment_keyspace_object(
+        keyspace_name,
+        object_id,
+        increment.path,
+        increment.delta,
+        preconditions,
+    ) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectCreated(object) => {
+                let response_body = json!(object.data);
 
-pub fn spawn() -> JoinHandle<()> {
+                Ok(HttpResponse::build(StatusCode::CREATED)
+                    .content_type("application/json")
+                    .set(object_etag(&object))
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceObjectUpdated(object) => {
+                let response_body = json!(object.data);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .set(object_etag(&object))
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectPreconditionFailed(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PRECONDITION_FAILED)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectTooLarge(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                    .content_type("application/json")
+                    .body(json_error_message("object is too large")))
+            }
+            KeyspaceFull(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("keyspace is full")))
+            }
+            MemoryLimitReached(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE)
+                    .content_type("application/json")
+                    .body(json_error_message("memory limit reached")))
+            }
+            KeyspaceIndexConflict(index_name) => {
+                Ok(HttpResponse::build(StatusCode::CONFLICT)
+                    .content_type("application/json")
+                    .body(index_conflict_message(&index_name)))
+            }
+            KeyspaceObjectPatchFailed(error) => {
+                Ok(HttpResponse::build(StatusCode::UNPROCESSABLE_ENTITY)
+                    .content_type("application/json")
+                    .body(json_error_message(&format!("{:?}", error))))
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_get_keyspace_object_history(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let object_id: String = request.match_info().query("object_id")?;
+
+    match storage.read_keyspace_object_history(keyspace_name, object_id) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectHistoryFound(revisions) => {
+                let versions: Vec<serde_json::Value> = revisions
+                    .iter()
+                    .map(|revision| {
+                        json!({
+                            "version": revision.sequence,
+                            "timestamp": revision.timestamp,
+                            "deleted": revision.object.is_none(),
+                            "object": revision
+                                .object
+                                .as_ref()
+                                .map(|object| &object.data),
+                        })
+                    })
+                    .collect();
+                let response_body = json!({ "versions": versions });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectNotFound(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_multi_get_keyspace_objects(
+    request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let c = Configuration::read();
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let multi_get: MultiGetRequest = match serde_json::from_value(body) {
+        Ok(multi_get) => multi_get,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+
+    if multi_get.ids.len() > c.http_resources_max_bulk_size {
+        return Ok(HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+            .content_type("application/json")
+            .body(json_error_message(&format!(
+                "multi-get requests can have at most {} IDs",
+                c.http_resources_max_bulk_size
+            ))));
+    }
+
+    match storage.read_keyspace_objects(keyspace_name, multi_get.ids) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectsRead(objects, missing_object_ids) => {
+                let objects: Vec<&ObjectData> =
+                    objects.iter().map(|object| &object.data).collect();
+                let response_body = json!({
+                    "objects": objects,
+                    "missing": missing_object_ids,
+                });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_list_keyspace_objects(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let c = Configuration::read();
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let after = request.query().get("after").cloned();
+    let limit = match request.query().get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => {
+                cmp::min(limit, c.http_resources_max_page_size)
+            }
+            _ => {
+                return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                    .content_type("application/json")
+                    .body(json_error_message(
+                        "limit must be a positive integer",
+                    )))
+            }
+        },
+        None => c.http_resources_default_page_size,
+    };
+    let reverse = match request.query().get("reverse").map(String::as_str) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("reverse must be true or false")))
+        }
+    };
+    let range = Range {
+        start: request.query().get("start").cloned(),
+        end: request.query().get("end").cloned(),
+        prefix: request.query().get("prefix").cloned(),
+        reverse,
+    };
+
+    match storage.scan_keyspace_objects(keyspace_name, &range, after, limit) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectsFound(objects, next_cursor) => {
+                let objects: Vec<&ObjectData> =
+                    objects.iter().map(|object| &object.data).collect();
+                let response_body = json!({
+                    "objects": objects,
+                    "next_cursor": next_cursor,
+                });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+// Queries return at most the configured default page size of objects unless
+// they have a limit, which is capped at the maximum page size.
+fn handle_query_keyspace_objects(
+    request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let c = Configuration::read();
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let mut query: Query = match serde_json::from_value(body) {
+        Ok(query) => query,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+
+    if let Err(message) = query.validate() {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message(&message)));
+    }
+
+    query.limit = match query.limit {
+        Some(0) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("limit must be a positive integer")))
+        }
+        Some(limit) => Some(cmp::min(limit, c.http_resources_max_page_size)),
+        None => Some(c.http_resources_default_page_size),
+    };
+
+    match storage.query_keyspace_objects(keyspace_name, &query) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectsQueried(objects, index_name) => {
+                let response_body = json!({
+                    "objects": objects,
+                    "full_scan": index_name.is_none(),
+                    "index": index_name,
+                });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_aggregate_keyspace_objects(
+    request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let aggregation: AggregationQuery = match serde_json::from_value(body) {
+        Ok(aggregation) => aggregation,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+
+    if let Err(message) = aggregation.validate() {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message(&message)));
+    }
+
+    match storage.aggregate_keyspace_objects(keyspace_name, &aggregation) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectsAggregated(groups, index_name) => {
+                let mut response_body = json!({
+                    "full_scan": index_name.is_none(),
+                    "index": index_name,
+                });
+
+                match aggregation.group_by {
+                    Some(_group_by) => response_body["groups"] = json!(groups),
+                    None => {
+                        response_body["aggregations"] =
+                            json!(groups[0].aggregations)
+                    }
+                }
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+// Changes are streamed as newline-delimited JSON write-ahead log entries,
+// starting with the ones journaled after `since` that are still in the log
+// and following with new ones as they're applied, until the client goes away.
+// The storage write lock is only taken to subscribe, so that no entry is
+// missed between the ones in the log and the ones sent to the subscriber.
+fn handle_keyspace_changes(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let since = match request.query().get("since") {
+        Some(since) => match since.parse::<u64>() {
+            Ok(since) => Some(since),
+            Err(_error) => {
+                return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                    .content_type("application/json")
+                    .body(json_error_message(
+                        "since must be a sequence number",
+                    )))
+            }
+        },
+        None => None,
+    };
+
+    match components::storage::STATE
+        .read()
+        .unwrap()
+        .read_keyspace(keyspace_name.clone())
+    {
+        Ok(KeyspaceFound(_keyspace)) => (),
+        Ok(_outcome) => {
+            return Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                .content_type("application/json")
+                .finish())
+        }
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish())
+        }
+    }
+
+    let subscription = match components::storage::STATE
+        .write()
+        .unwrap()
+        .subscribe(&keyspace_name, since)
+    {
+        Ok(subscription) => subscription,
+        Err(storage::Error::SequenceNotRetained(sequence)) => {
+            return Ok(HttpResponse::build(StatusCode::GONE)
+                .content_type("application/json")
+                .body(json_error_message(&format!(
+                    "changes are only retained after sequence {}",
+                    sequence
+                ))))
+        }
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish())
+        }
+    };
+    let changes = stream::iter_ok(subscription.entries)
+        .chain(subscription.receiver)
+        .map(|entry| {
+            let mut line = serde_json::to_vec(&entry).unwrap();
+            line.push(b'\n');
+            Bytes::from(line)
+        })
+        .map_err(|()| error::ErrorInternalServerError("change feed closed"));
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/x-ndjson")
+        .streaming(changes))
+}
+
+fn handle_truncate_keyspace(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+
+    match storage.truncate_keyspace(keyspace_name) {
+        Ok(outcome) => match outcome {
+            KeyspaceTruncated(keyspace) => {
+                let response_body = json!(keyspace.metadata);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_create_or_update_keyspace_index(
+    request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let index_name: String = request.match_info().query("index")?;
+    let index: IndexRequest = match serde_json::from_value(body) {
+        Ok(index) => index,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+
+    if !index.path.starts_with('/') {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message("index path must be a JSON Pointer")));
+    }
+
+    match storage.create_keyspace_index(
+        keyspace_name,
+        index_name,
+        Index::make(&index.path, index.unique),
+    ) {
+        Ok(outcome) => match outcome {
+            KeyspaceIndexCreated(index_name, index) => {
+                let response_body = index_information(&index_name, &index);
+
+                Ok(HttpResponse::build(StatusCode::CREATED)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceIndexUpdated(index_name, index) => {
+                let response_body = index_information(&index_name, &index);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceIndexConflict(index_name) => {
+                Ok(HttpResponse::build(StatusCode::CONFLICT)
+                    .content_type("application/json")
+                    .body(json_error_message(&format!(
+                        "objects have duplicate values for unique index {}",
+                        index_name
+                    ))))
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_delete_keyspace_index(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let index_name: String = request.match_info().query("index")?;
+
+    match storage.delete_keyspace_index(keyspace_name, index_name) {
+        Ok(outcome) => match outcome {
+            KeyspaceIndexDeleted(index_name, index) => {
+                let response_body = index_information(&index_name, &index);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceIndexNotFound(_index_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+// Values are taken as JSON when they parse as such, so `42` looks up the
+// number and `"42"` the string. Anything else is looked up as a string.
+fn handle_lookup_keyspace_index(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let storage = components::storage::STATE.read().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let index_name: String = request.match_info().query("index")?;
+    let value: String = request.match_info().query("value")?;
+    let value = match serde_json::from_str(&value) {
+        Ok(value) => value,
+        Err(_error) => serde_json::Value::String(value),
+    };
+
+    match storage.read_keyspace_index(keyspace_name, index_name, &value) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectsFound(objects, _next_cursor) => {
+                let objects: Vec<&ObjectData> =
+                    objects.iter().map(|object| &object.data).collect();
+                let response_body = json!({ "objects": objects });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceIndexNotFound(_index_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_delete_keyspace(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+
+    match storage.delete_keyspace(keyspace_name) {
+        Ok(outcome) => match outcome {
+            KeyspaceDeleted(keyspace) => {
+                let response_body = json!(keyspace.metadata);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+fn handle_delete_keyspace_object(
+    request: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let object_id: String = request.match_info().query("object_id")?;
+    let preconditions = match preconditions(request) {
+        Ok(preconditions) => preconditions,
+        Err(_error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error_message("invalid precondition headers")))
+        }
+    };
+
+    match storage.delete_keyspace_object(
+        keyspace_name,
+        object_id,
+        preconditions,
+    ) {
+        Ok(outcome) => match outcome {
+            KeyspaceObjectDeleted(object) => {
+                let response_body = json!(object.data);
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            KeyspaceNotFound(_keyspace_name) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectNotFound(_object_id)
+            | KeyspaceObjectExpired(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                    .content_type("application/json")
+                    .finish())
+            }
+            KeyspaceObjectPreconditionFailed(_object_id) => {
+                Ok(HttpResponse::build(StatusCode::PRECONDITION_FAILED)
+                    .content_type("application/json")
+                    .finish())
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+// Operations are applied as a single batch, so that they're journaled
+// together.
+fn handle_bulk(
+    request: &HttpRequest,
+    body: Bytes,
+) -> Result<HttpResponse, Error> {
+    let c = Configuration::read();
+
+    let keyspace_name: String = request.match_info().query("keyspace")?;
+    let operations = match bulk_operations(
+        request.content_type(),
+        &body,
+        c.http_resources_max_bulk_size,
+    ) {
+        Ok(operations) => operations,
+        Err(response) => return Ok(response),
+    };
+
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    match storage.read_keyspace(keyspace_name.to_owned()) {
+        Ok(KeyspaceFound(_keyspace)) => (),
+        Ok(_outcome) => {
+            return Ok(HttpResponse::build(StatusCode::NOT_FOUND)
+                .content_type("application/json")
+                .finish())
+        }
+        Err(_error) => {
+            return Ok(HttpResponse::build(
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ).content_type("application/json")
+                .finish())
+        }
+    }
+
+    let requires_client_ids = requires_client_ids(&storage, &keyspace_name);
+    let mut object_ids = Vec::new();
+    let mut batch = Vec::new();
+
+    for operation in operations {
+        match operation {
+            BulkRequestOperation::Put { id: None, .. }
+                if requires_client_ids =>
+            {
+                object_ids.push(None);
+            }
+            BulkRequestOperation::Put { id, data, ttl } => {
+                let object_id = id.unwrap_or_else(make_id_string);
+                let mut object = Object::make(&object_id, data);
+
+                if let Some(ttl) = ttl {
+                    object.set_expires_at(expires_at(ttl));
+                }
+
+                object_ids.push(Some(object_id));
+                batch.push(TransactionOperation::CreateOrUpdate {
+                    keyspace_name: keyspace_name.to_owned(),
+                    object,
+                    preconditions: vec![],
+                });
+            }
+            BulkRequestOperation::Delete { id } => {
+                object_ids.push(Some(id.to_owned()));
+                batch.push(TransactionOperation::Delete {
+                    keyspace_name: keyspace_name.to_owned(),
+                    object_id: id,
+                    preconditions: vec![],
+                });
+            }
+        }
+    }
+
+    let outcome = if batch.is_empty() {
+        Ok(BatchApplied(vec![]))
+    } else {
+        storage.apply_batch(batch)
+    };
+    let results = bulk_results(object_ids, &outcome);
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/json")
+        .body(json_body(&json!({ "results": results }))))
+}
+
+fn handle_transaction(
+    _request: &HttpRequest,
+    body: serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let transaction: TransactionRequest = match serde_json::from_value(body) {
+        Ok(transaction) => transaction,
+        Err(error) => {
+            return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                .content_type("application/json")
+                .body(json_error(error)))
+        }
+    };
+
+    if transaction.operations.iter().any(|operation| match *operation {
+        TransactionRequestOperation::Put { ref id, .. } => {
+            is_reserved_name(id)
+        }
+        _ => false,
+    }) {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+            .content_type("application/json")
+            .body(json_error_message(RESERVED_NAME)));
+    }
+
+    let operations: Vec<TransactionOperation> = transaction
+        .operations
+        .into_iter()
+        .map(TransactionOperation::from)
+        .collect();
+
+    let mut storage = components::storage::STATE.write().unwrap();
+
+    match storage.commit_transaction(operations) {
+        Ok(outcome) => match outcome {
+            TransactionCommitted(outcomes) => {
+                let results: Vec<serde_json::Value> =
+                    outcomes.iter().map(transaction_result).collect();
+                let response_body = json!({ "results": results });
+
+                Ok(HttpResponse::build(StatusCode::OK)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            TransactionAborted(index, outcome) => {
+                let status_code = outcome_status_code(&outcome);
+                let response_body = json!({
+                    "error": "transaction aborted",
+                    "failed_operation": index,
+                });
+
+                Ok(HttpResponse::build(status_code)
+                    .content_type("application/json")
+                    .body(json_body(&response_body)))
+            }
+            _ => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .content_type("application/json")
+                .finish()),
+        },
+        Err(_error) => Ok(HttpResponse::build(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ).content_type("application/json")
+            .finish()),
+    }
+}
+
+pub fn application() -> App {
+    App::new()
+        .resource("/", |r| {
+            r.method(http::Method::GET).f(handle_cluster_information)
+        })
+        .scope("/_cluster/", |s| {
+            s.resource("state", |r| {
+                r.method(http::Method::GET).f(handle_cluster_state)
+            })
+        })
+        .resource("/_keyspaces", |r| {
+            r.method(http::Method::GET).f(handle_list_keyspaces);
+        })
+        .resource("/_txn", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(&handle_transaction)(
+                    request,
+                )
+            });
+        })
+        .resource("/{keyspace}", |r| {
+            r.method(http::Method::PUT).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_create_or_update_keyspace,
+                )(request)
+            });
+            r.method(http::Method::GET).f(handle_get_keyspace);
+            r.method(http::Method::DELETE).f(handle_delete_keyspace);
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_create_or_update_keyspace_object,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/_bulk", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                let c = Configuration::read();
+
+                make_handler_for_request_with_raw_body(
+                    c.http_resources_max_bulk_body_size,
+                    &handle_bulk,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/_mget", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_multi_get_keyspace_objects,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/_query", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_query_keyspace_objects,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/_aggregate", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_aggregate_keyspace_objects,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/_changes", |r| {
+            r.method(http::Method::GET).f(handle_keyspace_changes);
+        })
+        .resource("/{keyspace}/_truncate", |r| {
+            r.method(http::Method::POST).f(handle_truncate_keyspace);
+        })
+        .resource("/{keyspace}/_objects", |r| {
+            r.method(http::Method::GET).f(handle_list_keyspace_objects);
+        })
+        .resource("/{keyspace}/_index/{index}", |r| {
+            r.method(http::Method::PUT).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_create_or_update_keyspace_index,
+                )(request)
+            });
+            r.method(http::Method::DELETE).f(handle_delete_keyspace_index);
+        })
+        .resource("/{keyspace}/_index/{index}/{value}", |r| {
+            r.method(http::Method::GET).f(handle_lookup_keyspace_index);
+        })
+        .resource("/{keyspace}/{object_id}/_history", |r| {
+            r.method(http::Method::GET).f(handle_get_keyspace_object_history);
+        })
+        .resource("/{keyspace}/{object_id}/_incr", |r| {
+            r.method(http::Method::POST).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_increment_keyspace_object,
+                )(request)
+            });
+        })
+        .resource("/{keyspace}/{object_id}", |r| {
+            r.method(http::Method::PUT).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_create_or_update_keyspace_object,
+                )(request)
+            });
+            r.method(http::Method::PATCH).with(|request: HttpRequest| {
+                make_handler_for_request_with_body(
+                    &handle_patch_keyspace_object,
+                )(request)
+            });
+            r.method(http::Method::GET).f(handle_get_keyspace_object);
+            r.method(http::Method::DELETE)
+                .f(handle_delete_keyspace_object);
+        })
+}
+
+pub fn start() -> () {
+    let c = Configuration::read();
+
+    let http_resources_address: SocketAddr =
+        format!("{}:{}", c.bind_host, c.http_resources_port)
+            .parse()
+            .unwrap();
+
+    let http_resources_server = server::new(application)
+        .workers(c.http_resources_workers)
+        .bind(http_resources_address)
+        .unwrap();
+
+    info!("Starting resources server");
+    http_resources_server.run()
+}
+
+pub fn spawn() -> JoinHandle<()> {
     thread::spawn(start)
 }
+
+#[cfg(test)]
+mod tests {
+    use components::http_resources::*;
+
+    #[test]
+    fn test_bulk_operations() {
+        let body = br#"[
+            { "op": "put", "data": {} },
+            { "op": "delete", "id": "1" }
+        ]"#;
+        let operations = bulk_operations("application/json", body, 2).unwrap();
+        assert_eq!(operations.len(), 2);
+
+        let body = br#"{ "op": "delete", "id": "1" }
+
+            { "op": "put", "id": "2", "data": {}, "ttl": 1 }
+        "#;
+        let operations =
+            bulk_operations("application/x-ndjson", body, 2).unwrap();
+        match operations[1] {
+            BulkRequestOperation::Put {
+                ref id, ttl: Some(1), ..
+            } => assert_eq!(id, &Some("2".to_owned())),
+            ref operation => panic!("unexpected operation {:?}", operation),
+        }
+
+        // NDJSON isn't parsed as an array unless it says so, and vice versa.
+        assert_eq!(
+            bulk_operations("application/json", body, 2)
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            bulk_operations("application/json", b"[{ \"op\": \"get\" }]", 2)
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            bulk_operations("application/x-ndjson", body, 1)
+                .unwrap_err()
+                .status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let body = br#"[
+            { "op": "put", "data": {}, "ttl": 18446744073709551615 }
+        ]"#;
+        assert_eq!(
+            bulk_operations("application/json", body, 1)
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let body = br#"[{ "op": "put", "id": "_query", "data": {} }]"#;
+        assert_eq!(
+            bulk_operations("application/json", body, 1)
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_is_reserved_name() {
+        assert!(is_reserved_name("_query"));
+        assert!(is_reserved_name("_"));
+        assert!(!is_reserved_name("people"));
+        assert!(!is_reserved_name("people_1"));
+    }
+
+    #[test]
+    fn test_is_valid_ttl() {
+        let now = now_millis();
+
+        assert!(is_valid_ttl(0, now));
+        assert!(is_valid_ttl(60, now));
+        assert!(is_valid_ttl((18446744073709551615 - now) / 1000, now));
+        assert!(!is_valid_ttl((18446744073709551615 - now) / 1000 + 1, now));
+        assert!(!is_valid_ttl(18446744073709551615, now));
+    }
+
+    #[test]
+    fn test_bulk_results() {
+        let object_ids =
+            vec![Some("1".to_owned()), None, Some("2".to_owned())];
+        let object = Object::make("1", ObjectData::new());
+        let outcome = Ok(BatchApplied(vec![
+            KeyspaceObjectCreated(object),
+            KeyspaceObjectNotFound("2".to_owned()),
+        ]));
+
+        assert_eq!(
+            bulk_results(object_ids.clone(), &outcome),
+            vec![
+                json!({ "id": "1", "status": 201 }),
+                json!({
+                    "id": null,
+                    "status": 400,
+                    "error": CLIENT_IDS_REQUIRED,
+                }),
+                json!({ "id": "2", "status": 404 }),
+            ]
+        );
+
+        let outcome = Ok(MemoryLimitReached("people".to_owned()));
+        let status_codes: Vec<serde_json::Value> =
+            bulk_results(object_ids, &outcome)
+                .iter()
+                .map(|result| result["status"].to_owned())
+                .collect();
+        assert_eq!(status_codes, vec![json!(507), json!(400), json!(507)]);
+    }
+
+    #[test]
+    fn test_outcome_status_code() {
+        let object = Object::make("1", ObjectData::new());
+
+        assert_eq!(
+            outcome_status_code(&KeyspaceObjectCreated(object.to_owned())),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceObjectDeleted(object)),
+            StatusCode::OK
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceNotFound("people".to_owned())),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceObjectPreconditionFailed(
+                "1".to_owned()
+            )),
+            StatusCode::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceObjectTooLarge("1".to_owned())),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceFull("people".to_owned())),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceIndexConflict("email".to_owned())),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            outcome_status_code(&KeyspaceObjectsRead(vec![], vec![])),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
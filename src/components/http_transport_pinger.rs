@@ -1,34 +1,18 @@
 use libc;
-use std::io::Error as IoError;
-use std::os::raw::c_int;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use crossbeam_channel::{self as channel, Receiver, Sender};
+use crossbeam_channel as channel;
 use reqwest::{self, Client};
 use serde_json;
-use signal_hook;
 
 use cluster::{Cluster, Pong};
 use components::configuration::Configuration;
 use node::{Node, UnknownNode};
+use utils::notify;
 
 use components;
 
-fn notify(
-    signals: &[c_int],
-) -> Result<(Sender<c_int>, Receiver<c_int>), IoError> {
-    let (s, r) = channel::bounded(100);
-    let signals = signal_hook::iterator::Signals::new(signals)?;
-    let sender = s.clone();
-    thread::spawn(move || {
-        for signal in signals.forever() {
-            sender.send(signal);
-        }
-    });
-    Ok((s, r))
-}
-
 #[derive(Debug)]
 pub enum Error {
     HttpError(reqwest::Error),
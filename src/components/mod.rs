@@ -5,3 +5,7 @@ pub mod http_transport;
 pub mod http_transport_pinger;
 pub mod logging;
 pub mod storage;
+pub mod storage_compactor;
+pub mod storage_reaper;
+pub mod storage_snapshotter;
+pub mod storage_wal_syncer;
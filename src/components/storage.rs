@@ -1,7 +1,60 @@
+use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
+use components::configuration::Configuration;
+use engine::Engine;
 use storage::Storage;
+use wal::FsyncPolicy;
+
+static WAL_DIRECTORY: &str = "wal";
+
+static SNAPSHOT_DIRECTORY: &str = "snapshots";
+
+static ENGINE_DIRECTORY: &str = "objects";
 
 lazy_static! {
     pub static ref STATE: RwLock<Storage> = RwLock::new(Storage::new());
 }
+
+pub fn wal_directory(c: &Configuration) -> PathBuf {
+    Path::new(&c.storage_directory).join(WAL_DIRECTORY)
+}
+
+pub fn snapshot_directory(c: &Configuration) -> PathBuf {
+    Path::new(&c.storage_directory).join(SNAPSHOT_DIRECTORY)
+}
+
+pub fn engine_directory(c: &Configuration) -> PathBuf {
+    Path::new(&c.storage_directory).join(ENGINE_DIRECTORY)
+}
+
+pub fn initialize() {
+    let c = Configuration::read();
+
+    let wal_directory = wal_directory(&c);
+    let snapshot_directory = snapshot_directory(&c);
+    let engine_directory = engine_directory(&c);
+    let fsync_policy = FsyncPolicy::make(
+        &c.storage_wal_fsync,
+        c.storage_wal_fsync_interval,
+    ).unwrap();
+    let engine = Engine::make(&c.storage_engine).unwrap();
+    let mut storage = STATE.write().unwrap();
+
+    info!(
+        "Recovering storage from {:?} and {:?}",
+        snapshot_directory, wal_directory
+    );
+
+    *storage = Storage::open(
+        &wal_directory,
+        &snapshot_directory,
+        &engine_directory,
+        fsync_policy,
+        engine,
+        c.storage_engine_memory_budget,
+        c.storage_memory_limit,
+    ).unwrap();
+
+    info!("Storage recovered up to sequence {}", storage.sequence());
+}
@@ -0,0 +1,72 @@
+use libc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel as channel;
+
+use components::configuration::Configuration;
+use engine::Error;
+use utils::notify;
+
+use components;
+
+// The storage lock is only held to start and finish compactions, not while
+// they're merging segments. Compactions run one after the other until no
+// keyspace needs one.
+fn compact(throughput: u64) -> Result<(), Error> {
+    loop {
+        let (keyspace_name, mut compaction) =
+            match components::storage::STATE.write().unwrap().begin_compaction()
+            {
+                Some(compaction) => compaction,
+                None => return Ok(()),
+            };
+
+        let result = compaction.run(throughput);
+        let is_installed = components::storage::STATE
+            .write()
+            .unwrap()
+            .finish_compaction(&keyspace_name, &compaction);
+
+        result?;
+
+        if is_installed {
+            debug!("Compacted objects of keyspace {:?}", keyspace_name);
+        }
+    }
+}
+
+pub fn start() {
+    let c = Configuration::read();
+
+    let duration = Duration::from_millis(c.storage_compaction_schedule);
+    let compact_receiver = channel::tick(duration);
+    let (_signal_sender, signal_receiver) =
+        notify(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+
+    loop {
+        select! {
+            recv(compact_receiver, tick) => match tick {
+                Some(_tick) => {
+                    debug!("compact_receiver got message");
+                    let throughput = c.storage_compaction_throughput;
+                    if let Err(error) = compact(throughput) {
+                        error!("Failed to compact objects: {:?}", error);
+                    }
+                },
+                None => error!("compact_receiver channel closed"),
+            }
+            recv(signal_receiver, signal) => match signal {
+                Some(signal) => {
+                    warn!("Received signal {:?}, exiting", signal);
+                    break;
+                },
+                None => error!("signal_receiver channel closed"),
+            }
+        }
+    }
+}
+
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn(start)
+}
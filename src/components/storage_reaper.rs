@@ -0,0 +1,59 @@
+use libc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel as channel;
+
+use components::configuration::Configuration;
+use storage::Error;
+use utils::{now_millis, notify};
+
+use components;
+
+fn reap() -> Result<(), Error> {
+    let mut storage = components::storage::STATE.write().unwrap();
+    let now = now_millis();
+    let reaped = storage.reap_expired_objects(now)?;
+
+    storage.prune_history(now);
+
+    if reaped > 0 {
+        info!("Reaped {} expired objects", reaped);
+    }
+
+    Ok(())
+}
+
+pub fn start() {
+    let c = Configuration::read();
+
+    let duration = Duration::from_millis(c.storage_reaper_schedule);
+    let reap_receiver = channel::tick(duration);
+    let (_signal_sender, signal_receiver) =
+        notify(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+
+    loop {
+        select! {
+            recv(reap_receiver, tick) => match tick {
+                Some(_tick) => {
+                    debug!("reap_receiver got message");
+                    if let Err(error) = reap() {
+                        error!("Failed to reap expired objects: {:?}", error);
+                    }
+                },
+                None => error!("reap_receiver channel closed"),
+            }
+            recv(signal_receiver, signal) => match signal {
+                Some(signal) => {
+                    warn!("Received signal {:?}, exiting", signal);
+                    break;
+                },
+                None => error!("signal_receiver channel closed"),
+            }
+        }
+    }
+}
+
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn(start)
+}
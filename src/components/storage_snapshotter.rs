@@ -0,0 +1,74 @@
+use libc;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel as channel;
+
+use components::configuration::Configuration;
+use storage::Error;
+use utils::notify;
+
+use components;
+
+// The storage lock is only held to start and finish the snapshot, not while
+// it's being written to disk.
+fn snapshot(snapshot_directory: &Path) -> Result<(), Error> {
+    let snapshot =
+        match components::storage::STATE.write().unwrap().begin_snapshot()? {
+            Some(snapshot) => snapshot,
+            None => {
+                debug!("No operations since last snapshot, skipping");
+                return Ok(());
+            }
+        };
+
+    let path = snapshot.write(snapshot_directory)?;
+
+    components::storage::STATE
+        .write()
+        .unwrap()
+        .finish_snapshot(&snapshot)?;
+
+    info!(
+        "Wrote snapshot {:?} up to sequence {}",
+        path, snapshot.sequence
+    );
+
+    Ok(())
+}
+
+pub fn start() {
+    let c = Configuration::read();
+
+    let snapshot_directory = components::storage::snapshot_directory(&c);
+    let duration = Duration::from_millis(c.storage_snapshot_schedule);
+    let snapshot_receiver = channel::tick(duration);
+    let (_signal_sender, signal_receiver) =
+        notify(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+
+    loop {
+        select! {
+            recv(snapshot_receiver, tick) => match tick {
+                Some(_tick) => {
+                    debug!("snapshot_receiver got message");
+                    if let Err(error) = snapshot(&snapshot_directory) {
+                        error!("Failed to write snapshot: {:?}", error);
+                    }
+                },
+                None => error!("snapshot_receiver channel closed"),
+            }
+            recv(signal_receiver, signal) => match signal {
+                Some(signal) => {
+                    warn!("Received signal {:?}, exiting", signal);
+                    break;
+                },
+                None => error!("signal_receiver channel closed"),
+            }
+        }
+    }
+}
+
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn(start)
+}
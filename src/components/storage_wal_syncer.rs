@@ -0,0 +1,54 @@
+use libc;
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel as channel;
+
+use components::configuration::Configuration;
+use utils::notify;
+use wal::FsyncPolicy;
+
+use components;
+
+// Appends under the interval policy only sync once the interval has passed,
+// so without this the last operations before writes stop would never be.
+pub fn start() {
+    let c = Configuration::read();
+
+    let interval = match FsyncPolicy::make(
+        &c.storage_wal_fsync,
+        c.storage_wal_fsync_interval,
+    ) {
+        Ok(FsyncPolicy::Interval(interval)) => interval,
+        _ => return,
+    };
+    let sync_receiver = channel::tick(interval);
+    let (_signal_sender, signal_receiver) =
+        notify(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+
+    loop {
+        select! {
+            recv(sync_receiver, tick) => match tick {
+                Some(_tick) => {
+                    debug!("sync_receiver got message");
+                    let result =
+                        components::storage::STATE.write().unwrap().sync_wal();
+                    if let Err(error) = result {
+                        error!("Failed to sync write-ahead log: {:?}", error);
+                    }
+                },
+                None => error!("sync_receiver channel closed"),
+            }
+            recv(signal_receiver, signal) => match signal {
+                Some(signal) => {
+                    warn!("Received signal {:?}, exiting", signal);
+                    break;
+                },
+                None => error!("signal_receiver channel closed"),
+            }
+        }
+    }
+}
+
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn(start)
+}
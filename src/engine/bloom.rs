@@ -0,0 +1,54 @@
+use crc::crc64;
+
+// ~1% false positives.
+const BITS_PER_KEY: usize = 10;
+
+const HASH_COUNT: u32 = 7;
+
+/// Tells whether a segment may have an ID without reading it. Bit positions
+/// come from a CRC64 of the ID, so filters read back from disk stay valid
+/// across builds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    pub fn make<'a, I>(keys: I, key_count: usize) -> Self
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let mut filter = Self {
+            bits: vec![0; (key_count * BITS_PER_KEY / 64).max(1)],
+        };
+
+        for key in keys {
+            for position in filter.positions(key) {
+                filter.bits[position / 64] |= 1 << (position % 64);
+            }
+        }
+
+        filter
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        if self.bits.is_empty() {
+            return false;
+        }
+
+        self.positions(key).all(|position| {
+            self.bits[position / 64] & 1 << (position % 64) != 0
+        })
+    }
+
+    // Double hashing on both halves of the CRC64.
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = crc64::checksum_ecma(key.as_bytes());
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let bit_count = self.bits.len() as u32 * 64;
+
+        (0..HASH_COUNT).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize
+        })
+    }
+}
@@ -0,0 +1,186 @@
+use std::cmp::Ordering::Equal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use engine::disk::{segment_source, Merge};
+use engine::segment::{is_tombstone, Segment, SegmentWriter};
+use engine::Error;
+use range::Range;
+
+// Level 0 is compacted once it has this many segments.
+const LEVEL_0_SEGMENT_LIMIT: usize = 4;
+
+// Level 1 is compacted once its segments add up to this many bytes, and every
+// level below it holds 10 times more than the one above.
+const LEVEL_1_SIZE_LIMIT: u64 = 10 * 1024 * 1024;
+
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+const TARGET_SEGMENT_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Merges segments of a level that's past its limit, and the ones they
+/// overlap in the level below, into new segments in the level below. All of
+/// level 0 is merged at once, and a single segment of any other level.
+///
+/// Compactions are run without holding the storage lock, and only installed
+/// if the engine still has all of their inputs.
+#[derive(Debug)]
+pub struct Compaction {
+    level: usize,
+    // Newest first.
+    inputs: Vec<Arc<Segment>>,
+    directory: PathBuf,
+    // Tombstones are dropped when there are no segments below the output
+    // level that they could hide objects in.
+    drops_tombstones: bool,
+    score: f64,
+    outputs: Option<Vec<Arc<Segment>>>,
+}
+
+/// The compaction of the level that's the furthest past its limit, if any
+/// is.
+pub fn pick(
+    levels: &[Vec<Arc<Segment>>],
+    directory: &Path,
+) -> Option<Compaction> {
+    let (level, score) = (0..levels.len())
+        .map(|level| (level, score(levels, level)))
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Equal))?;
+
+    if score < 1.0 {
+        return None;
+    }
+
+    let next_level: &[Arc<Segment>] = match levels.get(level + 1) {
+        Some(segments) => segments,
+        None => &[],
+    };
+    let inputs = if level == 0 {
+        levels[0].clone()
+    } else {
+        // The segment that overlaps the fewest bytes in the level below.
+        let segment = levels[level].iter().min_by_key(|segment| {
+            overlapping(next_level, segment.first_id(), segment.last_id())
+                .iter()
+                .map(|segment| segment.size)
+                .sum::<u64>()
+        })?;
+        vec![segment.to_owned()]
+    };
+    let overlapping_inputs = {
+        let first_id = inputs.iter().map(|segment| segment.first_id()).min()?;
+        let last_id = inputs.iter().map(|segment| segment.last_id()).max()?;
+        overlapping(next_level, first_id, last_id)
+    };
+
+    Some(Compaction {
+        level,
+        inputs: inputs.into_iter().chain(overlapping_inputs).collect(),
+        directory: directory.to_owned(),
+        drops_tombstones: levels.iter().skip(level + 2).all(Vec::is_empty),
+        score,
+        outputs: None,
+    })
+}
+
+impl Compaction {
+    /// How far past its limit the compacted level is, compactions of levels
+    /// further past theirs going first.
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    /// Writes the output segments, at most `throughput` bytes per second if
+    /// it isn't 0.
+    pub fn run(&mut self, throughput: u64) -> Result<(), Error> {
+        let mut writer = SegmentWriter::make(
+            &self.directory,
+            Some(TARGET_SEGMENT_SIZE),
+            throughput,
+        );
+        let range = Range::default();
+        let sources = self
+            .inputs
+            .iter()
+            .map(|segment| segment_source(segment, &range))
+            .collect();
+
+        for entry in Merge::make(sources, false) {
+            let (object_id, value) = entry?;
+
+            if self.drops_tombstones && is_tombstone(&value) {
+                continue;
+            }
+
+            writer.add(object_id, &value)?;
+        }
+
+        let outputs = writer.finish()?;
+        self.outputs = Some(outputs.into_iter().map(Arc::new).collect());
+
+        Ok(())
+    }
+
+    /// Replaces the inputs with the outputs in `levels`, unless the
+    /// compaction didn't run or `levels` doesn't have every input anymore.
+    pub fn install(&self, levels: &mut Vec<Vec<Arc<Segment>>>) -> bool {
+        let outputs = match self.outputs {
+            Some(ref outputs) => outputs,
+            None => return false,
+        };
+        let is_input = |segment: &Arc<Segment>| {
+            self.inputs.iter().any(|input| input.name == segment.name)
+        };
+        let input_count = levels
+            .iter()
+            .skip(self.level)
+            .take(2)
+            .map(|segments| segments.iter().filter(|s| is_input(s)).count())
+            .sum::<usize>();
+
+        if input_count != self.inputs.len() {
+            return false;
+        }
+
+        for segments in levels.iter_mut().skip(self.level).take(2) {
+            segments.retain(|segment| !is_input(segment));
+        }
+
+        while levels.len() < self.level + 2 {
+            levels.push(Vec::new());
+        }
+
+        let next_level = &mut levels[self.level + 1];
+        next_level.extend(outputs.iter().cloned());
+        next_level.sort_by(|a, b| a.first_id().cmp(b.first_id()));
+
+        true
+    }
+}
+
+// How far past its limit `level` is, from 1 on.
+fn score(levels: &[Vec<Arc<Segment>>], level: usize) -> f64 {
+    let segments = &levels[level];
+
+    if level == 0 {
+        return segments.len() as f64 / LEVEL_0_SEGMENT_LIMIT as f64;
+    }
+
+    let size: u64 = segments.iter().map(|segment| segment.size).sum();
+    let limit =
+        LEVEL_1_SIZE_LIMIT * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1);
+
+    size as f64 / limit as f64
+}
+
+fn overlapping(
+    segments: &[Arc<Segment>],
+    first_id: &str,
+    last_id: &str,
+) -> Vec<Arc<Segment>> {
+    segments
+        .iter()
+        .filter(|segment| segment.overlaps(first_id, last_id))
+        .cloned()
+        .collect()
+}
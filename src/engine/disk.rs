@@ -0,0 +1,686 @@
+use std::iter::{self, Peekable};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use im::ordmap::OrdMap;
+use serde_json;
+
+use engine::compaction::{self, Compaction};
+use engine::segment::{Segment, SegmentWriter, Value};
+use engine::{EngineState, Error, StorageEngine};
+use object::Object;
+use range::Range;
+use types::SortedBag;
+
+/// What's persisted of a disk engine: the names of the segments in each of
+/// its levels, the writes since the last flush and its counters.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub levels: Vec<Vec<String>>,
+    pub memtable: SortedBag<Option<Object>>,
+    pub object_count: usize,
+    pub size: usize,
+}
+
+/// A log-structured merge tree. Writes go to a memtable, where deleted
+/// objects are tombstones (`None`), and flushing it writes them to a new
+/// segment in level 0. Segments in level 0 are newest first and may overlap,
+/// while the ones in every level below don't overlap and are in ID order.
+/// Compactions merge levels that grew past their limit into the level below
+/// them.
+///
+/// Only the memtable and the meta of segments are kept in memory.
+#[derive(Clone, Debug)]
+pub struct DiskEngine {
+    directory: PathBuf,
+    levels: Vec<Vec<Arc<Segment>>>,
+    // Segments named in a manifest, until they're opened.
+    unopened_levels: Option<Vec<Vec<String>>>,
+    memtable: SortedBag<Option<Object>>,
+    memtable_size: usize,
+    object_count: usize,
+    size: usize,
+}
+
+/// The latest version of an object, or its tombstone: as written to the
+/// memtable or still encoded in a segment.
+pub enum Version<'a> {
+    Memtable(&'a Option<Object>),
+    Segment(Value),
+}
+
+pub type Source<'a, T> =
+    Box<Iterator<Item = Result<(String, T), Error>> + 'a>;
+
+/// Merges sources that are each in the same order, yielding every ID once
+/// with its entry from the first source that has it.
+pub struct Merge<'a, T> {
+    reverse: bool,
+    sources: Vec<Peekable<Source<'a, T>>>,
+}
+
+impl DiskEngine {
+    pub fn make(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            levels: Vec::new(),
+            unopened_levels: None,
+            memtable: OrdMap::new(),
+            memtable_size: 0,
+            object_count: 0,
+            size: 0,
+        }
+    }
+
+    fn levels(&self) -> Result<&[Vec<Arc<Segment>>], Error> {
+        let unopened_segment = self
+            .unopened_levels
+            .iter()
+            .flat_map(|levels| levels.iter())
+            .flat_map(|names| names.iter())
+            .next();
+
+        match unopened_segment {
+            Some(name) => Err(Error::SegmentNotOpen(name.to_owned())),
+            None => Ok(&self.levels),
+        }
+    }
+
+    fn has_segments(&self) -> bool {
+        !self.levels.iter().all(Vec::is_empty)
+    }
+
+    fn get_value(&self, object_id: &str) -> Result<Option<Value>, Error> {
+        for (level, segments) in self.levels()?.iter().enumerate() {
+            // Segments in levels below 0 don't overlap, so only the first one
+            // that ends at or past the ID may have it.
+            let segments = if level == 0 {
+                &segments[..]
+            } else {
+                let segment = match segments.binary_search_by(|segment| {
+                    segment.last_id().cmp(object_id)
+                }) {
+                    Ok(segment) | Err(segment) => segment,
+                };
+
+                &segments[segment..segments.len().min(segment + 1)]
+            };
+
+            for segment in segments {
+                if let Some(value) = segment.get(object_id)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Keeps track of how much memory the memtable takes.
+    fn buffer(&mut self, object_id: &str, entry: Option<Object>) {
+        self.memtable_size += entry_size(object_id, &entry);
+
+        if let Some(replaced) =
+            self.memtable.insert(object_id.to_owned(), entry)
+        {
+            self.memtable_size -= entry_size(object_id, &replaced);
+        }
+    }
+
+    fn sources<'a>(
+        &'a self,
+        levels: &'a [Vec<Arc<Segment>>],
+        range: &Range,
+    ) -> Vec<Source<'a, Version<'a>>> {
+        let memtable: Box<Iterator<Item = &(String, Option<Object>)>> =
+            if range.reverse {
+                Box::new(self.memtable.iter().rev())
+            } else {
+                Box::new(self.memtable.iter())
+            };
+        let skip_range = range.clone();
+        let take_range = range.clone();
+        let mut sources: Vec<Source<Version>> = vec![Box::new(
+            memtable
+                .skip_while(move |(object_id, _)| {
+                    skip_range.precedes(object_id)
+                })
+                .take_while(move |(object_id, _)| {
+                    !take_range.follows(object_id)
+                })
+                .map(|(object_id, entry)| {
+                    Ok((object_id.to_owned(), Version::Memtable(entry)))
+                }),
+        )];
+
+        for (level, segments) in levels.iter().enumerate() {
+            let segment_sources = if level == 0 {
+                segments
+                    .iter()
+                    .map(|segment| segment_source(segment, range))
+                    .collect()
+            } else {
+                vec![level_source(segments, range)]
+            };
+
+            sources.extend(segment_sources.into_iter().map(|source| {
+                Box::new(source.map(|entry| {
+                    entry.map(|(object_id, value)| {
+                        (object_id, Version::Segment(value))
+                    })
+                })) as Source<Version>
+            }));
+        }
+
+        sources
+    }
+}
+
+impl From<Manifest> for DiskEngine {
+    fn from(manifest: Manifest) -> Self {
+        let memtable_size = manifest
+            .memtable
+            .iter()
+            .map(|(object_id, entry)| entry_size(object_id, entry))
+            .sum();
+
+        Self {
+            directory: PathBuf::new(),
+            levels: Vec::new(),
+            unopened_levels: Some(manifest.levels),
+            memtable: manifest.memtable,
+            memtable_size,
+            object_count: manifest.object_count,
+            size: manifest.size,
+        }
+    }
+}
+
+impl StorageEngine for DiskEngine {
+    fn get(&self, object_id: &str) -> Result<Option<Object>, Error> {
+        if let Some(entry) = self.memtable.get(object_id) {
+            return Ok(entry.to_owned());
+        }
+
+        match self.get_value(object_id)? {
+            Some(value) => Version::Segment(value).decode(),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(
+        &mut self,
+        object_id: &str,
+        object: Object,
+    ) -> Result<Option<Object>, Error> {
+        let previous = self.get(object_id)?;
+
+        self.size += object.size();
+
+        match previous {
+            Some(ref previous) => self.size -= previous.size(),
+            None => self.object_count += 1,
+        }
+
+        self.buffer(object_id, Some(object));
+
+        Ok(previous)
+    }
+
+    // Objects only need a tombstone to hide them if they may be in a segment.
+    fn remove(&mut self, object_id: &str) -> Result<Option<Object>, Error> {
+        let previous = self.get(object_id)?;
+
+        if let Some(ref previous) = previous {
+            self.size -= previous.size();
+            self.object_count -= 1;
+
+            if self.has_segments() {
+                self.buffer(object_id, None);
+            } else if let Some(removed) = self.memtable.remove(object_id) {
+                self.memtable_size -= entry_size(object_id, &removed);
+            }
+        }
+
+        Ok(previous)
+    }
+
+    fn clear(&mut self) {
+        self.levels = Vec::new();
+        self.unopened_levels = None;
+        self.memtable = OrdMap::new();
+        self.memtable_size = 0;
+        self.object_count = 0;
+        self.size = 0;
+    }
+
+    fn len(&self) -> usize {
+        self.object_count
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn is_in_memory(&self) -> bool {
+        false
+    }
+
+    fn buffered_size(&self) -> usize {
+        self.memtable_size
+    }
+
+    fn scan<'a>(
+        &'a self,
+        range: Range,
+    ) -> Box<Iterator<Item = Result<(String, Object), Error>> + 'a> {
+        let levels = match self.levels() {
+            Ok(levels) => levels,
+            Err(error) => return Box::new(iter::once(Err(error))),
+        };
+        let merge = Merge::make(self.sources(levels, &range), range.reverse);
+
+        Box::new(merge.filter_map(|entry| {
+            let decoded = entry.and_then(|(object_id, version)| {
+                Ok((object_id, version.decode()?))
+            });
+
+            match decoded {
+                Ok((object_id, Some(object))) => Some(Ok((object_id, object))),
+                Ok((_object_id, None)) => None,
+                Err(error) => Some(Err(error)),
+            }
+        }))
+    }
+
+    fn open(&mut self, directory: &Path) -> Result<(), Error> {
+        self.directory = directory.to_owned();
+
+        if let Some(ref levels) = self.unopened_levels {
+            let mut opened_levels = Vec::new();
+
+            for names in levels {
+                let mut segments = Vec::new();
+
+                for name in names {
+                    segments.push(Arc::new(Segment::open(directory, name)?));
+                }
+
+                opened_levels.push(segments);
+            }
+
+            self.levels = opened_levels;
+        }
+
+        self.unopened_levels = None;
+
+        Ok(())
+    }
+
+    // Tombstones only need to be kept if there are segments they hide objects
+    // in.
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        self.levels()?;
+
+        let keeps_tombstones = self.has_segments();
+        let mut writer = SegmentWriter::make(&self.directory, None, 0);
+
+        for (object_id, entry) in self.memtable.iter() {
+            if entry.is_some() || keeps_tombstones {
+                writer.add(object_id.to_owned(), &serde_json::to_vec(entry)?)?;
+            }
+        }
+
+        let segments = writer.finish()?;
+
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+
+        for segment in segments {
+            self.levels[0].insert(0, Arc::new(segment));
+        }
+
+        self.memtable = OrdMap::new();
+        self.memtable_size = 0;
+
+        Ok(())
+    }
+
+    fn compaction(&self) -> Option<Compaction> {
+        match self.levels() {
+            Ok(levels) => compaction::pick(levels, &self.directory),
+            Err(_error) => None,
+        }
+    }
+
+    fn compact(&mut self, compaction: &Compaction) -> bool {
+        self.unopened_levels.is_none() && compaction.install(&mut self.levels)
+    }
+
+    fn files(&self) -> Vec<String> {
+        match self.unopened_levels {
+            Some(ref levels) => {
+                levels.iter().flat_map(|names| names.iter()).cloned().collect()
+            }
+            None => self
+                .levels
+                .iter()
+                .flat_map(|segments| segments.iter())
+                .map(|segment| segment.name.to_owned())
+                .collect(),
+        }
+    }
+
+    fn state(&self) -> EngineState {
+        let levels = match self.unopened_levels {
+            Some(ref levels) => levels.to_owned(),
+            None => self
+                .levels
+                .iter()
+                .map(|segments| {
+                    segments
+                        .iter()
+                        .map(|segment| segment.name.to_owned())
+                        .collect()
+                })
+                .collect(),
+        };
+
+        EngineState::Disk(Manifest {
+            levels,
+            memtable: self.memtable.clone(),
+            object_count: self.object_count,
+            size: self.size,
+        })
+    }
+
+    fn boxed_clone(&self) -> Box<StorageEngine> {
+        Box::new(self.clone())
+    }
+}
+
+impl<'a> Version<'a> {
+    pub fn decode(self) -> Result<Option<Object>, Error> {
+        match self {
+            Version::Memtable(entry) => Ok(entry.to_owned()),
+            Version::Segment(value) => Ok(serde_json::from_slice(&value)?),
+        }
+    }
+}
+
+impl<'a, T> Merge<'a, T> {
+    /// `sources` go from the newest to the oldest.
+    pub fn make(sources: Vec<Source<'a, T>>, reverse: bool) -> Self {
+        Self {
+            reverse,
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+
+    // Sources aren't read from anymore after one of them fails.
+    fn fail(&mut self, source: usize) -> Option<Result<(String, T), Error>> {
+        let entry = self.sources[source].next();
+        self.sources.clear();
+        entry
+    }
+}
+
+impl<'a, T> Iterator for Merge<'a, T> {
+    type Item = Result<(String, T), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, String)> = None;
+
+        for source in 0..self.sources.len() {
+            let object_id = match self.sources[source].peek() {
+                Some(&Ok((ref object_id, _))) => Some(object_id.to_owned()),
+                Some(&Err(_)) => None,
+                None => continue,
+            };
+            let object_id = match object_id {
+                Some(object_id) => object_id,
+                None => return self.fail(source),
+            };
+            let is_next = match next {
+                Some((_, ref next_id)) if self.reverse => object_id > *next_id,
+                Some((_, ref next_id)) => object_id < *next_id,
+                None => true,
+            };
+
+            if is_next {
+                next = Some((source, object_id));
+            }
+        }
+
+        let (source, object_id) = next?;
+        let entry = self.sources[source].next();
+
+        for other in 0..self.sources.len() {
+            let is_shadowed = match self.sources[other].peek() {
+                Some(&Ok((ref other_id, _))) => *other_id == object_id,
+                _ => false,
+            };
+
+            if other != source && is_shadowed {
+                self.sources[other].next();
+            }
+        }
+
+        entry
+    }
+}
+
+pub fn segment_source<'a>(
+    segment: &'a Segment,
+    range: &Range,
+) -> Source<'a, Value> {
+    Box::new(segment.entries(range))
+}
+
+// Segments in levels below 0 don't overlap, so they're read one after the
+// other.
+fn level_source<'a>(
+    segments: &'a [Arc<Segment>],
+    range: &Range,
+) -> Source<'a, Value> {
+    let range = range.clone();
+    let segments: Box<Iterator<Item = &Arc<Segment>>> = if range.reverse {
+        Box::new(segments.iter().rev())
+    } else {
+        Box::new(segments.iter())
+    };
+
+    Box::new(segments.flat_map(move |segment| segment.entries(&range)))
+}
+
+fn entry_size(object_id: &str, entry: &Option<Object>) -> usize {
+    object_id.len() + entry.as_ref().map_or(0, Object::size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use im::hashmap::HashMap;
+
+    use engine::disk::*;
+    use utils::make_id_string;
+
+    fn make_directory() -> PathBuf {
+        env::temp_dir().join(format!("noronha-engine-{}", make_id_string()))
+    }
+
+    fn make_object(object_id: &str, version: u64) -> Object {
+        let mut object = Object::make(object_id, HashMap::new());
+        object.set_version(version);
+        object
+    }
+
+    fn scan_ids(engine: &DiskEngine, range: Range) -> Vec<String> {
+        engine
+            .scan(range)
+            .map(|entry| entry.unwrap().0)
+            .collect()
+    }
+
+    // Checks every read against the objects the engine should have.
+    fn assert_objects(engine: &DiskEngine, expected: &BTreeMap<String, u64>) {
+        let object_ids: Vec<String> = expected.keys().cloned().collect();
+        let mut reverse_object_ids = object_ids.clone();
+        reverse_object_ids.reverse();
+        let prefix = Range {
+            prefix: Some("01".to_owned()),
+            ..Range::default()
+        };
+
+        assert_eq!(engine.len(), expected.len());
+        assert_eq!(scan_ids(engine, Range::default()), object_ids);
+        assert_eq!(
+            scan_ids(
+                engine,
+                Range {
+                    reverse: true,
+                    ..Range::default()
+                }
+            ),
+            reverse_object_ids
+        );
+        assert_eq!(
+            scan_ids(engine, prefix),
+            object_ids
+                .iter()
+                .filter(|object_id| object_id.starts_with("01"))
+                .cloned()
+                .collect::<Vec<String>>()
+        );
+
+        for i in 0..2500 {
+            let object_id = format!("{:04}", i);
+            let version = engine
+                .get(&object_id)
+                .unwrap()
+                .map(|object| object.version());
+
+            assert_eq!(version, expected.get(&object_id).cloned());
+        }
+    }
+
+    #[test]
+    fn test_flush_and_compact() {
+        let directory = make_directory();
+        let mut engine = DiskEngine::make(&directory);
+        let mut expected = BTreeMap::new();
+
+        for flush in 0..4 {
+            for i in 0..2000 {
+                let object_id = format!("{:04}", i + flush * 100);
+                let version = flush as u64 + 1;
+
+                if i % 7 == 3 {
+                    engine.remove(&object_id).unwrap();
+                    expected.remove(&object_id);
+                } else {
+                    engine.insert(&object_id, make_object(&object_id, version))
+                        .unwrap();
+                    expected.insert(object_id, version);
+                }
+            }
+
+            assert_objects(&engine, &expected);
+            engine.flush().unwrap();
+            assert_objects(&engine, &expected);
+            assert_eq!(engine.buffered_size(), 0);
+        }
+
+        assert_eq!(engine.levels[0].len(), 4);
+
+        let mut compaction = engine.compaction().unwrap();
+        assert!(!engine.compact(&compaction));
+        compaction.run(0).unwrap();
+        assert!(engine.compact(&compaction));
+        assert!(!engine.compact(&compaction));
+
+        assert_eq!(engine.levels[0].len(), 0);
+        assert!(!engine.levels[1].is_empty());
+        assert!(engine.compaction().is_none());
+        assert_objects(&engine, &expected);
+
+        let mut reopened_engine = match engine.state() {
+            EngineState::Disk(manifest) => DiskEngine::from(manifest),
+            state => panic!("unexpected state {:?}", state),
+        };
+
+        assert!(reopened_engine.get("0001").is_err());
+        reopened_engine.open(&directory).unwrap();
+        assert_objects(&reopened_engine, &expected);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_tombstones() {
+        // Tombstones are only dropped by compactions into the lowest level
+        // with segments.
+        let directory = make_directory();
+        let mut engine = DiskEngine::make(&directory);
+
+        engine.insert("a", make_object("a", 1)).unwrap();
+        engine.insert("b", make_object("b", 1)).unwrap();
+        engine.flush().unwrap();
+
+        let segment = engine.levels[0].remove(0);
+        engine.levels = vec![vec![], vec![], vec![segment]];
+
+        engine.remove("a").unwrap();
+
+        for i in 0..4 {
+            let object_id = format!("c{}", i);
+            engine.insert(&object_id, make_object(&object_id, 1)).unwrap();
+            engine.flush().unwrap();
+        }
+
+        let mut compaction = engine.compaction().unwrap();
+        compaction.run(0).unwrap();
+        assert!(engine.compact(&compaction));
+
+        assert_eq!(engine.get("a").unwrap(), None);
+        assert_eq!(
+            scan_ids(&engine, Range::default()),
+            vec!["b", "c0", "c1", "c2", "c3"]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_segment() {
+        let directory = make_directory();
+        let mut engine = DiskEngine::make(&directory);
+
+        engine.insert("a", make_object("a", 1)).unwrap();
+        engine.flush().unwrap();
+
+        let path = directory.join(&engine.files()[0]);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(12)).unwrap();
+        file.write_all(b"x").unwrap();
+
+        match engine.get("a") {
+            Err(Error::CorruptedSegment(corrupted_path, 0)) => {
+                assert_eq!(corrupted_path, path)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
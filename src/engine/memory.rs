@@ -0,0 +1,146 @@
+use std::cmp;
+use std::path::Path;
+
+use engine::compaction::Compaction;
+use engine::tree::Tree;
+use engine::{EngineState, Error, StorageEngine};
+use object::Object;
+use range::Range;
+use types::SortedBag;
+
+/// Keeps objects in a persistent tree, so clones share everything they
+/// haven't written to since and scans start from their first object without
+/// going through the ones before it. Their size is kept up to date as
+/// they're written, as storage checks it against its memory limit on every
+/// write.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryEngine {
+    objects: Tree<Object>,
+    size: usize,
+}
+
+impl From<SortedBag<Object>> for MemoryEngine {
+    fn from(objects: SortedBag<Object>) -> Self {
+        let mut engine = Self::default();
+
+        for (object_id, object) in objects {
+            engine.size += object.size();
+            engine.objects.insert(object_id, object);
+        }
+
+        engine
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, object_id: &str) -> Result<Option<Object>, Error> {
+        Ok(self.objects.get(object_id).cloned())
+    }
+
+    fn insert(
+        &mut self,
+        object_id: &str,
+        object: Object,
+    ) -> Result<Option<Object>, Error> {
+        self.size += object.size();
+
+        let previous = self.objects.insert(object_id.to_owned(), object);
+
+        if let Some(ref previous) = previous {
+            self.size -= previous.size();
+        }
+
+        Ok(previous)
+    }
+
+    fn remove(&mut self, object_id: &str) -> Result<Option<Object>, Error> {
+        let previous = self.objects.remove(object_id);
+
+        if let Some(ref previous) = previous {
+            self.size -= previous.size();
+        }
+
+        Ok(previous)
+    }
+
+    fn clear(&mut self) {
+        self.objects = Tree::default();
+        self.size = 0;
+    }
+
+    fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn is_in_memory(&self) -> bool {
+        true
+    }
+
+    fn buffered_size(&self) -> usize {
+        0
+    }
+
+    fn scan<'a>(
+        &'a self,
+        range: Range,
+    ) -> Box<Iterator<Item = Result<(String, Object), Error>> + 'a> {
+        let iterator = if range.reverse {
+            self.objects.seek_back(range.end.as_ref())
+        } else {
+            let start = cmp::max(range.start.as_ref(), range.prefix.as_ref());
+
+            self.objects.seek(start)
+        };
+        let follows_range = range.clone();
+
+        Box::new(
+            iterator
+                .skip_while(move |(object_id, _)| range.precedes(object_id))
+                .take_while(move |(object_id, _)| {
+                    !follows_range.follows(object_id)
+                })
+                .map(|(object_id, object)| {
+                    Ok((object_id.to_owned(), object.to_owned()))
+                }),
+        )
+    }
+
+    fn open(&mut self, _directory: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn compaction(&self) -> Option<Compaction> {
+        None
+    }
+
+    fn compact(&mut self, _compaction: &Compaction) -> bool {
+        false
+    }
+
+    fn files(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn state(&self) -> EngineState {
+        EngineState::Memory(
+            self.objects
+                .seek(None)
+                .map(|(object_id, object)| {
+                    (object_id.to_owned(), object.to_owned())
+                })
+                .collect(),
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<StorageEngine> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json;
+
+use object::Object;
+use range::Range;
+use types::SortedBag;
+
+pub mod bloom;
+pub mod compaction;
+pub mod disk;
+pub mod memory;
+pub mod segment;
+pub mod tree;
+
+use self::compaction::Compaction;
+use self::disk::{DiskEngine, Manifest};
+use self::memory::MemoryEngine;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    CorruptedSegment(PathBuf, u64),
+    SegmentNotOpen(String),
+    InvalidEngine(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::IoError(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::JsonError(error)
+    }
+}
+
+/// Keeps a keyspace's objects, ordered by ID. Keyspaces hold theirs through
+/// `Objects`, and every operation storage applies reads and writes objects
+/// through it.
+///
+/// Engines must be cheap to clone and clones must be independent from each
+/// other: transactions roll back by going back to a clone from before they
+/// started, and snapshots are taken from clones.
+pub trait StorageEngine: Debug + Send + Sync {
+    fn get(&self, object_id: &str) -> Result<Option<Object>, Error>;
+
+    /// Writes `object` with ID `object_id`, returning the one it replaced.
+    fn insert(
+        &mut self,
+        object_id: &str,
+        object: Object,
+    ) -> Result<Option<Object>, Error>;
+
+    fn remove(&mut self, object_id: &str) -> Result<Option<Object>, Error>;
+
+    fn clear(&mut self);
+
+    fn len(&self) -> usize;
+
+    /// The approximate size of the objects as JSON, in bytes.
+    fn size(&self) -> usize;
+
+    /// Whether every object is kept in memory, and counts towards storage's
+    /// memory limit.
+    fn is_in_memory(&self) -> bool;
+
+    /// The approximate size of the writes kept in memory until the next
+    /// flush, in bytes.
+    fn buffered_size(&self) -> usize;
+
+    /// The objects within `range` and their IDs, in the range's order.
+    fn scan<'a>(
+        &'a self,
+        range: Range,
+    ) -> Box<Iterator<Item = Result<(String, Object), Error>> + 'a>;
+
+    /// Opens the files in `directory` the objects are kept in. Engines read
+    /// back from snapshots have to be opened before they're used.
+    fn open(&mut self, directory: &Path) -> Result<(), Error>;
+
+    /// Moves the objects written since the last flush to files, so that the
+    /// engine's state doesn't grow with them.
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// The compaction the engine's files need the most, if any.
+    fn compaction(&self) -> Option<Compaction>;
+
+    /// Installs a compaction that was run, returning whether it could be.
+    fn compact(&mut self, compaction: &Compaction) -> bool;
+
+    /// The names of the files the objects are kept in.
+    fn files(&self) -> Vec<String>;
+
+    fn state(&self) -> EngineState;
+
+    fn boxed_clone(&self) -> Box<StorageEngine>;
+}
+
+/// The engine new keyspaces keep their objects with, as named in the
+/// configuration. Keyspaces keep the engine they were created with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Memory,
+    Disk,
+}
+
+impl Engine {
+    pub fn make(name: &str) -> Result<Self, Error> {
+        match name {
+            "memory" => Ok(Engine::Memory),
+            "disk" => Ok(Engine::Disk),
+            _ => Err(Error::InvalidEngine(name.to_owned())),
+        }
+    }
+
+    /// Objects for a new keyspace. On-disk engines keep their files in
+    /// `directory`.
+    pub fn objects(self, directory: &Path) -> Objects {
+        match self {
+            Engine::Memory => Objects(Box::new(MemoryEngine::default())),
+            Engine::Disk => Objects(Box::new(DiskEngine::make(directory))),
+        }
+    }
+}
+
+/// What snapshots and the write-ahead log hold of an engine: every object
+/// for in-memory ones, and the manifest of their files for on-disk ones.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum EngineState {
+    Disk(Manifest),
+    Memory(SortedBag<Object>),
+}
+
+/// A keyspace's objects, in whichever engine keeps them.
+#[derive(Debug)]
+pub struct Objects(Box<StorageEngine>);
+
+impl Default for Objects {
+    fn default() -> Self {
+        Objects(Box::new(MemoryEngine::default()))
+    }
+}
+
+impl Clone for Objects {
+    fn clone(&self) -> Self {
+        Objects(self.0.boxed_clone())
+    }
+}
+
+impl PartialEq for Objects {
+    fn eq(&self, other: &Objects) -> bool {
+        self.0.state() == other.0.state()
+    }
+}
+
+impl Deref for Objects {
+    type Target = StorageEngine;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl DerefMut for Objects {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.0
+    }
+}
+
+impl Serialize for Objects {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        self.0.state().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Objects {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        match EngineState::deserialize(deserializer)? {
+            EngineState::Disk(manifest) => {
+                Ok(Objects(Box::new(DiskEngine::from(manifest))))
+            }
+            EngineState::Memory(objects) => {
+                Ok(Objects(Box::new(MemoryEngine::from(objects))))
+            }
+        }
+    }
+}
+
+/// Removes the files in `directory` that aren't in `files`, left behind by
+/// flushes and by keyspaces that were truncated or deleted.
+pub fn remove_unused_files(
+    directory: &Path,
+    files: &HashSet<String>,
+) -> Result<(), Error> {
+    if !directory.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_used = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => files.contains(name),
+            None => false,
+        };
+
+        if !is_used && segment::is_segment(&path) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
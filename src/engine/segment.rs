@@ -0,0 +1,499 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_json;
+
+use engine::bloom::BloomFilter;
+use engine::Error;
+use range::Range;
+use utils::make_id_string;
+
+// Segments hold entries in ID order, where an entry is an object or a
+// tombstone for a deleted one, grouped in blocks:
+//
+//   [block]...[meta][meta offset: u64 LE]
+//
+// Blocks and meta are framed as:
+//
+//   [payload length: u32 LE][payload CRC32: u32 LE][payload]
+//
+// Block payloads are entries:
+//
+//   [ID length: u32 LE][ID][value length: u32 LE][value: JSON]
+//
+// where the value of a tombstone is `null`. Meta is JSON, with the first ID
+// and offset of every block, the last ID and a bloom filter of the IDs.
+//
+// Segments are written to a temporary file and renamed into place, and never
+// change afterwards.
+const FRAME_HEADER_LENGTH: u64 = 8;
+
+const FOOTER_LENGTH: u64 = 8;
+
+const BLOCK_SIZE: usize = 4096;
+
+static SEGMENT_EXTENSION: &str = "segment";
+
+static TEMPORARY_EXTENSION: &str = "tmp";
+
+static TOMBSTONE: &[u8] = b"null";
+
+/// An object or a tombstone as JSON, decoded only when it's needed.
+pub type Value = Vec<u8>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct BlockHandle {
+    first_id: String,
+    offset: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Meta {
+    blocks: Vec<BlockHandle>,
+    last_id: String,
+    bloom_filter: BloomFilter,
+}
+
+/// An open segment file. Only its meta is kept in memory, blocks are read
+/// from disk when they're needed.
+#[derive(Debug)]
+pub struct Segment {
+    pub name: String,
+    pub size: u64,
+    path: PathBuf,
+    blocks: Vec<BlockHandle>,
+    last_id: String,
+    bloom_filter: BloomFilter,
+}
+
+/// Iterates over a segment's entries within a range, in the range's order,
+/// reading one block at a time.
+pub struct Entries<'a> {
+    segment: &'a Segment,
+    range: Range,
+    blocks: Box<Iterator<Item = usize> + 'a>,
+    entries: vec::IntoIter<(String, Value)>,
+}
+
+/// Writes entries, which must come in ID order, into segments of about
+/// `target_size` bytes each, or a single one without it. Writing is throttled
+/// to `throughput` bytes per second, if it isn't 0.
+pub struct SegmentWriter {
+    directory: PathBuf,
+    target_size: Option<u64>,
+    throughput: u64,
+    started_at: Instant,
+    written: u64,
+    segment: Option<PartialSegment>,
+    segments: Vec<Segment>,
+}
+
+// A segment being written.
+struct PartialSegment {
+    name: String,
+    path: PathBuf,
+    temporary_path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+    ids: Vec<String>,
+}
+
+pub fn is_tombstone(value: &[u8]) -> bool {
+    value == TOMBSTONE
+}
+
+/// Whether `path` is a segment, or a temporary file left behind by a write
+/// that didn't finish.
+pub fn is_segment(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => {
+            extension == SEGMENT_EXTENSION || extension == TEMPORARY_EXTENSION
+        }
+        None => false,
+    }
+}
+
+impl Segment {
+    pub fn open(directory: &Path, name: &str) -> Result<Self, Error> {
+        let path = directory.join(name);
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+
+        if size < FOOTER_LENGTH {
+            return Err(Error::CorruptedSegment(path, 0));
+        }
+
+        file.seek(SeekFrom::Start(size - FOOTER_LENGTH))?;
+        let meta_offset = file.read_u64::<LittleEndian>()?;
+        let meta = read_frame(&mut file, &path, meta_offset)?;
+        let meta: Meta = serde_json::from_slice(&meta)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            size,
+            path,
+            blocks: meta.blocks,
+            last_id: meta.last_id,
+            bloom_filter: meta.bloom_filter,
+        })
+    }
+
+    pub fn first_id(&self) -> &str {
+        &self.blocks[0].first_id
+    }
+
+    pub fn last_id(&self) -> &str {
+        &self.last_id
+    }
+
+    /// Whether any of the segment's IDs are between `first_id` and `last_id`,
+    /// both inclusive.
+    pub fn overlaps(&self, first_id: &str, last_id: &str) -> bool {
+        self.first_id() <= last_id && first_id <= self.last_id()
+    }
+
+    pub fn get(&self, object_id: &str) -> Result<Option<Value>, Error> {
+        if !self.overlaps(object_id, object_id)
+            || !self.bloom_filter.may_contain(object_id)
+        {
+            return Ok(None);
+        }
+
+        let block = match self
+            .blocks
+            .binary_search_by(|block| block.first_id.as_str().cmp(object_id))
+        {
+            Ok(block) => block,
+            Err(block) => block - 1,
+        };
+
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(id, _value)| id == object_id)
+            .map(|(_id, value)| value))
+    }
+
+    pub fn entries<'a>(&'a self, range: &Range) -> Entries<'a> {
+        let block_count = self.blocks.len();
+        let blocks = &self.blocks;
+        let skip_range = range.clone();
+        let take_range = range.clone();
+        let indices: Box<Iterator<Item = usize>> = if range.reverse {
+            if range.follows(&self.last_id) {
+                Box::new(iter::empty())
+            } else {
+                // A block only has IDs past its first one.
+                Box::new(
+                    (0..block_count)
+                        .rev()
+                        .skip_while(move |&block| {
+                            skip_range.precedes(&blocks[block].first_id)
+                        })
+                        .take_while(move |&block| {
+                            block + 1 == block_count
+                                || !take_range
+                                    .follows(&blocks[block + 1].first_id)
+                        }),
+                )
+            }
+        } else if range.precedes(&self.last_id) {
+            Box::new(iter::empty())
+        } else {
+            // A block only has IDs before the next one's first one.
+            Box::new(
+                (0..block_count)
+                    .skip_while(move |&block| {
+                        block + 1 < block_count
+                            && skip_range.precedes(&blocks[block + 1].first_id)
+                    })
+                    .take_while(move |&block| {
+                        !take_range.follows(&blocks[block].first_id)
+                    }),
+            )
+        };
+
+        Entries {
+            segment: self,
+            range: range.clone(),
+            blocks: indices,
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(String, Value)>, Error> {
+        let offset = self.blocks[block].offset;
+        let mut file = File::open(&self.path)?;
+        let payload = read_frame(&mut file, &self.path, offset)?;
+        let mut reader = &payload[..];
+        let mut entries = Vec::new();
+
+        while !reader.is_empty() {
+            let id_length = reader.read_u32::<LittleEndian>()?;
+            let mut object_id = vec![0; id_length as usize];
+            reader.read_exact(&mut object_id)?;
+            let object_id = match String::from_utf8(object_id) {
+                Ok(object_id) => object_id,
+                Err(_error) => {
+                    return Err(Error::CorruptedSegment(
+                        self.path.to_owned(),
+                        offset,
+                    ))
+                }
+            };
+            let value_length = reader.read_u32::<LittleEndian>()?;
+            let mut value = vec![0; value_length as usize];
+            reader.read_exact(&mut value)?;
+
+            entries.push((object_id, value));
+        }
+
+        Ok(entries)
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<(String, Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((object_id, value)) = self.entries.next() {
+                if self.range.precedes(&object_id) {
+                    continue;
+                }
+
+                if self.range.follows(&object_id) {
+                    self.blocks = Box::new(iter::empty());
+                    self.entries = Vec::new().into_iter();
+                    return None;
+                }
+
+                return Some(Ok((object_id, value)));
+            }
+
+            let block = self.blocks.next()?;
+
+            match self.segment.read_block(block) {
+                Ok(mut entries) => {
+                    if self.range.reverse {
+                        entries.reverse();
+                    }
+                    self.entries = entries.into_iter();
+                }
+                Err(error) => {
+                    self.blocks = Box::new(iter::empty());
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+impl SegmentWriter {
+    pub fn make(
+        directory: &Path,
+        target_size: Option<u64>,
+        throughput: u64,
+    ) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            target_size,
+            throughput,
+            started_at: Instant::now(),
+            written: 0,
+            segment: None,
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        object_id: String,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        if self.segment.is_none() {
+            self.segment = Some(PartialSegment::create(&self.directory)?);
+        }
+
+        let written = match self.segment {
+            Some(ref mut segment) => segment.add(object_id, value)?,
+            None => 0,
+        };
+
+        self.throttle(written);
+
+        let is_full = match (self.segment.as_ref(), self.target_size) {
+            (Some(segment), Some(target_size)) => {
+                segment.offset >= target_size
+            }
+            _ => false,
+        };
+
+        if is_full {
+            self.finish_segment()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the segments written, in ID order.
+    pub fn finish(mut self) -> Result<Vec<Segment>, Error> {
+        self.finish_segment()?;
+
+        if !self.segments.is_empty() {
+            File::open(&self.directory)?.sync_all()?;
+        }
+
+        Ok(self.segments)
+    }
+
+    fn finish_segment(&mut self) -> Result<(), Error> {
+        if let Some(segment) = self.segment.take() {
+            let segment = segment.finish()?;
+            self.throttle(segment.size);
+            self.segments.push(segment);
+        }
+
+        Ok(())
+    }
+
+    // Sleeps for as long as writing is ahead of the throughput.
+    fn throttle(&mut self, written: u64) {
+        if self.throughput == 0 {
+            return;
+        }
+
+        self.written += written;
+
+        let target =
+            Duration::from_millis(self.written * 1000 / self.throughput);
+        let elapsed = self.started_at.elapsed();
+
+        if target > elapsed {
+            thread::sleep(target - elapsed);
+        }
+    }
+}
+
+impl PartialSegment {
+    fn create(directory: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(directory)?;
+
+        let name = format!("{}.{}", make_id_string(), SEGMENT_EXTENSION);
+        let path = directory.join(&name);
+        let temporary_path = path.with_extension(TEMPORARY_EXTENSION);
+        let writer = BufWriter::new(File::create(&temporary_path)?);
+
+        Ok(Self {
+            name,
+            path,
+            temporary_path,
+            writer,
+            offset: 0,
+            block: Vec::new(),
+            blocks: Vec::new(),
+            ids: Vec::new(),
+        })
+    }
+
+    // Returns how many bytes were written to the file.
+    fn add(&mut self, object_id: String, value: &[u8]) -> Result<u64, Error> {
+        if self.block.is_empty() {
+            self.blocks.push(BlockHandle {
+                first_id: object_id.to_owned(),
+                offset: self.offset,
+            });
+        }
+
+        self.block.write_u32::<LittleEndian>(object_id.len() as u32)?;
+        self.block.write_all(object_id.as_bytes())?;
+        self.block.write_u32::<LittleEndian>(value.len() as u32)?;
+        self.block.write_all(value)?;
+        self.ids.push(object_id);
+
+        if self.block.len() >= BLOCK_SIZE {
+            return self.write_block();
+        }
+
+        Ok(0)
+    }
+
+    fn write_block(&mut self) -> Result<u64, Error> {
+        if self.block.is_empty() {
+            return Ok(0);
+        }
+
+        let written = write_frame(&mut self.writer, &self.block)?;
+        self.offset += written;
+        self.block.clear();
+
+        Ok(written)
+    }
+
+    fn finish(mut self) -> Result<Segment, Error> {
+        self.write_block()?;
+
+        let meta = Meta {
+            blocks: self.blocks,
+            last_id: self.ids.last().cloned().unwrap_or_default(),
+            bloom_filter: BloomFilter::make(
+                self.ids.iter().map(String::as_str),
+                self.ids.len(),
+            ),
+        };
+        let meta_offset = self.offset;
+        let size = meta_offset
+            + write_frame(&mut self.writer, &serde_json::to_vec(&meta)?)?
+            + FOOTER_LENGTH;
+
+        self.writer.write_u64::<LittleEndian>(meta_offset)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.temporary_path, &self.path)?;
+
+        Ok(Segment {
+            name: self.name,
+            size,
+            path: self.path,
+            blocks: meta.blocks,
+            last_id: meta.last_id,
+            bloom_filter: meta.bloom_filter,
+        })
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<u64, Error> {
+    writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+    writer.write_u32::<LittleEndian>(crc32::checksum_ieee(payload))?;
+    writer.write_all(payload)?;
+
+    Ok(FRAME_HEADER_LENGTH + payload.len() as u64)
+}
+
+fn read_frame(
+    file: &mut File,
+    path: &Path,
+    offset: u64,
+) -> Result<Vec<u8>, Error> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let length = file.read_u32::<LittleEndian>()?;
+    let checksum = file.read_u32::<LittleEndian>()?;
+    let mut payload = vec![0; length as usize];
+    file.read_exact(&mut payload)?;
+
+    if crc32::checksum_ieee(&payload) != checksum {
+        return Err(Error::CorruptedSegment(path.to_owned(), offset));
+    }
+
+    Ok(payload)
+}
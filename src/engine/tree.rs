@@ -0,0 +1,381 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::sync::Arc;
+
+/// A persistent map with string keys, ordered by key. Clones share every
+/// node neither of them has written to since, and unlike `im`'s ordered
+/// maps it can be iterated over from any key on without going through the
+/// keys before it.
+///
+/// It's a treap whose priorities are hashes of the keys, so that it stays
+/// balanced whatever order keys are inserted in. Hashes are keyed randomly
+/// for each tree, so that keys can't be picked to unbalance it either.
+#[derive(Clone, Debug)]
+pub struct Tree<V> {
+    root: Link<V>,
+    len: usize,
+    hasher: RandomState,
+}
+
+type Link<V> = Option<Arc<Node<V>>>;
+
+#[derive(Clone, Debug)]
+struct Node<V> {
+    key: String,
+    value: V,
+    priority: u64,
+    left: Link<V>,
+    right: Link<V>,
+}
+
+/// Entries of a tree in key order, or in reverse key order.
+pub struct Iter<'a, V: 'a> {
+    // The nodes still to be yielded whose subtrees in iteration order
+    // haven't been visited yet, the next one last.
+    stack: Vec<&'a Node<V>>,
+    reverse: bool,
+}
+
+impl<V> Default for Tree<V> {
+    fn default() -> Self {
+        Tree {
+            root: None,
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V: Clone> Tree<V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        let mut link = &self.root;
+
+        while let Some(ref node) = *link {
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => link = &node.right,
+                Ordering::Equal => return Some(&node.value),
+            }
+        }
+
+        None
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaced.
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        let priority = self.priority(&key);
+        let previous = insert(&mut self.root, key, value, priority);
+
+        if previous.is_none() {
+            self.len += 1;
+        }
+
+        previous
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let previous = remove(&mut self.root, key);
+
+        if previous.is_some() {
+            self.len -= 1;
+        }
+
+        previous
+    }
+
+    /// The entries with keys from `key` on, in key order, or every entry if
+    /// it's `None`.
+    pub fn seek<'a>(&'a self, key: Option<&String>) -> Iter<'a, V> {
+        self.seek_in_order(key, false)
+    }
+
+    /// The entries with keys before `key`, in reverse key order, or every
+    /// entry if it's `None`.
+    pub fn seek_back<'a>(&'a self, key: Option<&String>) -> Iter<'a, V> {
+        self.seek_in_order(key, true)
+    }
+
+    fn priority(&self, key: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(key.as_bytes());
+        hasher.finish()
+    }
+
+    // Goes down from the root, stacking the nodes within bounds on the way
+    // to the first of them.
+    fn seek_in_order<'a>(
+        &'a self,
+        key: Option<&String>,
+        reverse: bool,
+    ) -> Iter<'a, V> {
+        let mut stack = Vec::new();
+        let mut link = &self.root;
+
+        while let Some(ref node) = *link {
+            let is_within_bounds = match key {
+                Some(key) if reverse => node.key < *key,
+                Some(key) => node.key >= *key,
+                None => true,
+            };
+
+            if is_within_bounds {
+                stack.push(&**node);
+            }
+
+            link = if is_within_bounds == reverse {
+                &node.right
+            } else {
+                &node.left
+            };
+        }
+
+        Iter { stack, reverse }
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        let mut link = if self.reverse { &node.left } else { &node.right };
+
+        while let Some(ref child) = *link {
+            self.stack.push(&**child);
+            link = if self.reverse { &child.right } else { &child.left };
+        }
+
+        Some((&node.key, &node.value))
+    }
+}
+
+fn link_priority<V>(link: &Link<V>) -> u64 {
+    match *link {
+        Some(ref node) => node.priority,
+        None => 0,
+    }
+}
+
+// Nodes are copied on the way down unless nothing else shares them, and
+// rotated back up past parents with a lower priority.
+fn insert<V: Clone>(
+    link: &mut Link<V>,
+    key: String,
+    value: V,
+    priority: u64,
+) -> Option<V> {
+    if link.is_none() {
+        *link = Some(Arc::new(Node {
+            key,
+            value,
+            priority,
+            left: None,
+            right: None,
+        }));
+        return None;
+    }
+
+    let (previous, rotation) = {
+        let node = Arc::make_mut(link.as_mut().unwrap());
+
+        match key.cmp(&node.key) {
+            Ordering::Equal => {
+                (Some(mem::replace(&mut node.value, value)), None)
+            }
+            Ordering::Less => {
+                let previous = insert(&mut node.left, key, value, priority);
+                let rotation = if link_priority(&node.left) > node.priority {
+                    Some(Ordering::Less)
+                } else {
+                    None
+                };
+                (previous, rotation)
+            }
+            Ordering::Greater => {
+                let previous = insert(&mut node.right, key, value, priority);
+                let rotation = if link_priority(&node.right) > node.priority
+                {
+                    Some(Ordering::Greater)
+                } else {
+                    None
+                };
+                (previous, rotation)
+            }
+        }
+    };
+
+    match rotation {
+        Some(Ordering::Less) => rotate_right(link),
+        Some(_) => rotate_left(link),
+        None => (),
+    }
+
+    previous
+}
+
+fn remove<V: Clone>(link: &mut Link<V>, key: &str) -> Option<V> {
+    let ordering = match *link {
+        Some(ref node) => key.cmp(&node.key),
+        None => return None,
+    };
+
+    match ordering {
+        Ordering::Less => {
+            remove(&mut Arc::make_mut(link.as_mut().unwrap()).left, key)
+        }
+        Ordering::Greater => {
+            remove(&mut Arc::make_mut(link.as_mut().unwrap()).right, key)
+        }
+        Ordering::Equal => {
+            let node = match Arc::try_unwrap(link.take().unwrap()) {
+                Ok(node) => node,
+                Err(node) => (*node).clone(),
+            };
+
+            *link = merge(node.left, node.right);
+            Some(node.value)
+        }
+    }
+}
+
+// Joins two subtrees, every key in `left` being lower than the ones in
+// `right`, keeping the nodes with the highest priorities on top.
+fn merge<V: Clone>(left: Link<V>, right: Link<V>) -> Link<V> {
+    match (left, right) {
+        (None, right) => right,
+        (left, None) => left,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority > right.priority {
+                {
+                    let node = Arc::make_mut(&mut left);
+                    let left_right = node.right.take();
+                    node.right = merge(left_right, Some(right));
+                }
+                Some(left)
+            } else {
+                {
+                    let node = Arc::make_mut(&mut right);
+                    let right_left = node.left.take();
+                    node.left = merge(Some(left), right_left);
+                }
+                Some(right)
+            }
+        }
+    }
+}
+
+fn rotate_right<V: Clone>(link: &mut Link<V>) {
+    let mut node = link.take().unwrap();
+    let mut left = Arc::make_mut(&mut node).left.take().unwrap();
+
+    Arc::make_mut(&mut node).left = Arc::make_mut(&mut left).right.take();
+    Arc::make_mut(&mut left).right = Some(node);
+    *link = Some(left);
+}
+
+fn rotate_left<V: Clone>(link: &mut Link<V>) {
+    let mut node = link.take().unwrap();
+    let mut right = Arc::make_mut(&mut node).right.take().unwrap();
+
+    Arc::make_mut(&mut node).right = Arc::make_mut(&mut right).left.take();
+    Arc::make_mut(&mut right).left = Some(node);
+    *link = Some(right);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::collections::BTreeMap;
+
+    use engine::tree::*;
+
+    fn depth<V>(link: &Link<V>) -> usize {
+        match *link {
+            Some(ref node) => {
+                1 + cmp::max(depth(&node.left), depth(&node.right))
+            }
+            None => 0,
+        }
+    }
+
+    #[test]
+    fn test_tree() {
+        let mut tree = Tree::default();
+        let mut map = BTreeMap::new();
+        let mut clones = Vec::new();
+
+        for i in 0..2000u64 {
+            let key = format!("{:04}", (i * 7919) % 1000);
+
+            if i % 3 == 0 {
+                assert_eq!(tree.remove(&key), map.remove(&key));
+            } else {
+                assert_eq!(tree.insert(key.to_owned(), i), map.insert(key, i));
+            }
+
+            if i % 500 == 0 {
+                clones.push((tree.clone(), map.clone()));
+            }
+        }
+
+        clones.push((tree, map));
+
+        // Clones are left as they were when they were taken.
+        for (tree, map) in clones {
+            assert_eq!(tree.len(), map.len());
+            assert_eq!(tree.get("0007"), map.get("0007"));
+
+            let keys = vec![
+                None,
+                Some("0000".to_owned()),
+                Some("0500".to_owned()),
+                Some("0500\u{0}".to_owned()),
+            ];
+
+            for key in &keys {
+                let entries: Vec<(&String, &u64)> = match *key {
+                    Some(ref key) => map.range(key.to_owned()..).collect(),
+                    None => map.iter().collect(),
+                };
+                let seeked: Vec<(&String, &u64)> =
+                    tree.seek(key.as_ref()).collect();
+                assert_eq!(seeked, entries);
+
+                let entries: Vec<(&String, &u64)> = match *key {
+                    Some(ref key) => {
+                        map.range(..key.to_owned()).rev().collect()
+                    }
+                    None => map.iter().rev().collect(),
+                };
+                let seeked: Vec<(&String, &u64)> =
+                    tree.seek_back(key.as_ref()).collect();
+                assert_eq!(seeked, entries);
+            }
+        }
+    }
+
+    #[test]
+    fn test_balance() {
+        // Trees stay balanced when keys are inserted in order, and priorities
+        // differ from one tree to another.
+        let mut tree = Tree::default();
+
+        for i in 0..10_000 {
+            tree.insert(format!("{:05}", i), i);
+        }
+
+        assert!(depth(&tree.root) < 100);
+
+        let trees: Vec<Tree<u64>> = (0..4).map(|_| Tree::default()).collect();
+        let priorities: Vec<u64> =
+            trees.iter().map(|tree| tree.priority("0")).collect();
+
+        assert!(priorities.iter().any(|priority| *priority != priorities[0]));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// How writes to a keyspace that would take storage over its memory limit
+/// make room: by evicting the least recently or least frequently used
+/// objects of the keyspace. Writes to keyspaces without one are rejected.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    Lru,
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(name.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// How many objects were evicted from a keyspace, and their approximate size
+/// as JSON in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Evictions {
+    pub object_count: u64,
+    pub size: usize,
+}
+
+// When an object was last used, on the clock of `Accesses`, and how many
+// times it was.
+#[derive(Clone, Copy, Debug)]
+struct Access {
+    last: u64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+struct KeyspaceAccesses {
+    objects: HashMap<String, Access>,
+    // Ordered by last use, and by use count then last use, so that victims
+    // are found without going through every object.
+    recency: BTreeSet<(u64, String)>,
+    frequency: BTreeSet<(u64, u64, String)>,
+}
+
+/// Uses of the objects of keyspaces that evict them, and evictions from
+/// every keyspace since storage was opened.
+///
+/// Uses aren't journaled: they only decide which objects get evicted, and
+/// evictions are journaled as deletes.
+#[derive(Debug, Default)]
+pub struct Accesses {
+    clock: u64,
+    keyspaces: HashMap<String, KeyspaceAccesses>,
+    evictions: HashMap<String, Evictions>,
+}
+
+impl Accesses {
+    pub fn is_tracking(&self, keyspace_name: &str) -> bool {
+        self.keyspaces.contains_key(keyspace_name)
+    }
+
+    /// Starts tracking uses of the objects of the keyspace.
+    pub fn track(&mut self, keyspace_name: &str) {
+        self.keyspaces.entry(keyspace_name.to_owned()).or_default();
+    }
+
+    /// Records a use of the object, if the keyspace's are tracked.
+    pub fn touch(&mut self, keyspace_name: &str, object_id: &str) {
+        let keyspace = match self.keyspaces.get_mut(keyspace_name) {
+            Some(keyspace) => keyspace,
+            None => return,
+        };
+
+        self.clock += 1;
+
+        let access = match keyspace.objects.get(object_id) {
+            Some(access) => {
+                keyspace.recency.remove(&(access.last, object_id.to_owned()));
+                keyspace.frequency.remove(&(
+                    access.count,
+                    access.last,
+                    object_id.to_owned(),
+                ));
+                Access {
+                    last: self.clock,
+                    count: access.count + 1,
+                }
+            }
+            None => Access {
+                last: self.clock,
+                count: 1,
+            },
+        };
+
+        keyspace.objects.insert(object_id.to_owned(), access);
+        keyspace.recency.insert((access.last, object_id.to_owned()));
+        keyspace
+            .frequency
+            .insert((access.count, access.last, object_id.to_owned()));
+    }
+
+    pub fn forget(&mut self, keyspace_name: &str, object_id: &str) {
+        let keyspace = match self.keyspaces.get_mut(keyspace_name) {
+            Some(keyspace) => keyspace,
+            None => return,
+        };
+
+        if let Some(access) = keyspace.objects.remove(object_id) {
+            keyspace.recency.remove(&(access.last, object_id.to_owned()));
+            keyspace.frequency.remove(&(
+                access.count,
+                access.last,
+                object_id.to_owned(),
+            ));
+        }
+    }
+
+    /// Stops tracking uses of the objects of the keyspace. Its evictions are
+    /// kept until `forget_evictions`.
+    pub fn forget_keyspace(&mut self, keyspace_name: &str) {
+        self.keyspaces.remove(keyspace_name);
+    }
+
+    /// The object of the keyspace that `policy` evicts first, leaving out
+    /// the ones in `excluded_object_ids`.
+    pub fn victim(
+        &self,
+        keyspace_name: &str,
+        policy: EvictionPolicy,
+        excluded_object_ids: &[String],
+    ) -> Option<String> {
+        let keyspace = self.keyspaces.get(keyspace_name)?;
+        let mut object_ids: Box<Iterator<Item = &String>> = match policy {
+            EvictionPolicy::Lru => Box::new(
+                keyspace.recency.iter().map(|(_, object_id)| object_id),
+            ),
+            EvictionPolicy::Lfu => Box::new(
+                keyspace.frequency.iter().map(|(_, _, object_id)| object_id),
+            ),
+        };
+
+        object_ids
+            .find(|object_id| !excluded_object_ids.contains(*object_id))
+            .cloned()
+    }
+
+    pub fn record_eviction(&mut self, keyspace_name: &str, size: usize) {
+        let evictions = self
+            .evictions
+            .entry(keyspace_name.to_owned())
+            .or_default();
+
+        evictions.object_count += 1;
+        evictions.size += size;
+    }
+
+    pub fn evictions(&self, keyspace_name: &str) -> Evictions {
+        self.evictions
+            .get(keyspace_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn forget_evictions(&mut self, keyspace_name: &str) {
+        self.evictions.remove(keyspace_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eviction::*;
+
+    #[test]
+    fn test_victim() {
+        let mut accesses = Accesses::default();
+
+        accesses.touch("people", "1");
+        assert!(!accesses.is_tracking("people"));
+        assert_eq!(accesses.victim("people", EvictionPolicy::Lru, &[]), None);
+
+        accesses.track("people");
+        accesses.touch("people", "1");
+        accesses.touch("people", "2");
+        accesses.touch("people", "1");
+        accesses.touch("people", "3");
+
+        assert_eq!(
+            accesses.victim("people", EvictionPolicy::Lru, &[]),
+            Some("2".to_owned())
+        );
+        assert_eq!(
+            accesses.victim("people", EvictionPolicy::Lfu, &[]),
+            Some("2".to_owned())
+        );
+        assert_eq!(
+            accesses.victim("people", EvictionPolicy::Lfu, &["2".to_owned()]),
+            Some("3".to_owned())
+        );
+
+        accesses.touch("people", "2");
+        accesses.touch("people", "2");
+        accesses.forget("people", "3");
+
+        assert_eq!(
+            accesses.victim("people", EvictionPolicy::Lru, &[]),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            accesses.victim("people", EvictionPolicy::Lfu, &[]),
+            Some("1".to_owned())
+        );
+
+        accesses.record_eviction("people", 10);
+        accesses.record_eviction("people", 5);
+        accesses.forget_keyspace("people");
+
+        assert!(!accesses.is_tracking("people"));
+        assert_eq!(
+            accesses.evictions("people"),
+            Evictions {
+                object_count: 2,
+                size: 15,
+            }
+        );
+    }
+}
@@ -1,7 +1,6 @@
-use std::str;
-
 use actix_web::{
-    AsyncResponder, Error, HttpMessage, HttpRequest, HttpResponse,
+    http::StatusCode, AsyncResponder, Error, HttpMessage, HttpRequest,
+    HttpResponse,
 };
 use bytes::Bytes;
 use futures::future::Future;
@@ -15,6 +14,13 @@ pub fn json_error(error: serde_json::Error) -> String {
     json_body(&json!({ "error": format!("{:?}", error) }))
 }
 
+pub fn json_error_message(message: &str) -> String {
+    json_body(&json!({ "error": message }))
+}
+
+/// Makes a handler that parses the request body as JSON before handing it to
+/// `handler`. Empty bodies are handed over as null, and bodies that aren't
+/// JSON get a 400 response.
 pub fn make_handler_for_request_with_body(
     handler: &'static for<'r> Fn(&HttpRequest, serde_json::Value)
         -> Result<HttpResponse, Error>,
@@ -24,10 +30,37 @@ pub fn make_handler_for_request_with_body(
             .body()
             .from_err()
             .and_then(move |bytes: Bytes| -> Result<HttpResponse, Error> {
-                let body: serde_json::Value = serde_json::from_str(
-                    str::from_utf8(&bytes).unwrap(),
-                ).unwrap_or(json!(null));
-                handler(&request, body)
+                if bytes.iter().all(|byte| byte.is_ascii_whitespace()) {
+                    return handler(&request, json!(null));
+                }
+
+                match serde_json::from_slice(&bytes) {
+                    Ok(body) => handler(&request, body),
+                    Err(error) => {
+                        Ok(HttpResponse::build(StatusCode::BAD_REQUEST)
+                            .content_type("application/json")
+                            .body(json_error(error)))
+                    }
+                }
+            })
+            .responder()
+    })
+}
+
+/// Like `make_handler_for_request_with_body`, but hands the body over as is
+/// and accepts bodies of up to `limit` bytes instead of actix's default.
+pub fn make_handler_for_request_with_raw_body(
+    limit: usize,
+    handler: &'static for<'r> Fn(&HttpRequest, Bytes)
+        -> Result<HttpResponse, Error>,
+) -> Box<Fn(HttpRequest) -> Box<Future<Item = HttpResponse, Error = Error>>> {
+    Box::new(move |request: HttpRequest| {
+        request
+            .body()
+            .limit(limit)
+            .from_err()
+            .and_then(move |bytes: Bytes| -> Result<HttpResponse, Error> {
+                handler(&request, bytes)
             })
             .responder()
     })
@@ -0,0 +1,133 @@
+use im::ordmap::{self, OrdMap};
+use im::ordset::OrdSet;
+use serde_json::{self, Value};
+
+use object::Object;
+
+/// A secondary index mapping the value at `path`, a JSON Pointer into object
+/// data, to the IDs of the objects holding it. Objects without a value at
+/// `path` aren't indexed.
+///
+/// Only the definition is persisted: entries are rebuilt from the objects
+/// when storage is opened.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Index {
+    pub path: String,
+    pub unique: bool,
+    #[serde(skip)]
+    entries: OrdMap<String, OrdSet<String>>,
+}
+
+impl Index {
+    pub fn make(path: &str, unique: bool) -> Self {
+        Self {
+            path: path.to_owned(),
+            unique,
+            entries: OrdMap::new(),
+        }
+    }
+
+    /// Values are indexed by their JSON representation, so that the string
+    /// "42" and the number 42 are told apart.
+    pub fn key(value: &Value) -> String {
+        serde_json::to_string(value).unwrap()
+    }
+
+    fn object_key(&self, object: &Object) -> Option<String> {
+        object.pointer(&self.path).map(Self::key)
+    }
+
+    /// The IDs of the other objects a unique index holds with the value of
+    /// `object`, which writing it with ID `object_id` would conflict with.
+    pub fn conflicting_object_ids(
+        &self,
+        object_id: &str,
+        object: &Object,
+    ) -> Vec<String> {
+        if !self.unique {
+            return Vec::new();
+        }
+
+        match self.object_key(object) {
+            Some(key) => self
+                .lookup(&key)
+                .into_iter()
+                .filter(|indexed_object_id| indexed_object_id != object_id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, object_id: &str, object: &Object) {
+        if let Some(key) = self.object_key(object) {
+            self.entries
+                .entry(key)
+                .or_default()
+                .insert(object_id.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, object_id: &str, object: &Object) {
+        if let Some(key) = self.object_key(object) {
+            if let ordmap::Entry::Occupied(mut entry) = self.entries.entry(key)
+            {
+                entry.get_mut().remove(object_id);
+
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = OrdMap::new();
+    }
+
+    /// The IDs of the objects indexed under `key`, in order.
+    pub fn lookup(&self, key: &str) -> Vec<String> {
+        match self.entries.get(key) {
+            Some(object_ids) => object_ids.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use index::*;
+
+    fn make_object(object_id: &str, data: Value) -> Object {
+        Object::make(object_id, serde_json::from_value(data).unwrap())
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = Index::make("/address/city", true);
+        let berlin =
+            make_object("1", json!({ "address": { "city": "Berlin" } }));
+        let lisbon =
+            make_object("2", json!({ "address": { "city": "Lisbon" } }));
+        let nowhere = make_object("3", json!({ "name": "Nobody" }));
+
+        index.insert("1", &berlin);
+        index.insert("2", &lisbon);
+        index.insert("3", &nowhere);
+
+        let berlin_key = Index::key(&json!("Berlin"));
+
+        assert_eq!(index.lookup(&berlin_key), vec!["1".to_owned()]);
+        assert!(index.lookup(&Index::key(&json!("Porto"))).is_empty());
+        assert_eq!(
+            index.conflicting_object_ids("2", &berlin),
+            vec!["1".to_owned()]
+        );
+        assert!(index.conflicting_object_ids("1", &berlin).is_empty());
+        assert!(index.conflicting_object_ids("2", &nowhere).is_empty());
+
+        index.remove("1", &berlin);
+
+        assert!(index.lookup(&berlin_key).is_empty());
+        assert!(index.conflicting_object_ids("2", &berlin).is_empty());
+    }
+}
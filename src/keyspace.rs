@@ -1,13 +1,108 @@
+use std::str::FromStr;
+
 use im::hashmap::HashMap;
+use im::ordmap::OrdMap;
+use im::ordset::OrdSet;
+use serde::de::{Deserialize, Deserializer, Error};
 
+use engine::{self, Objects};
+use eviction::{EvictionPolicy, Evictions};
+use index::Index;
 use object::Object;
-use types::{Bag, Metadata};
+use range::Range;
+use types::{Bag, Metadata, SortedBag};
 use utils::make_id_string;
 
-#[derive(Clone, Debug, PartialEq)]
+static DEFAULT_TTL: &str = "default_ttl";
+
+static MAX_OBJECT_SIZE: &str = "max_object_size";
+
+static MAX_OBJECT_COUNT: &str = "max_object_count";
+
+static REQUIRE_CLIENT_IDS: &str = "require_client_ids";
+
+static HISTORY_RETENTION: &str = "history_retention";
+
+static EVICTION_POLICY: &str = "eviction_policy";
+
+// The most seconds that can be turned into milliseconds, `u64::MAX / 1000`.
+const MAX_SECONDS: u64 = 18_446_744_073_709_551;
+
+static CREATED_AT: &str = "created_at";
+
+static UPDATED_AT: &str = "updated_at";
+
+/// Settings governing the objects written to a keyspace. `default_ttl` and
+/// `history_retention` are in seconds and `max_object_size` in bytes of the
+/// object's JSON. Past versions of objects are only kept with a
+/// `history_retention`. The `eviction_policy` applies once storage is over
+/// its memory limit, and writes past it are rejected without one.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyspaceSettings {
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub default_ttl: Option<u64>,
+    pub max_object_size: Option<usize>,
+    pub max_object_count: Option<usize>,
+    pub require_client_ids: bool,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub history_retention: Option<u64>,
+    pub eviction_policy: Option<EvictionPolicy>,
+}
+
+/// The state of an object as of the operation with `sequence`, applied at
+/// `timestamp`. Deletions have no object.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Revision {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub object: Option<Object>,
+}
+
+impl Revision {
+    /// The approximate size of the revision's object as JSON, in bytes.
+    /// Deletions take none.
+    pub fn size(&self) -> usize {
+        self.object.as_ref().map(Object::size).unwrap_or(0)
+    }
+}
+
+/// A point in a keyspace's history, either the sequence of an operation or
+/// a timestamp in milliseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsOf {
+    Sequence(u64),
+    Timestamp(u64),
+}
+
+/// `size` is the approximate size of the keyspace's objects as JSON, in
+/// bytes. Timestamps are in milliseconds since the Unix epoch. Evictions are
+/// counted from when storage was opened.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct KeyspaceStats {
+    pub object_count: usize,
+    pub size: usize,
+    pub evictions: Evictions,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Keyspace {
     pub metadata: Metadata,
-    pub objects: Bag<Object>,
+    pub objects: Objects,
+    #[serde(default)]
+    pub indexes: Bag<Index>,
+    #[serde(default)]
+    pub history: SortedBag<Vec<Revision>>,
+    // The approximate size of the revisions in `history`, in bytes. It isn't
+    // persisted, and is worked out again whenever keyspaces are loaded.
+    #[serde(skip)]
+    history_size: usize,
+    // When objects expire and their IDs, so that expired objects can be
+    // found without going through every object.
+    #[serde(default)]
+    pub expirations: OrdSet<(u64, String)>,
 }
 
 impl Keyspace {
@@ -18,7 +113,329 @@ impl Keyspace {
 
         Self {
             metadata: metadata,
-            objects: HashMap::new(),
+            objects: Objects::default(),
+            indexes: HashMap::new(),
+            history: OrdMap::new(),
+            history_size: 0,
+            expirations: OrdSet::new(),
         }
     }
+
+    /// Settings are kept in the keyspace's metadata, next to its ID and name.
+    pub fn settings(&self) -> KeyspaceSettings {
+        KeyspaceSettings {
+            default_ttl: parse_metadata(&self.metadata, DEFAULT_TTL),
+            max_object_size: parse_metadata(&self.metadata, MAX_OBJECT_SIZE),
+            max_object_count: parse_metadata(&self.metadata, MAX_OBJECT_COUNT),
+            require_client_ids: parse_metadata(
+                &self.metadata,
+                REQUIRE_CLIENT_IDS,
+            ).unwrap_or(false),
+            history_retention: parse_metadata(
+                &self.metadata,
+                HISTORY_RETENTION,
+            ),
+            eviction_policy: parse_metadata(&self.metadata, EVICTION_POLICY),
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: &KeyspaceSettings) {
+        let mut set = |key: &str, value: Option<String>| match value {
+            Some(value) => {
+                self.metadata.insert(key.to_owned(), value);
+            }
+            None => {
+                self.metadata.remove(key);
+            }
+        };
+
+        set(DEFAULT_TTL, settings.default_ttl.map(|ttl| ttl.to_string()));
+        set(
+            MAX_OBJECT_SIZE,
+            settings.max_object_size.map(|size| size.to_string()),
+        );
+        set(
+            MAX_OBJECT_COUNT,
+            settings.max_object_count.map(|count| count.to_string()),
+        );
+        set(
+            REQUIRE_CLIENT_IDS,
+            Some(settings.require_client_ids.to_string()),
+        );
+        set(
+            HISTORY_RETENTION,
+            settings.history_retention.map(|retention| retention.to_string()),
+        );
+        set(
+            EVICTION_POLICY,
+            settings.eviction_policy.map(|policy| policy.to_string()),
+        );
+    }
+
+    pub fn set_created_at(&mut self, timestamp: u64) {
+        self.metadata
+            .insert(CREATED_AT.to_owned(), timestamp.to_string());
+    }
+
+    pub fn set_updated_at(&mut self, timestamp: u64) {
+        self.metadata
+            .insert(UPDATED_AT.to_owned(), timestamp.to_string());
+    }
+
+    /// The name of a unique index that writing `object` with ID `object_id`
+    /// at `now` would violate, if any. Objects expired at `now` are as
+    /// missing to indexes as they are to writes, even before they're reaped.
+    pub fn index_conflict(
+        &self,
+        object_id: &str,
+        object: &Object,
+        now: u64,
+    ) -> Result<Option<String>, engine::Error> {
+        for (index_name, index) in self.indexes.iter() {
+            let other_object_ids =
+                index.conflicting_object_ids(object_id, object);
+
+            for other_object_id in other_object_ids {
+                match self.objects.get(&other_object_id)? {
+                    Some(ref other) if !other.is_expired(now) => {
+                        return Ok(Some(index_name.to_owned()))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Moves the index entries and expiration of the object with ID
+    /// `object_id` from its `previous` version to its current one. Either is
+    /// `None` when the object is being created or deleted.
+    pub fn reindex_object(
+        &mut self,
+        object_id: &str,
+        previous: Option<&Object>,
+        object: Option<&Object>,
+    ) {
+        if let Some(expires_at) = previous.and_then(Object::expires_at) {
+            self.expirations.remove(&(expires_at, object_id.to_owned()));
+        }
+
+        if let Some(expires_at) = object.and_then(Object::expires_at) {
+            self.expirations.insert((expires_at, object_id.to_owned()));
+        }
+
+        for index in self.indexes.iter_mut() {
+            if let Some(previous) = previous {
+                index.remove(object_id, previous);
+            }
+
+            if let Some(object) = object {
+                index.insert(object_id, object);
+            }
+        }
+    }
+
+    /// Index entries aren't persisted, so they're rebuilt from the objects
+    /// whenever keyspaces are loaded.
+    pub fn rebuild_indexes(&mut self) -> Result<(), engine::Error> {
+        for index in self.indexes.iter_mut() {
+            index.clear();
+        }
+
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+
+        for entry in self.objects.scan(Range::default()) {
+            let (object_id, object) = entry?;
+
+            for index in self.indexes.iter_mut() {
+                index.insert(&object_id, &object);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records `object` as the state of the object with ID `object_id` as of
+    /// `sequence`, if the keyspace retains history, and prunes the object's
+    /// history at `now`.
+    pub fn record_revision(
+        &mut self,
+        object_id: &str,
+        object: Option<&Object>,
+        sequence: u64,
+        now: u64,
+    ) {
+        let history_retention = match self.settings().history_retention {
+            Some(history_retention) => history_retention,
+            None => {
+                if let Some(revisions) = self.history.remove(object_id) {
+                    self.history_size = self
+                        .history_size
+                        .saturating_sub(revisions_size(&revisions));
+                }
+                return;
+            }
+        };
+        let mut revisions =
+            self.history.get(object_id).cloned().unwrap_or_default();
+        let revision = Revision {
+            sequence,
+            timestamp: now,
+            object: object.cloned(),
+        };
+
+        self.history_size += revision.size();
+        revisions.push(revision);
+
+        let pruned_size =
+            prune_revisions(&mut revisions, now, history_retention);
+        self.history_size = self.history_size.saturating_sub(pruned_size);
+
+        if revisions.is_empty() {
+            self.history.remove(object_id);
+        } else {
+            self.history.insert(object_id.to_owned(), revisions);
+        }
+    }
+
+    /// Drops the revisions older than the keyspace's history retention at
+    /// `now`, or every revision if it has none.
+    pub fn prune_history(&mut self, now: u64) {
+        let history_retention = match self.settings().history_retention {
+            Some(history_retention) => history_retention,
+            None => {
+                self.history = OrdMap::new();
+                self.history_size = 0;
+                return;
+            }
+        };
+        let mut pruned_size = 0;
+
+        self.history = self
+            .history
+            .iter()
+            .filter_map(|(object_id, revisions)| {
+                let mut revisions = revisions.to_owned();
+                pruned_size +=
+                    prune_revisions(&mut revisions, now, history_retention);
+
+                if revisions.is_empty() {
+                    None
+                } else {
+                    Some((object_id.to_owned(), revisions))
+                }
+            })
+            .collect();
+        self.history_size = self.history_size.saturating_sub(pruned_size);
+    }
+
+    /// The approximate size of the retained revisions of the keyspace's
+    /// objects as JSON, in bytes.
+    pub fn history_size(&self) -> usize {
+        self.history_size
+    }
+
+    /// The size of the history isn't persisted either, so it's worked out
+    /// again whenever keyspaces are loaded.
+    pub fn rebuild_history_size(&mut self) {
+        self.history_size = self
+            .history
+            .values()
+            .map(|revisions| revisions_size(revisions))
+            .sum();
+    }
+
+    /// The retained revisions of the object with ID `object_id`, oldest
+    /// first.
+    pub fn revisions(&self, object_id: &str) -> &[Revision] {
+        match self.history.get(object_id) {
+            Some(revisions) => revisions,
+            None => &[],
+        }
+    }
+
+    /// The latest revision of the object with ID `object_id` as of `as_of`,
+    /// if it's retained.
+    pub fn revision_as_of(
+        &self,
+        object_id: &str,
+        as_of: AsOf,
+    ) -> Option<&Revision> {
+        self.revisions(object_id)
+            .iter()
+            .rev()
+            .find(|revision| match as_of {
+                AsOf::Sequence(sequence) => revision.sequence <= sequence,
+                AsOf::Timestamp(timestamp) => revision.timestamp <= timestamp,
+            })
+    }
+
+    /// The IDs of the objects expired at `now`, soonest expired first.
+    pub fn expired_object_ids(&self, now: u64) -> Vec<String> {
+        self.expirations
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .map(|(_, object_id)| object_id.to_owned())
+            .collect()
+    }
+
+    pub fn stats(&self) -> KeyspaceStats {
+        KeyspaceStats {
+            object_count: self.objects.len(),
+            size: self.objects.size(),
+            evictions: Evictions::default(),
+            created_at: parse_metadata(&self.metadata, CREATED_AT)
+                .unwrap_or(0),
+            updated_at: parse_metadata(&self.metadata, UPDATED_AT)
+                .unwrap_or(0),
+        }
+    }
+}
+
+// Revisions from before the retention period are dropped, except for the
+// latest of them when it's still the object's state at the start of the
+// period. Returns the size of the revisions that were dropped.
+fn prune_revisions(
+    revisions: &mut Vec<Revision>,
+    now: u64,
+    history_retention: u64,
+) -> usize {
+    let cutoff = now.saturating_sub(history_retention.saturating_mul(1000));
+    let expired_count = revisions
+        .iter()
+        .take_while(|revision| revision.timestamp < cutoff)
+        .count();
+    let mut pruned_count = expired_count.saturating_sub(1);
+
+    if expired_count > 0 && revisions[pruned_count].object.is_none() {
+        pruned_count += 1;
+    }
+
+    revisions
+        .drain(..pruned_count)
+        .map(|revision| revision.size())
+        .sum()
+}
+
+fn revisions_size(revisions: &[Revision]) -> usize {
+    revisions.iter().map(Revision::size).sum()
+}
+
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(seconds) if seconds > MAX_SECONDS => Err(D::Error::custom(
+            format!("at most {} seconds are allowed", MAX_SECONDS),
+        )),
+        seconds => Ok(seconds),
+    }
+}
+
+fn parse_metadata<T: FromStr>(metadata: &Metadata, key: &str) -> Option<T> {
+    metadata.get(key).and_then(|value| value.parse().ok())
 }
@@ -1,6 +1,8 @@
 extern crate actix_web;
+extern crate byteorder;
 extern crate bytes;
 extern crate config;
+extern crate crc;
 #[macro_use]
 extern crate crossbeam_channel;
 extern crate either;
@@ -22,15 +24,24 @@ extern crate signal_hook;
 extern crate state;
 extern crate uuid;
 
+mod aggregation;
 mod cluster;
 mod components;
+mod engine;
+mod eviction;
 mod http_utils;
+mod index;
 mod keyspace;
 mod node;
 mod object;
+mod patch;
+mod query;
+mod range;
+mod snapshot;
 mod storage;
 mod types;
 mod utils;
+mod wal;
 
 use components::configuration::Configuration;
 use components::logging::Logging;
@@ -43,12 +54,21 @@ fn main() {
     info!("{:#?}", c);
 
     components::cluster::initialize();
+    components::storage::initialize();
 
     let http_resources_thread = components::http_resources::spawn();
     let http_transport_thread = components::http_transport::spawn();
     let http_transport_pinger_thread =
         components::http_transport_pinger::spawn();
+    let storage_snapshotter_thread = components::storage_snapshotter::spawn();
+    let storage_reaper_thread = components::storage_reaper::spawn();
+    let storage_compactor_thread = components::storage_compactor::spawn();
+    let storage_wal_syncer_thread = components::storage_wal_syncer::spawn();
     http_transport_thread.join().unwrap();
     http_resources_thread.join().unwrap();
     http_transport_pinger_thread.join().unwrap();
+    storage_snapshotter_thread.join().unwrap();
+    storage_reaper_thread.join().unwrap();
+    storage_compactor_thread.join().unwrap();
+    storage_wal_syncer_thread.join().unwrap();
 }
@@ -1,10 +1,11 @@
 use im::hashmap::HashMap;
+use serde_json::{self, Value};
 
 use types::{Bag, Metadata};
 
-pub type ObjectData = Bag<String>;
+pub type ObjectData = Bag<Value>;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Object {
     pub metadata: Metadata,
     pub data: ObjectData,
@@ -15,11 +16,67 @@ impl Object {
         let mut metadata: Metadata = HashMap::new();
         metadata.insert("id".to_owned(), id.to_owned());
 
-        data.insert("id".to_string(), id.to_string());
+        data.insert("id".to_string(), Value::String(id.to_string()));
 
         Self {
             metadata: metadata,
             data: data,
         }
     }
+
+    /// The value at `pointer`, a JSON Pointer into the object's data.
+    pub fn pointer(&self, pointer: &str) -> Option<&Value> {
+        if !pointer.starts_with('/') {
+            return None;
+        }
+
+        let (token, rest) = match pointer[1..].find('/') {
+            Some(index) => (&pointer[1..index + 1], &pointer[index + 1..]),
+            None => (&pointer[1..], ""),
+        };
+        let token = token.replace("~1", "/").replace("~0", "~");
+
+        self.data.get(&token).and_then(|value| value.pointer(rest))
+    }
+
+    /// The sequence of the operation that last wrote the object, or 0 if it
+    /// was never written.
+    pub fn version(&self) -> u64 {
+        self.metadata
+            .get("version")
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn set_version(&mut self, version: u64) {
+        self.metadata
+            .insert("version".to_owned(), version.to_string());
+    }
+
+    /// The size of the object's data as JSON, in bytes.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(&self.data)
+            .map(|data| data.len())
+            .unwrap_or(0)
+    }
+
+    /// When the object expires, in milliseconds since the Unix epoch, if it
+    /// was written with a TTL.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.metadata
+            .get("expires_at")
+            .and_then(|expires_at| expires_at.parse().ok())
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: u64) {
+        self.metadata
+            .insert("expires_at".to_owned(), expires_at.to_string());
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at() {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
 }
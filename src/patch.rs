@@ -0,0 +1,401 @@
+use serde_json::{Map, Number, Value};
+
+/// A partial update to a JSON document, either an RFC 7396 JSON Merge Patch
+/// or an RFC 6902 JSON Patch.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Patch {
+    MergePatch(Value),
+    JsonPatch(Vec<PatchOperation>),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    InvalidDocument,
+    InvalidPointer(String),
+    PathNotFound(String),
+    TestFailed(String),
+    NotANumber(String),
+    Overflow(String),
+}
+
+use self::Error::*;
+use self::PatchOperation::*;
+
+impl Patch {
+    /// Returns a patched copy of `document`. Either every operation in the
+    /// patch is applied or none is.
+    pub fn apply(&self, document: &Value) -> Result<Value, Error> {
+        let mut document = document.clone();
+
+        match *self {
+            Patch::MergePatch(ref patch) => merge(&mut document, patch),
+            Patch::JsonPatch(ref operations) => {
+                for operation in operations {
+                    apply_operation(&mut document, operation)?;
+                }
+            }
+        }
+
+        Ok(document)
+    }
+}
+
+/// Returns a copy of `document` with `delta` added to the number at `path`,
+/// a JSON Pointer. Missing numbers count as 0 and missing objects on the way
+/// to them are created. Integers stay integers unless either side isn't one.
+pub fn increment(
+    document: &Value,
+    path: &str,
+    delta: &Number,
+) -> Result<Value, Error> {
+    let mut document = document.clone();
+    let (parent_path, token) = split_pointer(path)?;
+    let mut parent = &mut document;
+
+    for token in parent_path.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+
+        parent = match *{ parent } {
+            Value::Object(ref mut object) => {
+                object.entry(token).or_insert_with(|| json!({}))
+            }
+            Value::Array(ref mut array) => match token.parse::<usize>() {
+                Ok(index) if index < array.len() => &mut array[index],
+                _ => return Err(PathNotFound(path.to_owned())),
+            },
+            _ => return Err(PathNotFound(path.to_owned())),
+        };
+    }
+
+    let target = match *parent {
+        Value::Object(ref mut object) => {
+            object.entry(token).or_insert_with(|| json!(0))
+        }
+        Value::Array(ref mut array) => match token.parse::<usize>() {
+            Ok(index) if index < array.len() => &mut array[index],
+            _ => return Err(PathNotFound(path.to_owned())),
+        },
+        _ => return Err(PathNotFound(path.to_owned())),
+    };
+    let sum = match *target {
+        Value::Number(ref number) => add_numbers(number, delta),
+        _ => return Err(NotANumber(path.to_owned())),
+    };
+
+    match sum {
+        Some(sum) => *target = Value::Number(sum),
+        None => return Err(Overflow(path.to_owned())),
+    }
+
+    Ok(document)
+}
+
+// Integers are added as such as long as the sum fits in an `i64` or `u64`,
+// and everything else as floats.
+fn add_numbers(a: &Number, b: &Number) -> Option<Number> {
+    let integer = |number: &Number| {
+        number
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| number.as_u64().map(i128::from))
+    };
+
+    match (integer(a), integer(b)) {
+        (Some(a), Some(b)) => {
+            let sum = a + b;
+
+            if sum < 0 && i128::from(sum as i64) == sum {
+                Some(Number::from(sum as i64))
+            } else if sum >= 0 && i128::from(sum as u64) == sum {
+                Some(Number::from(sum as u64))
+            } else {
+                None
+            }
+        }
+        _ => Number::from_f64(a.as_f64()? + b.as_f64()?),
+    }
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    match *patch {
+        Value::Object(ref patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+
+            if let Value::Object(ref mut target) = *target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        target.remove(key);
+                    } else {
+                        merge(
+                            target.entry(key.to_owned()).or_insert(Value::Null),
+                            value,
+                        );
+                    }
+                }
+            }
+        }
+        _ => *target = patch.to_owned(),
+    }
+}
+
+fn apply_operation(
+    document: &mut Value,
+    operation: &PatchOperation,
+) -> Result<(), Error> {
+    match *operation {
+        Add {
+            ref path,
+            ref value,
+        } => add(document, path, value.to_owned()),
+        Remove { ref path } => remove(document, path).map(|_value| ()),
+        Replace {
+            ref path,
+            ref value,
+        } => match document.pointer_mut(path) {
+            Some(target) => {
+                *target = value.to_owned();
+                Ok(())
+            }
+            None => Err(PathNotFound(path.to_owned())),
+        },
+        Move { ref from, ref path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(InvalidPointer(path.to_owned()));
+            }
+
+            let value = remove(document, from)?;
+            add(document, path, value)
+        }
+        Copy { ref from, ref path } => match document.pointer(from) {
+            Some(value) => {
+                let value = value.to_owned();
+                add(document, path, value)
+            }
+            None => Err(PathNotFound(from.to_owned())),
+        },
+        Test {
+            ref path,
+            ref value,
+        } => match document.pointer(path) {
+            Some(target) if target == value => Ok(()),
+            _ => Err(TestFailed(path.to_owned())),
+        },
+    }
+}
+
+// Splits a JSON Pointer into the pointer to its parent and its unescaped last
+// reference token.
+fn split_pointer(path: &str) -> Result<(&str, String), Error> {
+    match path.rfind('/') {
+        Some(index) => {
+            let token = path[index + 1..].replace("~1", "/").replace("~0", "~");
+            Ok((&path[..index], token))
+        }
+        None => Err(InvalidPointer(path.to_owned())),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), Error> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+
+    let (parent_path, token) = split_pointer(path)?;
+
+    match document.pointer_mut(parent_path) {
+        Some(Value::Object(parent)) => {
+            parent.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(parent)) => {
+            if token == "-" {
+                parent.push(value);
+                return Ok(());
+            }
+
+            match token.parse::<usize>() {
+                Ok(index) if index <= parent.len() => {
+                    parent.insert(index, value);
+                    Ok(())
+                }
+                _ => Err(PathNotFound(path.to_owned())),
+            }
+        }
+        _ => Err(PathNotFound(path.to_owned())),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, Error> {
+    let (parent_path, token) = split_pointer(path)?;
+
+    match document.pointer_mut(parent_path) {
+        Some(Value::Object(parent)) => match parent.remove(&token) {
+            Some(value) => Ok(value),
+            None => Err(PathNotFound(path.to_owned())),
+        },
+        Some(Value::Array(parent)) => match token.parse::<usize>() {
+            Ok(index) if index < parent.len() => Ok(parent.remove(index)),
+            _ => Err(PathNotFound(path.to_owned())),
+        },
+        _ => Err(PathNotFound(path.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use patch::*;
+
+    #[test]
+    fn test_merge_patch() {
+        // Example from RFC 7396, section 3.
+        let document = json!({
+            "title": "Goodbye!",
+            "author": { "givenName": "John", "familyName": "Doe" },
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged"
+        });
+        let patch = Patch::MergePatch(json!({
+            "title": "Hello!",
+            "phoneNumber": "+01-123-456-7890",
+            "author": { "familyName": null },
+            "tags": ["example"]
+        }));
+
+        assert_eq!(
+            patch.apply(&document).unwrap(),
+            json!({
+                "title": "Hello!",
+                "author": { "givenName": "John" },
+                "tags": ["example"],
+                "content": "This will be unchanged",
+                "phoneNumber": "+01-123-456-7890"
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let document = json!({
+            "name": "John",
+            "tags": ["a", "c"],
+            "address": { "city": "Berlin" },
+            "a/b": 1
+        });
+        let operations: Vec<PatchOperation> = serde_json::from_value(json!([
+            { "op": "test", "path": "/name", "value": "John" },
+            { "op": "add", "path": "/tags/1", "value": "b" },
+            { "op": "add", "path": "/tags/-", "value": "d" },
+            { "op": "replace", "path": "/name", "value": "Jane" },
+            { "op": "copy", "from": "/address/city", "path": "/city" },
+            { "op": "move", "from": "/a~1b", "path": "/address/number" },
+            { "op": "remove", "path": "/tags/0" }
+        ])).unwrap();
+
+        assert_eq!(
+            Patch::JsonPatch(operations).apply(&document).unwrap(),
+            json!({
+                "name": "Jane",
+                "tags": ["b", "c", "d"],
+                "address": { "city": "Berlin", "number": 1 },
+                "city": "Berlin"
+            })
+        );
+    }
+
+    #[test]
+    fn test_increment() {
+        let document = json!({
+            "views": 1,
+            "name": "John",
+            "scores": [1.5],
+            "big": 9223372036854775807u64
+        });
+        let increment = |path: &str, delta: Value| match delta {
+            Value::Number(ref delta) => increment(&document, path, delta),
+            _ => panic!("delta isn't a number"),
+        };
+
+        assert_eq!(
+            increment("/views", json!(-3)).unwrap()["views"],
+            json!(-2)
+        );
+        assert_eq!(
+            increment("/scores/0", json!(1)).unwrap()["scores"],
+            json!([2.5])
+        );
+        assert_eq!(
+            increment("/big", json!(1)).unwrap()["big"],
+            json!(9223372036854775808u64)
+        );
+        assert_eq!(
+            increment("/stats/likes", json!(2)).unwrap()["stats"],
+            json!({ "likes": 2 })
+        );
+        assert_eq!(
+            increment("/name", json!(1)),
+            Err(NotANumber("/name".to_owned()))
+        );
+        assert_eq!(
+            increment("/name/first", json!(1)),
+            Err(PathNotFound("/name/first".to_owned()))
+        );
+        assert_eq!(
+            increment("/scores/1", json!(1)),
+            Err(PathNotFound("/scores/1".to_owned()))
+        );
+        assert_eq!(
+            increment("/big", json!(18446744073709551615u64)),
+            Err(Overflow("/big".to_owned()))
+        );
+        assert_eq!(
+            increment("views", json!(1)),
+            Err(InvalidPointer("views".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_json_patch_errors() {
+        let document = json!({ "name": "John", "tags": [] });
+        let apply = |operations: Value| {
+            let operations: Vec<PatchOperation> =
+                serde_json::from_value(operations).unwrap();
+            Patch::JsonPatch(operations).apply(&document)
+        };
+
+        assert_eq!(
+            apply(json!([
+                { "op": "replace", "path": "/name", "value": "Jane" },
+                { "op": "test", "path": "/name", "value": "John" }
+            ])),
+            Err(TestFailed("/name".to_owned()))
+        );
+        assert_eq!(
+            apply(json!([{ "op": "remove", "path": "/age" }])),
+            Err(PathNotFound("/age".to_owned()))
+        );
+        assert_eq!(
+            apply(json!([{ "op": "add", "path": "/tags/1", "value": 1 }])),
+            Err(PathNotFound("/tags/1".to_owned()))
+        );
+        assert_eq!(
+            apply(json!([{ "op": "add", "path": "name", "value": 1 }])),
+            Err(InvalidPointer("name".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use std::cmp::Ordering::{self, Equal, Greater, Less};
+use std::collections::BTreeSet;
+
+use serde_json::{Map, Value};
+
+use index::Index;
+use object::Object;
+use types::Bag;
+
+/// A condition on the values at JSON Pointers into object data. Objects
+/// without a value at a filter's path only match `not` filters.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Filter {
+    Eq {
+        path: String,
+        value: Value,
+    },
+    Range {
+        path: String,
+        gt: Option<Value>,
+        gte: Option<Value>,
+        lt: Option<Value>,
+        lte: Option<Value>,
+    },
+    In {
+        path: String,
+        values: Vec<Value>,
+    },
+    Exists {
+        path: String,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Sorts are ascending unless their order says otherwise.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Sort {
+    pub path: String,
+    pub order: Option<Order>,
+}
+
+/// Objects matching `filter`, or every object without one, sorted by `sort`
+/// and then by ID. `fields` projects objects down to the values at the given
+/// paths, next to their IDs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Query {
+    pub filter: Option<Filter>,
+    pub fields: Option<Vec<String>>,
+    pub sort: Vec<Sort>,
+    pub limit: Option<usize>,
+}
+
+use self::Filter::*;
+
+impl Filter {
+    pub fn matches(&self, object: &Object) -> bool {
+        match *self {
+            Eq {
+                ref path,
+                ref value,
+            } => object.pointer(path) == Some(value),
+            Range {
+                ref path,
+                ref gt,
+                ref gte,
+                ref lt,
+                ref lte,
+            } => match object.pointer(path) {
+                Some(value) => {
+                    within(value, gt, &[Greater])
+                        && within(value, gte, &[Greater, Equal])
+                        && within(value, lt, &[Less])
+                        && within(value, lte, &[Less, Equal])
+                }
+                None => false,
+            },
+            In {
+                ref path,
+                ref values,
+            } => match object.pointer(path) {
+                Some(value) => values.contains(value),
+                None => false,
+            },
+            Exists { ref path } => object.pointer(path).is_some(),
+            And(ref filters) => {
+                filters.iter().all(|filter| filter.matches(object))
+            }
+            Or(ref filters) => {
+                filters.iter().any(|filter| filter.matches(object))
+            }
+            Not(ref filter) => !filter.matches(object),
+        }
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        match *self {
+            Eq { ref path, .. }
+            | Range { ref path, .. }
+            | In { ref path, .. }
+            | Exists { ref path } => vec![path],
+            And(ref filters) | Or(ref filters) => {
+                filters.iter().flat_map(|filter| filter.paths()).collect()
+            }
+            Not(ref filter) => filter.paths(),
+        }
+    }
+
+    /// Picks an index on a path the filter requires to be one of a few
+    /// values, returning its name and the IDs of the objects it holds for
+    /// them in order. Only those objects can match the filter, so the rest
+    /// don't have to be scanned.
+    pub fn plan(&self, indexes: &Bag<Index>) -> Option<(String, Vec<String>)> {
+        let mut index_names: Vec<&String> = indexes.keys().collect();
+        index_names.sort();
+
+        for index_name in index_names {
+            let index = &indexes[index_name];
+
+            if let Some(values) = self.required_values(&index.path) {
+                let object_ids: BTreeSet<String> = values
+                    .iter()
+                    .flat_map(|value| index.lookup(&Index::key(value)))
+                    .collect();
+
+                return Some((
+                    index_name.to_owned(),
+                    object_ids.into_iter().collect(),
+                ));
+            }
+        }
+
+        None
+    }
+
+    // The values the filter requires the value at `path` to be one of, if it
+    // does.
+    fn required_values(&self, path: &str) -> Option<Vec<&Value>> {
+        match *self {
+            Eq {
+                path: ref filter_path,
+                ref value,
+            } if filter_path == path => Some(vec![value]),
+            In {
+                path: ref filter_path,
+                ref values,
+            } if filter_path == path => Some(values.iter().collect()),
+            And(ref filters) => filters
+                .iter()
+                .filter_map(|filter| filter.required_values(path))
+                .next(),
+            _ => None,
+        }
+    }
+}
+
+impl Query {
+    /// Checks that every path in the query is a JSON Pointer.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut paths: Vec<&str> = Vec::new();
+
+        if let Some(ref filter) = self.filter {
+            paths.extend(filter.paths());
+        }
+
+        if let Some(ref fields) = self.fields {
+            paths.extend(fields.iter().map(String::as_str));
+        }
+
+        paths.extend(self.sort.iter().map(|sort| sort.path.as_str()));
+
+        match paths.iter().find(|path| !path.starts_with('/')) {
+            Some(path) => Err(format!("{} isn't a JSON Pointer", path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sorts objects already in ID order, which is kept between objects
+    /// sorting the same.
+    pub fn sort(&self, objects: &mut Vec<&Object>) {
+        if self.sort.is_empty() {
+            return;
+        }
+
+        objects.sort_by(|a, b| {
+            self.sort
+                .iter()
+                .map(|sort| {
+                    let (a, b) = (a.pointer(&sort.path), b.pointer(&sort.path));
+
+                    match (&sort.order, a, b) {
+                        (Some(Order::Desc), Some(a), Some(b)) => {
+                            compare_sort_values(Some(a), Some(b)).reverse()
+                        }
+                        _ => compare_sort_values(a, b),
+                    }
+                })
+                .find(|ordering| *ordering != Equal)
+                .unwrap_or(Equal)
+        });
+    }
+
+    pub fn project(&self, object: &Object) -> Value {
+        let fields = match self.fields {
+            Some(ref fields) => fields,
+            None => return json!(object.data),
+        };
+        let mut projection = Map::new();
+
+        if let Some(object_id) = object.data.get("id") {
+            projection.insert("id".to_owned(), object_id.to_owned());
+        }
+
+        for field in fields {
+            if let Some(value) = object.pointer(field) {
+                let tokens: Vec<String> = field[1..]
+                    .split('/')
+                    .map(|token| token.replace("~1", "/").replace("~0", "~"))
+                    .collect();
+
+                insert_at(&mut projection, &tokens, value.to_owned());
+            }
+        }
+
+        Value::Object(projection)
+    }
+}
+
+// Values of different types aren't comparable, so they never satisfy a bound.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64())
+        {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn within(
+    value: &Value,
+    bound: &Option<Value>,
+    orderings: &[Ordering],
+) -> bool {
+    match *bound {
+        Some(ref bound) => match compare(value, bound) {
+            Some(ordering) => orderings.contains(&ordering),
+            None => false,
+        },
+        None => true,
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match *value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+/// A total order over values for sorting: values of different types are
+/// ordered by type, and missing values sort last.
+pub fn compare_sort_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => compare(a, b)
+            .unwrap_or_else(|| type_rank(a).cmp(&type_rank(b))),
+        (Some(_), None) => Less,
+        (None, Some(_)) => Greater,
+        (None, None) => Equal,
+    }
+}
+
+// Inserts `value` into `target` at the path made of `tokens`, creating the
+// objects along the way.
+fn insert_at(target: &mut Map<String, Value>, tokens: &[String], value: Value) {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if rest.is_empty() {
+        target.insert(token.to_owned(), value);
+        return;
+    }
+
+    let child = target
+        .entry(token.to_owned())
+        .or_insert_with(|| Value::Object(Map::new()));
+
+    if let Value::Object(ref mut child) = *child {
+        insert_at(child, rest, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use query::*;
+
+    fn make_objects() -> Vec<Object> {
+        vec![
+            json!({ "name": "Ann", "age": 42, "address": { "city": "Porto" } }),
+            json!({ "name": "Bob", "age": 17, "tags": ["admin"] }),
+            json!({ "name": "Cid", "age": "unknown" }),
+            json!({ "name": "Dee", "age": 30, "address": { "city": "Faro" } }),
+        ].into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let data = serde_json::from_value(data).unwrap();
+                Object::make(&(index + 1).to_string(), data)
+            })
+            .collect()
+    }
+
+    fn run(query: Value) -> Vec<Value> {
+        let query: Query = serde_json::from_value(query).unwrap();
+        let filter = query.filter.to_owned().unwrap_or(And(vec![]));
+        let objects = make_objects();
+        let mut objects: Vec<&Object> =
+            objects.iter().filter(|object| filter.matches(object)).collect();
+
+        query.sort(&mut objects);
+
+        objects.iter().map(|object| query.project(object)).collect()
+    }
+
+    fn ids(query: Value) -> Vec<Value> {
+        run(query).iter().map(|object| object["id"].to_owned()).collect()
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            ids(json!({
+                "filter": { "eq": { "path": "/name", "value": "Bob" } }
+            })),
+            vec![json!("2")]
+        );
+        assert_eq!(
+            ids(json!({
+                "filter": { "range": { "path": "/age", "gte": 18, "lt": 42 } }
+            })),
+            vec![json!("4")]
+        );
+        assert_eq!(
+            ids(json!({
+                "filter": {
+                    "in": {
+                        "path": "/address/city",
+                        "values": ["Faro", "Porto"]
+                    }
+                }
+            })),
+            vec![json!("1"), json!("4")]
+        );
+        assert_eq!(
+            ids(json!({
+                "filter": {
+                    "or": [
+                        { "exists": { "path": "/tags/0" } },
+                        {
+                            "and": [
+                                { "range": { "path": "/age", "gt": 18 } },
+                                {
+                                    "not": {
+                                        "eq": {
+                                            "path": "/address/city",
+                                            "value": "Porto"
+                                        }
+                                    }
+                                }
+                            ]
+                        }
+                    ]
+                }
+            })),
+            vec![json!("2"), json!("4")]
+        );
+    }
+
+    #[test]
+    fn test_sort_and_projection() {
+        assert_eq!(
+            ids(json!({ "sort": [{ "path": "/age", "order": "desc" }] })),
+            vec![json!("3"), json!("1"), json!("4"), json!("2")]
+        );
+        assert_eq!(
+            ids(json!({ "sort": [{ "path": "/address/city" }] })),
+            vec![json!("4"), json!("1"), json!("2"), json!("3")]
+        );
+        assert_eq!(
+            run(json!({
+                "filter": { "eq": { "path": "/name", "value": "Ann" } },
+                "fields": ["/name", "/address/city", "/missing"]
+            })),
+            vec![json!({
+                "id": "1",
+                "name": "Ann",
+                "address": { "city": "Porto" }
+            })]
+        );
+
+        let query: Query = serde_json::from_value(json!({
+            "filter": { "not": { "exists": { "path": "name" } } }
+        })).unwrap();
+
+        assert_eq!(
+            query.validate(),
+            Err("name isn't a JSON Pointer".to_owned())
+        );
+    }
+}
@@ -0,0 +1,83 @@
+/// Bounds for scanning keys in order. `start` is inclusive, `end` is exclusive
+/// and `prefix` restricts keys to the ones starting with it. All bounds are
+/// optional and combine with each other.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Range {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub prefix: Option<String>,
+    pub reverse: bool,
+}
+
+impl Range {
+    /// Whether `key` comes before every key in the range, in iteration order.
+    pub fn precedes(&self, key: &str) -> bool {
+        if self.reverse {
+            self.is_above_end(key) || self.is_above_prefix(key)
+        } else {
+            self.is_below_start(key) || self.is_below_prefix(key)
+        }
+    }
+
+    /// Whether `key` comes after every key in the range, in iteration order.
+    pub fn follows(&self, key: &str) -> bool {
+        if self.reverse {
+            self.is_below_start(key) || self.is_below_prefix(key)
+        } else {
+            self.is_above_end(key) || self.is_above_prefix(key)
+        }
+    }
+
+    /// The part of the range past `cursor`, in iteration order.
+    pub fn after(&self, cursor: &str) -> Self {
+        let mut range = self.clone();
+
+        if self.reverse {
+            if !self.is_above_end(cursor) {
+                range.end = Some(cursor.to_owned());
+            }
+        } else {
+            // The smallest key greater than `cursor` is `cursor` followed by
+            // the NUL character.
+            let start = format!("{}\u{0}", cursor);
+
+            if !self.is_below_start(&start) {
+                range.start = Some(start);
+            }
+        }
+
+        range
+    }
+
+    fn is_below_start(&self, key: &str) -> bool {
+        match self.start {
+            Some(ref start) => key < start.as_str(),
+            None => false,
+        }
+    }
+
+    fn is_above_end(&self, key: &str) -> bool {
+        match self.end {
+            Some(ref end) => key >= end.as_str(),
+            None => false,
+        }
+    }
+
+    fn is_below_prefix(&self, key: &str) -> bool {
+        match self.prefix {
+            Some(ref prefix) => key < prefix.as_str(),
+            None => false,
+        }
+    }
+
+    // Keys starting with a prefix are contiguous and sort right after the
+    // prefix itself.
+    fn is_above_prefix(&self, key: &str) -> bool {
+        match self.prefix {
+            Some(ref prefix) => {
+                key > prefix.as_str() && !key.starts_with(prefix.as_str())
+            }
+            None => false,
+        }
+    }
+}
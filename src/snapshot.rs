@@ -0,0 +1,183 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_json;
+
+use keyspace::Keyspace;
+use types::Bag;
+
+static SNAPSHOT_EXTENSION: &str = "snapshot";
+
+static TEMPORARY_EXTENSION: &str = "tmp";
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    CorruptedSnapshot(PathBuf),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::IoError(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::JsonError(error)
+    }
+}
+
+/// A point-in-time copy of every keyspace, covering all operations up to and
+/// including `sequence`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub sequence: u64,
+    pub keyspaces: Bag<Keyspace>,
+}
+
+impl Snapshot {
+    /// Atomically writes the snapshot into `directory` and removes the
+    /// snapshots it supersedes.
+    ///
+    /// Snapshot files are a CRC32 (u32 LE) followed by the JSON payload.
+    pub fn write(&self, directory: &Path) -> Result<PathBuf, Error> {
+        fs::create_dir_all(directory)?;
+
+        let payload = serde_json::to_vec(self)?;
+        let path = make_snapshot_path(directory, self.sequence);
+        let temporary_path = path.with_extension(TEMPORARY_EXTENSION);
+
+        {
+            let mut file = File::create(&temporary_path)?;
+            file.write_u32::<LittleEndian>(crc32::checksum_ieee(&payload))?;
+            file.write_all(&payload)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temporary_path, &path)?;
+        File::open(directory)?.sync_all()?;
+
+        for older_path in snapshot_paths(directory)? {
+            if older_path < path {
+                fs::remove_file(older_path)?;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Reads the most recent snapshot in `directory`, if there is one.
+    pub fn read_latest(directory: &Path) -> Result<Option<Self>, Error> {
+        if !directory.exists() {
+            return Ok(None);
+        }
+
+        match snapshot_paths(directory)?.last() {
+            Some(path) => Ok(Some(read_snapshot(path)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn make_snapshot_path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", sequence, SNAPSHOT_EXTENSION))
+}
+
+// Snapshot file names are zero-padded sequence numbers, so sorting them
+// lexicographically sorts them by sequence.
+fn snapshot_paths(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().and_then(|e| e.to_str())
+                == Some(SNAPSHOT_EXTENSION)
+        })
+        .collect();
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn read_snapshot(path: &Path) -> Result<Snapshot, Error> {
+    let mut file = File::open(path)?;
+    let checksum = match file.read_u32::<LittleEndian>() {
+        Ok(checksum) => checksum,
+        Err(_error) => return Err(Error::CorruptedSnapshot(path.to_owned())),
+    };
+    let mut payload = Vec::new();
+    file.read_to_end(&mut payload)?;
+
+    if crc32::checksum_ieee(&payload) != checksum {
+        return Err(Error::CorruptedSnapshot(path.to_owned()));
+    }
+
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use im::hashmap::HashMap;
+
+    use snapshot::*;
+    use utils::make_id_string;
+
+    fn make_snapshot(sequence: u64) -> Snapshot {
+        let mut keyspaces: Bag<Keyspace> = HashMap::new();
+        keyspaces.insert("people".to_owned(), Keyspace::make("people"));
+
+        Snapshot {
+            sequence,
+            keyspaces,
+        }
+    }
+
+    #[test]
+    fn test_write_and_read_latest() {
+        let directory = env::temp_dir()
+            .join(format!("noronha-snapshot-{}", make_id_string()));
+
+        assert_eq!(Snapshot::read_latest(&directory).unwrap(), None);
+
+        let older_snapshot = make_snapshot(3);
+        let newer_snapshot = make_snapshot(10);
+        older_snapshot.write(&directory).unwrap();
+        let newer_path = newer_snapshot.write(&directory).unwrap();
+
+        assert_eq!(
+            Snapshot::read_latest(&directory).unwrap(),
+            Some(newer_snapshot)
+        );
+        assert_eq!(snapshot_paths(&directory).unwrap(), vec![newer_path]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_snapshot() {
+        let directory = env::temp_dir()
+            .join(format!("noronha-snapshot-{}", make_id_string()));
+        let path = make_snapshot(1).write(&directory).unwrap();
+
+        let mut contents = fs::read(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        fs::write(&path, contents).unwrap();
+
+        match Snapshot::read_latest(&directory) {
+            Err(Error::CorruptedSnapshot(corrupted_path)) => {
+                assert_eq!(corrupted_path, path)
+            }
+            other => panic!("expected corrupted snapshot, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,10 +1,31 @@
+use std::cmp::Ordering::Equal;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use futures::sync::mpsc::{self, Receiver, Sender};
 use im::hashmap::Entry::{Occupied, Vacant};
+use im::ordset::OrdSet;
+use serde_json::{self, Number, Value};
 
-use keyspace::Keyspace;
-use object::Object;
-use types::Bag;
+use engine::compaction::Compaction;
+use engine::{self, Engine};
+use eviction::Accesses;
+use index::Index;
+use keyspace::{
+    AsOf, Keyspace, KeyspaceSettings, KeyspaceStats, Revision,
+};
+use object::{Object, ObjectData};
+use aggregation::{AggregationQuery, Group};
+use patch::{self, Patch};
+use query::{Filter, Query};
+use range::Range;
+use snapshot::{self, Snapshot};
+use types::{Bag, Metadata};
+use utils::now_millis;
+use wal::{self, Entry, FsyncPolicy, WriteAheadLog};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Operation {
     CreateOrUpdateKeyspace {
         keyspace: Keyspace,
@@ -12,329 +33,4695 @@ pub enum Operation {
     CreateOrUpdateKeyspaceObject {
         keyspace_name: String,
         object: Object,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
+    },
+    PatchKeyspaceObject {
+        keyspace_name: String,
+        object_id: String,
+        patch: Patch,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
+    },
+    IncrementKeyspaceObject {
+        keyspace_name: String,
+        object_id: String,
+        path: String,
+        delta: Number,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
+    },
+    UpdateKeyspaceSettings {
+        keyspace_name: String,
+        settings: KeyspaceSettings,
+    },
+    TruncateKeyspace {
+        keyspace_name: String,
+    },
+    CreateKeyspaceIndex {
+        keyspace_name: String,
+        index_name: String,
+        index: Index,
+    },
+    DeleteKeyspaceIndex {
+        keyspace_name: String,
+        index_name: String,
     },
-    ReadKeyspace {
+    DeleteKeyspace {
         keyspace_name: String,
     },
-    ReadKeyspaceObject {
+    DeleteKeyspaceObject {
         keyspace_name: String,
         object_id: String,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
     },
+    Transaction {
+        operations: Vec<TransactionOperation>,
+    },
+    Batch {
+        operations: Vec<TransactionOperation>,
+    },
+}
+
+/// A step of a transaction. Checks don't change anything but, like writes,
+/// abort the whole transaction if their preconditions don't hold.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TransactionOperation {
+    Check {
+        keyspace_name: String,
+        object_id: String,
+        preconditions: Vec<Precondition>,
+    },
+    CreateOrUpdate {
+        keyspace_name: String,
+        object: Object,
+        preconditions: Vec<Precondition>,
+    },
+    Delete {
+        keyspace_name: String,
+        object_id: String,
+        preconditions: Vec<Precondition>,
+    },
+}
+
+/// Conditions on the current state of an object that must hold for a write
+/// to it to be applied.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Precondition {
+    Exists,
+    DoesNotExist,
+    VersionMatches(Vec<u64>),
+    VersionDoesNotMatch(Vec<u64>),
 }
 
-#[derive(Clone, Debug, Default, PartialEq)]
+#[derive(Debug, Default)]
 pub struct Storage {
-    log: Vec<Operation>,
+    log: Vec<Entry>,
+    // The approximate size of the entries in `log`, in bytes.
+    log_size: usize,
     keyspaces: Bag<Keyspace>,
+    sequence: u64,
+    timestamp: u64,
+    snapshot_sequence: u64,
+    wal: Option<WriteAheadLog>,
+    // The keyspace each subscriber is subscribed to, and where to send the
+    // entries that change it.
+    subscribers: Vec<(String, Sender<Entry>)>,
+    // The engine new keyspaces keep their objects with, memory if unset.
+    engine: Option<Engine>,
+    engine_directory: PathBuf,
+    memory_budget: usize,
+    memory_limit: usize,
+    accesses: Mutex<Accesses>,
+    is_compacting: bool,
+}
+
+/// The entries changing a keyspace still in the log after a sequence, and a
+/// receiver for the ones journaled from the moment of subscribing on. The
+/// receiver ends if entries aren't taken from it fast enough.
+#[derive(Debug)]
+pub struct Subscription {
+    pub entries: Vec<Entry>,
+    pub receiver: Receiver<Entry>,
 }
 
+// How many entries can be waiting to be received by a subscriber before
+// it's dropped, so that slow subscribers can't take up unbounded memory.
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Outcome {
     KeyspaceCreated(Keyspace),
     KeyspaceUpdated(Keyspace),
     KeyspaceFound(Keyspace),
     KeyspaceNotFound(String),
+    KeyspacesListed(Vec<(Metadata, KeyspaceStats)>),
+    KeyspaceTruncated(Keyspace),
+    KeyspaceDeleted(Keyspace),
+    KeyspaceIndexCreated(String, Index),
+    KeyspaceIndexUpdated(String, Index),
+    KeyspaceIndexDeleted(String, Index),
+    KeyspaceIndexNotFound(String),
+    KeyspaceIndexConflict(String),
     KeyspaceObjectCreated(Object),
     KeyspaceObjectUpdated(Object),
     KeyspaceObjectFound(Object),
     KeyspaceObjectNotFound(String),
+    KeyspaceObjectDeleted(Object),
+    KeyspaceObjectExpired(String),
+    KeyspaceObjectPreconditionFailed(String),
+    KeyspaceObjectPatchFailed(patch::Error),
+    KeyspaceObjectTooLarge(String),
+    KeyspaceObjectHistoryFound(Vec<Revision>),
+    KeyspaceFull(String),
+    MemoryLimitReached(String),
+    KeyspaceObjectsFound(Vec<Object>, Option<String>),
+    KeyspaceObjectsRead(Vec<Object>, Vec<String>),
+    KeyspaceObjectsQueried(Vec<Value>, Option<String>),
+    KeyspaceObjectsAggregated(Vec<Group>, Option<String>),
+    TransactionCommitted(Vec<Outcome>),
+    TransactionAborted(usize, Box<Outcome>),
+    BatchApplied(Vec<Outcome>),
 }
 
 #[derive(Debug)]
-pub struct Error;
+pub enum Error {
+    KeyspaceWithoutName,
+    ObjectWithoutId,
+    SequenceNotRetained(u64),
+    WalError(wal::Error),
+    SnapshotError(snapshot::Error),
+    EngineError(engine::Error),
+}
+
+impl From<wal::Error> for Error {
+    fn from(error: wal::Error) -> Error {
+        Error::WalError(error)
+    }
+}
+
+impl From<snapshot::Error> for Error {
+    fn from(error: snapshot::Error) -> Error {
+        Error::SnapshotError(error)
+    }
+}
+
+impl From<engine::Error> for Error {
+    fn from(error: engine::Error) -> Error {
+        Error::EngineError(error)
+    }
+}
+
+// Objects read from keyspaces' engines.
+type ObjectResult = Result<Object, engine::Error>;
+
+// A write to a keyspace kept in memory, as part of the transaction
+// operation at `index` if it's in a transaction.
+struct MemoryWrite {
+    index: usize,
+    keyspace_name: String,
+}
 
 use self::Operation::*;
 use self::Outcome::*;
+use self::Precondition::*;
 
-impl Keyspace {
-    pub fn create_or_update_object(
-        &mut self,
-        object: Object,
-    ) -> Result<Outcome, Error> {
-        let object = object.clone();
+impl Operation {
+    /// The part of the operation that changes the keyspace named
+    /// `keyspace_name` or any of its objects, if any: transactions and
+    /// batches are left with only their writes to it.
+    pub fn for_keyspace(&self, keyspace_name: &str) -> Option<Operation> {
+        let name = match *self {
+            CreateOrUpdateKeyspace { ref keyspace } => {
+                keyspace.metadata.get("name").map(String::as_str)
+            }
+            CreateOrUpdateKeyspaceObject {
+                keyspace_name: ref name,
+                ..
+            }
+            | PatchKeyspaceObject {
+                keyspace_name: ref name,
+                ..
+            }
+            | IncrementKeyspaceObject {
+                keyspace_name: ref name,
+                ..
+            }
+            | UpdateKeyspaceSettings {
+                keyspace_name: ref name,
+                ..
+            }
+            | TruncateKeyspace {
+                keyspace_name: ref name,
+            }
+            | CreateKeyspaceIndex {
+                keyspace_name: ref name,
+                ..
+            }
+            | DeleteKeyspaceIndex {
+                keyspace_name: ref name,
+                ..
+            }
+            | DeleteKeyspace {
+                keyspace_name: ref name,
+            }
+            | DeleteKeyspaceObject {
+                keyspace_name: ref name,
+                ..
+            } => Some(name.as_str()),
+            Transaction { ref operations } | Batch { ref operations } => {
+                let operations: Vec<TransactionOperation> = operations
+                    .iter()
+                    .filter(|operation| match **operation {
+                        TransactionOperation::Check { .. } => false,
+                        TransactionOperation::CreateOrUpdate {
+                            keyspace_name: ref name,
+                            ..
+                        }
+                        | TransactionOperation::Delete {
+                            keyspace_name: ref name,
+                            ..
+                        } => name == keyspace_name,
+                    })
+                    .cloned()
+                    .collect();
+
+                return match *self {
+                    _ if operations.is_empty() => None,
+                    Transaction { .. } => Some(Transaction { operations }),
+                    _ => Some(Batch { operations }),
+                };
+            }
+        };
+
+        if name == Some(keyspace_name) {
+            Some(self.to_owned())
+        } else {
+            None
+        }
+    }
+
+    // The IDs of the objects of the keyspace named `keyspace_name` the
+    // operation writes, deletes or checks.
+    fn object_ids(&self, keyspace_name: &str) -> Vec<String> {
+        let operations = match *self {
+            CreateOrUpdateKeyspaceObject {
+                keyspace_name: ref name,
+                ref object,
+                ..
+            } if name == keyspace_name => {
+                return object.metadata.get("id").cloned().into_iter().collect()
+            }
+            PatchKeyspaceObject {
+                keyspace_name: ref name,
+                ref object_id,
+                ..
+            }
+            | IncrementKeyspaceObject {
+                keyspace_name: ref name,
+                ref object_id,
+                ..
+            }
+            | DeleteKeyspaceObject {
+                keyspace_name: ref name,
+                ref object_id,
+                ..
+            } if name == keyspace_name => return vec![object_id.to_owned()],
+            Transaction { ref operations } | Batch { ref operations } => {
+                operations
+            }
+            _ => return Vec::new(),
+        };
 
-        match object.data.get("id") {
-            Some(object_id) => match self.objects.entry(object_id.to_owned()) {
-                Occupied(mut entry) => {
-                    entry.insert(object.to_owned());
-                    let object = object.clone();
-                    Ok(KeyspaceObjectUpdated(object))
+        operations
+            .iter()
+            .filter_map(|operation| match *operation {
+                TransactionOperation::Check {
+                    keyspace_name: ref name,
+                    ref object_id,
+                    ..
                 }
-                Vacant(entry) => {
-                    entry.insert(object.to_owned());
-                    let object = object.clone();
-                    Ok(KeyspaceObjectCreated(object))
+                | TransactionOperation::Delete {
+                    keyspace_name: ref name,
+                    ref object_id,
+                    ..
+                } if name == keyspace_name => Some(object_id.to_owned()),
+                TransactionOperation::CreateOrUpdate {
+                    keyspace_name: ref name,
+                    ref object,
+                    ..
+                } if name == keyspace_name => {
+                    object.metadata.get("id").cloned()
                 }
-            },
-            None => Err(Error),
-        }
+                _ => None,
+            })
+            .collect()
     }
 }
 
-impl Storage {
-    pub fn new() -> Self {
-        Default::default()
+impl Outcome {
+    /// Whether the write with this outcome changed anything. Writes that
+    /// didn't aren't journaled, kept in the log or published.
+    pub fn changes_state(&self) -> bool {
+        match *self {
+            KeyspaceCreated(_)
+            | KeyspaceUpdated(_)
+            | KeyspaceTruncated(_)
+            | KeyspaceDeleted(_)
+            | KeyspaceIndexCreated(_, _)
+            | KeyspaceIndexUpdated(_, _)
+            | KeyspaceIndexDeleted(_, _)
+            | KeyspaceObjectCreated(_)
+            | KeyspaceObjectUpdated(_)
+            | KeyspaceObjectDeleted(_)
+            | KeyspaceObjectExpired(_) => true,
+            TransactionCommitted(ref outcomes) | BatchApplied(ref outcomes) => {
+                outcomes.iter().any(Outcome::changes_state)
+            }
+            _ => false,
+        }
     }
+}
 
-    pub fn create_or_update_keyspace(
-        &mut self,
-        keyspace: Keyspace,
-    ) -> Result<Outcome, Error> {
-        self.apply(CreateOrUpdateKeyspace { keyspace })
+// The part of `entry` that changes the keyspace named `keyspace_name`, if
+// any, as its subscribers are sent it.
+fn keyspace_entry(entry: &Entry, keyspace_name: &str) -> Option<Entry> {
+    entry
+        .operation
+        .for_keyspace(keyspace_name)
+        .map(|operation| Entry {
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            operation,
+        })
+}
+
+impl Precondition {
+    pub fn holds(&self, object: Option<&Object>) -> bool {
+        match (self, object) {
+            (Exists, object) => object.is_some(),
+            (DoesNotExist, object) => object.is_none(),
+            (VersionMatches(versions), Some(object)) => {
+                versions.contains(&object.version())
+            }
+            (VersionMatches(_versions), None) => false,
+            (VersionDoesNotMatch(versions), Some(object)) => {
+                !versions.contains(&object.version())
+            }
+            (VersionDoesNotMatch(_versions), None) => true,
+        }
     }
+}
 
-    pub fn read_keyspace(
-        &mut self,
-        keyspace_name: String,
-    ) -> Result<Outcome, Error> {
-        self.apply(ReadKeyspace { keyspace_name })
+impl Keyspace {
+    // Objects expired at `now` are as missing to writes and preconditions as
+    // they are to reads, even before they're reaped.
+    fn live_object(
+        &self,
+        object_id: &str,
+        now: u64,
+    ) -> Result<Option<Object>, Error> {
+        Ok(self
+            .objects
+            .get(object_id)?
+            .filter(|object| !object.is_expired(now)))
     }
 
-    pub fn create_or_update_keyspace_object(
-        &mut self,
-        keyspace_name: String,
-        object: Object,
-    ) -> Result<Outcome, Error> {
-        self.apply(CreateOrUpdateKeyspaceObject {
-            keyspace_name,
-            object,
-        })
+    fn check_preconditions(
+        &self,
+        object_id: &str,
+        preconditions: &[Precondition],
+        now: u64,
+    ) -> Result<bool, Error> {
+        let object = self.live_object(object_id, now)?;
+
+        Ok(preconditions
+            .iter()
+            .all(|precondition| precondition.holds(object.as_ref())))
     }
 
-    pub fn read_keyspace_object(
-        &mut self,
-        keyspace_name: String,
+    pub fn check_object(
+        &self,
         object_id: String,
+        preconditions: &[Precondition],
+        now: u64,
     ) -> Result<Outcome, Error> {
-        self.apply(ReadKeyspaceObject {
-            keyspace_name,
-            object_id,
-        })
-    }
+        if !self.check_preconditions(&object_id, preconditions, now)? {
+            return Ok(KeyspaceObjectPreconditionFailed(object_id));
+        }
 
-    fn apply(&mut self, operation: Operation) -> Result<Outcome, Error> {
-        self.log.push(operation.clone());
+        match self.live_object(&object_id, now)? {
+            Some(object) => Ok(KeyspaceObjectFound(object)),
+            None => Ok(KeyspaceObjectNotFound(object_id)),
+        }
+    }
 
-        match operation {
-            CreateOrUpdateKeyspace { keyspace } => {
-                self._create_or_update_keyspace(keyspace)
-            }
-            CreateOrUpdateKeyspaceObject {
-                keyspace_name,
-                object,
-            } => {
-                self._create_or_update_keyspace_object(keyspace_name, object)
+    // Checks `object` against the keyspace's size and count limits.
+    fn check_limits(
+        &self,
+        object_id: &str,
+        object: &Object,
+        settings: &KeyspaceSettings,
+    ) -> Result<Option<Outcome>, Error> {
+        if let Some(max_object_size) = settings.max_object_size {
+            if object.size() > max_object_size {
+                return Ok(Some(KeyspaceObjectTooLarge(object_id.to_owned())));
             }
-            ReadKeyspace { keyspace_name } => {
-                self._read_keyspace(keyspace_name)
+        }
+
+        if let Some(max_object_count) = settings.max_object_count {
+            if self.objects.len() >= max_object_count
+                && self.objects.get(object_id)?.is_none()
+            {
+                return Ok(Some(KeyspaceFull(object_id.to_owned())));
             }
-            ReadKeyspaceObject {
-                keyspace_name,
-                object_id,
-            } => self._read_keyspace_object(keyspace_name, object_id),
         }
+
+        Ok(None)
     }
 
-    fn _create_or_update_keyspace(
+    /// Writes `object` with `version` if all `preconditions` hold for the
+    /// object currently stored with the same ID and it's within the
+    /// keyspace's limits. Objects written without an expiration at `now`
+    /// get the keyspace's default TTL, if any.
+    pub fn create_or_update_object(
         &mut self,
-        keyspace: Keyspace,
+        mut object: Object,
+        preconditions: &[Precondition],
+        version: u64,
+        now: u64,
     ) -> Result<Outcome, Error> {
-        let keyspace = keyspace.clone();
-        let keyspace_name = keyspace.metadata.get("name").unwrap().to_owned();
+        let object_id = match object.metadata.get("id") {
+            Some(object_id) => object_id.to_owned(),
+            None => return Err(Error::ObjectWithoutId),
+        };
+        let settings = self.settings();
 
-        match self.keyspaces.entry(keyspace_name) {
-            Occupied(mut entry) => {
-                entry.insert(keyspace.to_owned());
-                Ok(KeyspaceUpdated(keyspace))
-            }
-            Vacant(entry) => {
-                entry.insert(keyspace.clone());
-                Ok(KeyspaceCreated(keyspace))
-            }
+        if !self.check_preconditions(&object_id, preconditions, now)? {
+            return Ok(KeyspaceObjectPreconditionFailed(object_id));
         }
-    }
 
-    fn _create_or_update_keyspace_object(
-        &mut self,
-        keyspace_name: String,
-        object: Object,
-    ) -> Result<Outcome, Error> {
-        match self.keyspaces.get_mut(&keyspace_name) {
-            Some(keyspace) => keyspace.create_or_update_object(object),
-            None => Ok(KeyspaceNotFound(keyspace_name)),
+        if let Some(outcome) =
+            self.check_limits(&object_id, &object, &settings)?
+        {
+            return Ok(outcome);
+        }
+
+        if let Some(index_name) =
+            self.index_conflict(&object_id, &object, now)?
+        {
+            return Ok(KeyspaceIndexConflict(index_name));
+        }
+
+        object.set_version(version);
+
+        if let (None, Some(default_ttl)) =
+            (object.expires_at(), settings.default_ttl)
+        {
+            object.set_expires_at(
+                now.saturating_add(default_ttl.saturating_mul(1000)),
+            );
+        }
+
+        let previous = self.objects.insert(&object_id, object.clone())?;
+        self.reindex_object(&object_id, previous.as_ref(), Some(&object));
+        self.record_revision(&object_id, Some(&object), version, now);
+        self.set_updated_at(now);
+
+        match previous.filter(|previous| !previous.is_expired(now)) {
+            Some(_previous) => Ok(KeyspaceObjectUpdated(object)),
+            None => Ok(KeyspaceObjectCreated(object)),
         }
     }
 
-    fn _read_keyspace(
+    /// Applies `patch` to the data of the object with ID `object_id` and
+    /// writes the result with `version` if all `preconditions` hold. The
+    /// object is left untouched if any part of the patch fails.
+    pub fn patch_object(
         &mut self,
-        keyspace_name: String,
+        object_id: String,
+        patch: &Patch,
+        preconditions: &[Precondition],
+        version: u64,
+        now: u64,
     ) -> Result<Outcome, Error> {
-        match self.keyspaces.get(&keyspace_name) {
-            Some(keyspace) => {
-                let keyspace = keyspace.to_owned();
-                Ok(KeyspaceFound(keyspace))
+        if !self.check_preconditions(&object_id, preconditions, now)? {
+            return Ok(KeyspaceObjectPreconditionFailed(object_id));
+        }
+
+        let object = match self.live_object(&object_id, now)? {
+            Some(object) => object,
+            None => return Ok(KeyspaceObjectNotFound(object_id)),
+        };
+
+        let expires_at = object.expires_at();
+        let data = match patch.apply(&json!(object.data)) {
+            Ok(data) => data,
+            Err(error) => return Ok(KeyspaceObjectPatchFailed(error)),
+        };
+
+        // Patches can't turn the object into something other than a JSON
+        // object, and the ID always wins over whatever the patch did to it.
+        let data: ObjectData = match serde_json::from_value(data) {
+            Ok(data) => data,
+            Err(_error) => {
+                return Ok(KeyspaceObjectPatchFailed(
+                    patch::Error::InvalidDocument,
+                ))
             }
-            None => Ok(KeyspaceNotFound(keyspace_name)),
+        };
+        let mut object = Object::make(&object_id, data);
+        object.set_version(version);
+
+        if let Some(expires_at) = expires_at {
+            object.set_expires_at(expires_at);
+        }
+
+        if let Some(outcome) =
+            self.check_limits(&object_id, &object, &self.settings())?
+        {
+            return Ok(outcome);
         }
+
+        if let Some(index_name) =
+            self.index_conflict(&object_id, &object, now)?
+        {
+            return Ok(KeyspaceIndexConflict(index_name));
+        }
+
+        let previous = self.objects.insert(&object_id, object.clone())?;
+        self.reindex_object(&object_id, previous.as_ref(), Some(&object));
+        self.record_revision(&object_id, Some(&object), version, now);
+        self.set_updated_at(now);
+
+        Ok(KeyspaceObjectUpdated(object))
     }
 
-    fn _read_keyspace_object(
+    /// Adds `delta` to the number at `path` in the data of the object with ID
+    /// `object_id` and writes the result with `version` if all
+    /// `preconditions` hold. Missing and expired objects are created, with
+    /// the default TTL like any other, and existing ones keep their
+    /// expiration.
+    pub fn increment_object(
         &mut self,
-        keyspace_name: String,
         object_id: String,
+        path: &str,
+        delta: &Number,
+        preconditions: &[Precondition],
+        version: u64,
+        now: u64,
     ) -> Result<Outcome, Error> {
-        match self.keyspaces.get(&keyspace_name) {
-            Some(keyspace) => match keyspace.objects.get(&object_id) {
-                Some(object) => {
-                    let object = object.to_owned();
-                    Ok(KeyspaceObjectFound(object))
-                }
-                None => Ok(KeyspaceObjectNotFound(object_id)),
-            },
-            None => Ok(KeyspaceNotFound(keyspace_name)),
+        if !self.check_preconditions(&object_id, preconditions, now)? {
+            return Ok(KeyspaceObjectPreconditionFailed(object_id));
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use im::hashmap::HashMap;
+        let previous = self.live_object(&object_id, now)?;
+        let data = match previous {
+            Some(ref previous) => json!(previous.data),
+            None => json!({}),
+        };
+        let data = match patch::increment(&data, path, delta) {
+            Ok(data) => data,
+            Err(error) => return Ok(KeyspaceObjectPatchFailed(error)),
+        };
+        let data: ObjectData = match serde_json::from_value(data) {
+            Ok(data) => data,
+            Err(_error) => {
+                return Ok(KeyspaceObjectPatchFailed(
+                    patch::Error::InvalidDocument,
+                ))
+            }
+        };
+        let mut object = Object::make(&object_id, data);
 
-    use storage::*;
+        if let Some(expires_at) =
+            previous.and_then(|previous| previous.expires_at())
+        {
+            object.set_expires_at(expires_at);
+        }
 
-    #[test]
-    fn test_new() {
-        let storage = Storage::new();
-        assert_eq!(storage.log, Vec::new());
-        assert_eq!(storage.keyspaces, HashMap::new());
+        self.create_or_update_object(object, &[], version, now)
     }
 
-    #[test]
-    fn test_operations() {
-        // Keyspace create:
-        // 1. has successful outcome
-        // 2. appends operation to log
-        // 3. inserts keyspace into keyspaces
-
-        let mut storage = Storage::new();
-        let keyspace_name = "people";
-        let keyspace = Keyspace::make(keyspace_name);
-        let create_or_update_keyspace = CreateOrUpdateKeyspace {
-            keyspace: keyspace.to_owned(),
+    /// Returns up to `limit` objects within `range` in ID order, starting
+    /// after the object with ID `after`, and the cursor for the next page if
+    /// there is one. Objects expired at `now` are skipped.
+    pub fn scan_objects(
+        &self,
+        range: &Range,
+        after: Option<String>,
+        limit: usize,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        let range = match after {
+            Some(ref after) => range.after(after),
+            None => range.to_owned(),
         };
+        let mut objects = Vec::new();
 
-        let expected_outcome = KeyspaceCreated(keyspace.clone());
-        let expected_log = vec![create_or_update_keyspace.clone()];
-        let mut expected_keyspaces: Bag<Keyspace> = HashMap::new();
-        expected_keyspaces
-            .insert(keyspace_name.to_owned(), keyspace.to_owned());
+        for entry in self.objects.scan(range) {
+            let (_object_id, object) = entry?;
 
-        let outcome =
-            storage.apply(create_or_update_keyspace.clone()).unwrap();
+            if !object.is_expired(now) {
+                objects.push(object);
+            }
 
-        assert_eq!(outcome, expected_outcome);
-        assert_eq!(storage.log, expected_log);
-        assert_eq!(storage.keyspaces, expected_keyspaces);
+            if objects.len() > limit {
+                break;
+            }
+        }
 
-        // Keyspace object create:
-        // 1. has successful outcome
-        // 2. appends operation to log
-        // 3. inserts object into keyspace
+        let next_cursor = if objects.len() > limit {
+            objects.truncate(limit);
+            objects
+                .last()
+                .and_then(|object| object.metadata.get("id"))
+                .map(|object_id| object_id.to_owned())
+        } else {
+            None
+        };
 
-        let mut keyspace = keyspace.clone();
-        let object_id = "1";
-        let object = Object::make(object_id, HashMap::new());
+        Ok(KeyspaceObjectsFound(objects, next_cursor))
+    }
 
-        keyspace
-            .objects
-            .insert(object_id.to_owned(), object.clone());
+    /// Runs `query` over the keyspace's objects, returning the matching ones
+    /// and the name of the index used to narrow them down, if any. Without
+    /// one every object is scanned. Objects expired at `now` are skipped.
+    pub fn query_objects(
+        &self,
+        query: &Query,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        let (objects, index_name) =
+            self.select_objects(query.filter.as_ref(), now);
+        let objects = objects.collect::<Result<Vec<Object>, engine::Error>>()?;
+        let mut objects: Vec<&Object> = objects.iter().collect();
 
-        let create_or_update_keyspace_object = CreateOrUpdateKeyspaceObject {
-            keyspace_name: keyspace_name.to_owned(),
-            object: object.clone(),
-        };
+        query.sort(&mut objects);
 
-        let expected_outcome = KeyspaceObjectCreated(object.clone());
-        let expected_log = vec![
-            create_or_update_keyspace.clone(),
-            create_or_update_keyspace_object.clone(),
-        ];
-        let mut expected_keyspaces: Bag<Keyspace> = HashMap::new();
-        expected_keyspaces
-            .insert(keyspace_name.to_owned(), keyspace.to_owned());
+        if let Some(limit) = query.limit {
+            objects.truncate(limit);
+        }
 
-        let outcome = storage
+        let objects = objects.iter().map(|object| query.project(object));
+
+        Ok(KeyspaceObjectsQueried(objects.collect(), index_name))
+    }
+
+    /// Folds the objects matching the aggregation's filter into its groups
+    /// as they're iterated over, returning them and the name of the index
+    /// used to narrow the objects down, if any.
+    pub fn aggregate_objects(
+        &self,
+        aggregation: &AggregationQuery,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        let (objects, index_name) =
+            self.select_objects(aggregation.filter.as_ref(), now);
+        let mut error = None;
+        let groups = aggregation.aggregate(objects.filter_map(|object| {
+            object.map_err(|object_error| error = Some(object_error)).ok()
+        }));
+
+        match error {
+            Some(error) => Err(Error::from(error)),
+            None => Ok(KeyspaceObjectsAggregated(groups, index_name)),
+        }
+    }
+
+    // Objects matching `filter` that aren't expired at `now`, in ID order,
+    // and the name of the index they were looked up in if it was possible to
+    // use one.
+    fn select_objects<'a>(
+        &'a self,
+        filter: Option<&'a Filter>,
+        now: u64,
+    ) -> (Box<Iterator<Item = ObjectResult> + 'a>, Option<String>) {
+        let plan = filter.and_then(|filter| filter.plan(&self.indexes));
+        let (objects, index_name): (Box<Iterator<Item = ObjectResult>>, _) =
+            match plan {
+                Some((index_name, object_ids)) => (
+                    Box::new(object_ids.into_iter().filter_map(
+                        move |object_id| match self.objects.get(&object_id) {
+                            Ok(Some(object)) => Some(Ok(object)),
+                            Ok(None) => None,
+                            Err(error) => Some(Err(error)),
+                        },
+                    )),
+                    Some(index_name),
+                ),
+                None => (
+                    Box::new(
+                        self.objects
+                            .scan(Range::default())
+                            .map(|entry| entry.map(|(_, object)| object)),
+                    ),
+                    None,
+                ),
+            };
+
+        let objects = objects.filter(move |object| match *object {
+            Ok(ref object) => {
+                !object.is_expired(now) && match filter {
+                    Some(filter) => filter.matches(object),
+                    None => true,
+                }
+            }
+            Err(_) => true,
+        });
+
+        (Box::new(objects), index_name)
+    }
+
+    /// Deletes the object with ID `object_id` if all `preconditions` hold.
+    /// Objects expired at `now` are deleted too, but aren't found: they're
+    /// reported as expired instead.
+    pub fn delete_object(
+        &mut self,
+        object_id: String,
+        preconditions: &[Precondition],
+        sequence: u64,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        if !self.check_preconditions(&object_id, preconditions, now)? {
+            return Ok(KeyspaceObjectPreconditionFailed(object_id));
+        }
+
+        match self.objects.remove(&object_id)? {
+            Some(object) => {
+                self.reindex_object(&object_id, Some(&object), None);
+                self.record_revision(&object_id, None, sequence, now);
+                self.set_updated_at(now);
+
+                if object.is_expired(now) {
+                    Ok(KeyspaceObjectExpired(object_id))
+                } else {
+                    Ok(KeyspaceObjectDeleted(object))
+                }
+            }
+            None => Ok(KeyspaceObjectNotFound(object_id)),
+        }
+    }
+
+    /// Builds `index` from the keyspace's objects and adds it as `index_name`,
+    /// replacing any index with the same name. Unique indexes aren't added if
+    /// two objects have the same value.
+    pub fn create_index(
+        &mut self,
+        index_name: String,
+        mut index: Index,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        index.clear();
+
+        // Objects expired at `now` are left out, so that they can't conflict
+        // with the ones still around.
+        for entry in self.objects.scan(Range::default()) {
+            let (object_id, object) = entry?;
+
+            if object.is_expired(now) {
+                continue;
+            }
+
+            if !index.conflicting_object_ids(&object_id, &object).is_empty() {
+                return Ok(KeyspaceIndexConflict(index_name));
+            }
+
+            index.insert(&object_id, &object);
+        }
+
+        self.set_updated_at(now);
+
+        match self.indexes.insert(index_name.clone(), index.clone()) {
+            Some(_previous) => Ok(KeyspaceIndexUpdated(index_name, index)),
+            None => Ok(KeyspaceIndexCreated(index_name, index)),
+        }
+    }
+
+    pub fn delete_index(
+        &mut self,
+        index_name: String,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        match self.indexes.remove(&index_name) {
+            Some(index) => {
+                self.set_updated_at(now);
+                Ok(KeyspaceIndexDeleted(index_name, index))
+            }
+            None => Ok(KeyspaceIndexNotFound(index_name)),
+        }
+    }
+
+    /// Returns the objects indexed under `value` by the index named
+    /// `index_name`, in ID order. Objects expired at `now` are skipped.
+    pub fn lookup_index(
+        &self,
+        index_name: String,
+        value: &Value,
+        now: u64,
+    ) -> Result<Outcome, Error> {
+        let index = match self.indexes.get(&index_name) {
+            Some(index) => index,
+            None => return Ok(KeyspaceIndexNotFound(index_name)),
+        };
+
+        let mut objects = Vec::new();
+
+        for object_id in index.lookup(&Index::key(value)) {
+            match self.objects.get(&object_id)? {
+                Some(ref object) if object.is_expired(now) => (),
+                Some(object) => objects.push(object),
+                None => (),
+            }
+        }
+
+        Ok(KeyspaceObjectsFound(objects, None))
+    }
+}
+
+impl Storage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Rebuilds keyspaces from the latest snapshot in `snapshot_directory`
+    /// and by replaying the operations journaled after it in the write-ahead
+    /// log in `wal_directory`. New keyspaces keep their objects with
+    /// `engine`, and on-disk engines keep their files in `engine_directory`
+    /// and flush their writes once they take more than `memory_budget` bytes
+    /// together, if it isn't 0. Writes to keyspaces kept in memory are held
+    /// to `memory_limit` bytes in total, along with the log and history, if
+    /// it isn't 0.
+    pub fn open(
+        wal_directory: &Path,
+        snapshot_directory: &Path,
+        engine_directory: &Path,
+        fsync_policy: FsyncPolicy,
+        engine: Engine,
+        memory_budget: usize,
+        memory_limit: usize,
+    ) -> Result<Self, Error> {
+        let mut storage = Self::new();
+        storage.engine = Some(engine);
+        storage.engine_directory = engine_directory.to_owned();
+        storage.memory_budget = memory_budget;
+        storage.memory_limit = memory_limit;
+
+        if let Some(snapshot) = Snapshot::read_latest(snapshot_directory)? {
+            storage.keyspaces = snapshot.keyspaces;
+            storage.sequence = snapshot.sequence;
+
+            for keyspace in storage.keyspaces.iter_mut() {
+                keyspace.objects.open(engine_directory)?;
+                keyspace.rebuild_indexes()?;
+                keyspace.rebuild_history_size();
+            }
+
+            storage.snapshot_sequence = snapshot.sequence;
+        }
+
+        engine::remove_unused_files(engine_directory, &storage.engine_files())?;
+
+        let (wal, entries) = WriteAheadLog::open(wal_directory, fsync_policy)?;
+        let snapshot_sequence = storage.snapshot_sequence;
+
+        for entry in entries
+            .into_iter()
+            .filter(|entry| entry.sequence > snapshot_sequence)
+        {
+            // Outcomes were already reported when the operations were first
+            // applied, and only operations that were applied are journaled,
+            // so failing to apply one again means storage can't be rebuilt.
+            let outcome = storage.commit(&entry)?;
+
+            // Logs journaled before writes that changed nothing stopped
+            // being can still have them, and their sequences stay taken.
+            storage.sequence = entry.sequence;
+            storage.timestamp = entry.timestamp;
+
+            if outcome.changes_state() {
+                storage.push_to_log(entry);
+            }
+
+            storage.flush_over_memory_budget()?;
+        }
+
+        let keyspace_names: Vec<String> =
+            storage.keyspaces.keys().cloned().collect();
+
+        for keyspace_name in keyspace_names {
+            storage.track_accesses(&keyspace_name)?;
+        }
+
+        storage.wal = Some(wal);
+
+        Ok(storage)
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Syncs the operations journaled since the write-ahead log was last
+    /// synced.
+    pub fn sync_wal(&mut self) -> Result<(), Error> {
+        if let Some(ref mut wal) = self.wal {
+            wal.sync_pending()?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a new write-ahead log segment and returns a snapshot covering
+    /// every operation journaled so far, or `None` if nothing was journaled
+    /// since the last snapshot.
+    ///
+    /// Keyspaces are persistent data structures so the snapshot is cheap to
+    /// take and can be written out after the storage lock is released.
+    /// Objects kept on disk are flushed first, so that the snapshot only
+    /// holds the names of the files they're in.
+    pub fn begin_snapshot(&mut self) -> Result<Option<Snapshot>, Error> {
+        if self.sequence == self.snapshot_sequence {
+            return Ok(None);
+        }
+
+        for keyspace in self.keyspaces.iter_mut() {
+            keyspace.objects.flush()?;
+        }
+
+        if let Some(ref mut wal) = self.wal {
+            wal.rotate(self.sequence + 1)?;
+        }
+
+        Ok(Some(Snapshot {
+            sequence: self.sequence,
+            keyspaces: self.keyspaces.clone(),
+        }))
+    }
+
+    /// Removes the write-ahead log segments and log entries covered by
+    /// `snapshot`, which must already have been written, and the engine files
+    /// neither it nor the keyspaces use anymore. Engine files are left alone
+    /// while a compaction is running, as it may still be writing or reading
+    /// them.
+    ///
+    /// The log only holds the entries a restart would replay, so that it
+    /// doesn't grow past what's journaled between snapshots.
+    pub fn finish_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+    ) -> Result<(), Error> {
+        if let Some(ref mut wal) = self.wal {
+            wal.truncate(snapshot.sequence)?;
+        }
+
+        self.snapshot_sequence = snapshot.sequence;
+        self.log.retain(|entry| entry.sequence > snapshot.sequence);
+        self.log_size = self.log.iter().map(Entry::size).sum();
+
+        if self.is_compacting {
+            return Ok(());
+        }
+
+        let mut files = self.engine_files();
+        files.extend(
+            snapshot
+                .keyspaces
+                .values()
+                .flat_map(|keyspace| keyspace.objects.files()),
+        );
+        engine::remove_unused_files(&self.engine_directory, &files)?;
+
+        Ok(())
+    }
+
+    fn engine_files(&self) -> HashSet<String> {
+        self.keyspaces
+            .values()
+            .flat_map(|keyspace| keyspace.objects.files())
+            .collect()
+    }
+
+    /// Returns the compaction of the keyspace whose files need it the most,
+    /// if any do, to be run without holding the storage lock. Only one
+    /// compaction runs at a time.
+    pub fn begin_compaction(&mut self) -> Option<(String, Compaction)> {
+        if self.is_compacting {
+            return None;
+        }
+
+        let compaction = self
+            .keyspaces
+            .iter()
+            .filter_map(|(keyspace_name, keyspace)| {
+                keyspace
+                    .objects
+                    .compaction()
+                    .map(|compaction| (keyspace_name.to_owned(), compaction))
+            })
+            .max_by(|(_, a), (_, b)| {
+                a.score().partial_cmp(&b.score()).unwrap_or(Equal)
+            });
+
+        self.is_compacting = compaction.is_some();
+
+        compaction
+    }
+
+    /// Installs `compaction` in the keyspace it was started for, returning
+    /// whether it could be. Compactions that failed or whose keyspace changed
+    /// in the meantime are discarded, and their files removed after the next
+    /// snapshot.
+    pub fn finish_compaction(
+        &mut self,
+        keyspace_name: &str,
+        compaction: &Compaction,
+    ) -> bool {
+        self.is_compacting = false;
+
+        match self.keyspaces.get_mut(keyspace_name) {
+            Some(keyspace) => keyspace.objects.compact(compaction),
+            None => false,
+        }
+    }
+
+    // Flushes the keyspaces with the most writes kept in memory until they're
+    // all back under the memory budget. Keyspaces with in-memory engines
+    // don't keep any.
+    fn flush_over_memory_budget(&mut self) -> Result<(), Error> {
+        if self.memory_budget == 0 {
+            return Ok(());
+        }
+
+        loop {
+            let buffered_size: usize = self
+                .keyspaces
+                .values()
+                .map(|keyspace| keyspace.objects.buffered_size())
+                .sum();
+
+            if buffered_size <= self.memory_budget {
+                return Ok(());
+            }
+
+            match self
+                .keyspaces
+                .iter_mut()
+                .max_by_key(|keyspace| keyspace.objects.buffered_size())
+            {
+                Some(keyspace) => keyspace.objects.flush()?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The approximate size of what storage keeps in memory, which is what
+    // the memory limit applies to: the objects of the keyspaces kept in
+    // memory, the history of every keyspace and the log. Keyspaces kept on
+    // disk only hold their writes in memory until they're flushed, and those
+    // are bounded by the memory budget.
+    fn memory_size(&self) -> usize {
+        let keyspaces_size: usize = self
+            .keyspaces
+            .values()
+            .map(|keyspace| {
+                let objects_size = if keyspace.objects.is_in_memory() {
+                    keyspace.objects.size()
+                } else {
+                    0
+                };
+
+                objects_size + keyspace.history_size()
+            })
+            .sum();
+
+        keyspaces_size + self.log_size
+    }
+
+    // Writes that would take storage over its memory limit first evict
+    // objects from the keyspaces they write to, if those evict them, and are
+    // rejected if that can't make enough room. Evictions are journaled as
+    // deletes, so replaying the write-ahead log gets to the same state
+    // without knowing how objects were used. Writes that don't add anything,
+    // including the ones whose preconditions or keyspace limits don't hold,
+    // are never rejected and never evict.
+    fn make_room(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<Option<Outcome>, Error> {
+        if self.memory_limit == 0 {
+            return Ok(None);
+        }
+
+        let writes = self.memory_writes(operation);
+
+        if writes.is_empty() {
+            return Ok(None);
+        }
+
+        let growth = self.memory_growth(operation)?;
+
+        if self.memory_size() + growth <= self.memory_limit {
+            return Ok(None);
+        }
+
+        // Objects the operation refers to are never evicted, so that it has
+        // the same outcome once room is made.
+        let mut evictions = Vec::new();
+
+        for write in &writes {
+            if evictions
+                .iter()
+                .all(|(keyspace_name, _)| *keyspace_name != write.keyspace_name)
+            {
+                let object_ids = operation.object_ids(&write.keyspace_name);
+                evictions.push((write.keyspace_name.to_owned(), object_ids));
+            }
+        }
+
+        let mut evictable_size = 0;
+
+        for (keyspace_name, object_ids) in &evictions {
+            evictable_size += self.evictable_size(keyspace_name, object_ids)?;
+        }
+
+        // Nothing is evicted for writes that wouldn't fit anyway.
+        if self.memory_size() + growth <= self.memory_limit + evictable_size {
+            for (keyspace_name, object_ids) in &evictions {
+                while self.memory_size() + growth > self.memory_limit {
+                    if !self.evict(keyspace_name, object_ids)? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.memory_size() + growth <= self.memory_limit {
+            return Ok(None);
+        }
+
+        let write = &writes[0];
+        let outcome = MemoryLimitReached(write.keyspace_name.to_owned());
+
+        // Batches that don't fit are rejected as a whole.
+        match *operation {
+            Transaction { .. } => {
+                Ok(Some(TransactionAborted(write.index, Box::new(outcome))))
+            }
+            _ => Ok(Some(outcome)),
+        }
+    }
+
+    // The writes of `operation` to keyspaces kept in memory.
+    fn memory_writes(&self, operation: &Operation) -> Vec<MemoryWrite> {
+        let writes = match *operation {
+            CreateOrUpdateKeyspaceObject {
+                ref keyspace_name,
+                ..
+            }
+            | PatchKeyspaceObject {
+                ref keyspace_name,
+                ..
+            }
+            | IncrementKeyspaceObject {
+                ref keyspace_name,
+                ..
+            } => vec![(0, keyspace_name)],
+            Transaction { ref operations } | Batch { ref operations } => {
+                operations
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, operation)| match *operation {
+                        TransactionOperation::CreateOrUpdate {
+                            ref keyspace_name,
+                            ..
+                        } => Some((index, keyspace_name)),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        writes
+            .into_iter()
+            .filter(|&(_index, keyspace_name)| self.is_in_memory(keyspace_name))
+            .map(|(index, keyspace_name)| MemoryWrite {
+                index,
+                keyspace_name: keyspace_name.to_owned(),
+            })
+            .collect()
+    }
+
+    // Roughly how many bytes applying `operation` would add to what storage
+    // keeps in memory, worked out from the operation rather than by applying
+    // it: its entry in the log and, for each object it writes, the revision
+    // it adds to the keyspace's history, if it keeps one, and how much
+    // larger the object gets, if the keyspace is kept in memory. Patches are
+    // taken to add at most their own size to objects, and increments
+    // nothing. Writes whose preconditions or keyspace limits don't hold
+    // aren't applied, so they add nothing.
+    fn memory_growth(&self, operation: &Operation) -> Result<usize, Error> {
+        let now = now_millis();
+        let growth = match *operation {
+            CreateOrUpdateKeyspaceObject {
+                ref keyspace_name,
+                ref object,
+                ref preconditions,
+            } => self.write_growth(
+                keyspace_name,
+                object.metadata.get("id"),
+                Some(object),
+                0,
+                preconditions,
+                now,
+            )?,
+            PatchKeyspaceObject {
+                ref keyspace_name,
+                ref object_id,
+                ref patch,
+                ref preconditions,
+            } => {
+                let patch_size = serde_json::to_vec(patch)
+                    .map(|patch| patch.len())
+                    .unwrap_or(0);
+
+                self.write_growth(
+                    keyspace_name,
+                    Some(object_id),
+                    None,
+                    patch_size,
+                    preconditions,
+                    now,
+                )?
+            }
+            IncrementKeyspaceObject {
+                ref keyspace_name,
+                ref object_id,
+                ref preconditions,
+                ..
+            } => self.write_growth(
+                keyspace_name,
+                Some(object_id),
+                None,
+                0,
+                preconditions,
+                now,
+            )?,
+            Transaction { ref operations } | Batch { ref operations } => {
+                let mut growth = Some(0);
+
+                for transaction_operation in operations {
+                    let operation_growth = match *transaction_operation {
+                        TransactionOperation::CreateOrUpdate {
+                            ref keyspace_name,
+                            ref object,
+                            ref preconditions,
+                        } => self.write_growth(
+                            keyspace_name,
+                            object.metadata.get("id"),
+                            Some(object),
+                            0,
+                            preconditions,
+                            now,
+                        )?,
+                        TransactionOperation::Check {
+                            ref keyspace_name,
+                            ref object_id,
+                            ref preconditions,
+                        }
+                        | TransactionOperation::Delete {
+                            ref keyspace_name,
+                            ref object_id,
+                            ref preconditions,
+                        } => self
+                            .write_growth(
+                                keyspace_name,
+                                Some(object_id),
+                                None,
+                                0,
+                                preconditions,
+                                now,
+                            )?
+                            .map(|_growth| 0),
+                    };
+
+                    // Any operation that fails aborts a transaction, while
+                    // batches go on without it.
+                    growth = match (operation_growth, operation) {
+                        (Some(operation_growth), _) => {
+                            growth.map(|growth| growth + operation_growth)
+                        }
+                        (None, &Transaction { .. }) => None,
+                        (None, _) => growth,
+                    };
+                }
+
+                growth
+            }
+            _ => None,
+        };
+        let entry_size = serde_json::to_vec(operation)
+            .map(|operation| operation.len())
+            .unwrap_or(0);
+
+        Ok(growth.map(|growth| growth + entry_size).unwrap_or(0))
+    }
+
+    // Roughly how many bytes writing to the object with ID `object_id` of the
+    // keyspace named `keyspace_name` adds to the objects and history kept in
+    // memory, given `object` if it's written whole, or else how much larger
+    // than the current object it gets. It's `None` for writes that aren't
+    // applied.
+    fn write_growth(
+        &self,
+        keyspace_name: &str,
+        object_id: Option<&String>,
+        object: Option<&Object>,
+        added_size: usize,
+        preconditions: &[Precondition],
+        now: u64,
+    ) -> Result<Option<usize>, Error> {
+        let (keyspace, object_id) =
+            match (self.keyspaces.get(keyspace_name), object_id) {
+                (Some(keyspace), Some(object_id)) => (keyspace, object_id),
+                _ => return Ok(None),
+            };
+        let settings = keyspace.settings();
+
+        if !keyspace.check_preconditions(object_id, preconditions, now)? {
+            return Ok(None);
+        }
+
+        let previous_size = match keyspace.objects.get(object_id)? {
+            Some(previous) => previous.size(),
+            None => 0,
+        };
+        let size = match object {
+            Some(object) => {
+                if keyspace
+                    .check_limits(object_id, object, &settings)?
+                    .is_some()
+                {
+                    return Ok(None);
+                }
+
+                object.size()
+            }
+            None => previous_size + added_size,
+        };
+        let mut growth = 0;
+
+        if keyspace.objects.is_in_memory() {
+            growth += size.saturating_sub(previous_size);
+        }
+
+        if settings.history_retention.is_some() {
+            growth += size;
+        }
+
+        Ok(Some(growth))
+    }
+
+    // How many bytes evicting every object of the keyspace but the ones in
+    // `excluded_object_ids` would free, if it evicts them.
+    fn evictable_size(
+        &self,
+        keyspace_name: &str,
+        excluded_object_ids: &[String],
+    ) -> Result<usize, Error> {
+        let keyspace = match self.keyspaces.get(keyspace_name) {
+            Some(keyspace)
+                if keyspace.objects.is_in_memory()
+                    && keyspace.settings().eviction_policy.is_some() =>
+            {
+                keyspace
+            }
+            _ => return Ok(0),
+        };
+        let mut size = keyspace.objects.size();
+
+        for object_id in excluded_object_ids {
+            if let Some(object) = keyspace.objects.get(object_id)? {
+                size = size.saturating_sub(object.size());
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn is_in_memory(&self, keyspace_name: &str) -> bool {
+        match self.keyspaces.get(keyspace_name) {
+            Some(keyspace) => keyspace.objects.is_in_memory(),
+            None => false,
+        }
+    }
+
+    // Evicts the object that the keyspace's eviction policy picks, leaving
+    // out the ones in `excluded_object_ids`, returning whether there was one.
+    fn evict(
+        &mut self,
+        keyspace_name: &str,
+        excluded_object_ids: &[String],
+    ) -> Result<bool, Error> {
+        let policy = match self
+            .keyspaces
+            .get(keyspace_name)
+            .and_then(|keyspace| keyspace.settings().eviction_policy)
+        {
+            Some(policy) => policy,
+            None => return Ok(false),
+        };
+        let object_id = match self.accesses.lock().unwrap().victim(
+            keyspace_name,
+            policy,
+            excluded_object_ids,
+        ) {
+            Some(object_id) => object_id,
+            None => return Ok(false),
+        };
+        let object = match self.keyspaces.get(keyspace_name) {
+            Some(keyspace) => keyspace.objects.get(&object_id)?,
+            None => None,
+        };
+        let outcome = match object {
+            Some(object) => self.delete_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+                vec![VersionMatches(vec![object.version()])],
+            )?,
+            None => KeyspaceObjectNotFound(object_id.to_owned()),
+        };
+        let mut accesses = self.accesses.lock().unwrap();
+
+        match outcome {
+            KeyspaceObjectDeleted(object) => {
+                debug!(
+                    "Evicted object {:?} from keyspace {:?}",
+                    object_id, keyspace_name
+                );
+                accesses.record_eviction(keyspace_name, object.size());
+            }
+            // Objects that weren't deleted stop being picked.
+            _ => accesses.forget(keyspace_name, &object_id),
+        }
+
+        Ok(true)
+    }
+
+    // Writes count as uses of the objects they write, and changes to
+    // keyspaces start or stop tracking the uses of their objects.
+    fn record_accesses(
+        &self,
+        operation: &Operation,
+        outcome: &Outcome,
+    ) -> Result<(), Error> {
+        match (operation, outcome) {
+            (CreateOrUpdateKeyspace { keyspace }, _) => {
+                match keyspace.metadata.get("name") {
+                    Some(keyspace_name) => self.track_accesses(keyspace_name),
+                    None => Ok(()),
+                }
+            }
+            (UpdateKeyspaceSettings { keyspace_name, .. }, _) => {
+                self.track_accesses(keyspace_name)
+            }
+            (TruncateKeyspace { keyspace_name }, _) => {
+                self.accesses.lock().unwrap().forget_keyspace(keyspace_name);
+                self.track_accesses(keyspace_name)
+            }
+            (DeleteKeyspace { keyspace_name }, _) => {
+                let mut accesses = self.accesses.lock().unwrap();
+                accesses.forget_keyspace(keyspace_name);
+                accesses.forget_evictions(keyspace_name);
+                Ok(())
+            }
+            (CreateOrUpdateKeyspaceObject { keyspace_name, .. }, outcome)
+            | (PatchKeyspaceObject { keyspace_name, .. }, outcome)
+            | (IncrementKeyspaceObject { keyspace_name, .. }, outcome)
+            | (DeleteKeyspaceObject { keyspace_name, .. }, outcome) => {
+                self.record_access(keyspace_name, outcome);
+                Ok(())
+            }
+            (Transaction { operations }, TransactionCommitted(outcomes))
+            | (Batch { operations }, BatchApplied(outcomes)) => {
+                for (operation, outcome) in operations.iter().zip(outcomes) {
+                    match *operation {
+                        TransactionOperation::Check {
+                            ref keyspace_name,
+                            ..
+                        }
+                        | TransactionOperation::CreateOrUpdate {
+                            ref keyspace_name,
+                            ..
+                        }
+                        | TransactionOperation::Delete {
+                            ref keyspace_name,
+                            ..
+                        } => self.record_access(keyspace_name, outcome),
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record_access(&self, keyspace_name: &str, outcome: &Outcome) {
+        let mut accesses = self.accesses.lock().unwrap();
+
+        match *outcome {
+            KeyspaceObjectCreated(ref object)
+            | KeyspaceObjectUpdated(ref object) => {
+                if let Some(object_id) = object.metadata.get("id") {
+                    accesses.touch(keyspace_name, object_id);
+                }
+            }
+            KeyspaceObjectDeleted(ref object) => {
+                if let Some(object_id) = object.metadata.get("id") {
+                    accesses.forget(keyspace_name, object_id);
+                }
+            }
+            KeyspaceObjectExpired(ref object_id) => {
+                accesses.forget(keyspace_name, object_id)
+            }
+            _ => (),
+        }
+    }
+
+    // Tracks the uses of the objects of the keyspace if it's kept in memory
+    // and evicts them, starting from its current objects in ID order, and
+    // stops tracking them otherwise.
+    fn track_accesses(&self, keyspace_name: &str) -> Result<(), Error> {
+        let mut accesses = self.accesses.lock().unwrap();
+        let keyspace = match self.keyspaces.get(keyspace_name) {
+            Some(keyspace)
+                if keyspace.objects.is_in_memory()
+                    && keyspace.settings().eviction_policy.is_some() =>
+            {
+                keyspace
+            }
+            _ => {
+                accesses.forget_keyspace(keyspace_name);
+                return Ok(());
+            }
+        };
+
+        if accesses.is_tracking(keyspace_name) {
+            return Ok(());
+        }
+
+        accesses.track(keyspace_name);
+
+        for entry in keyspace.objects.scan(Range::default()) {
+            accesses.touch(keyspace_name, &entry?.0);
+        }
+
+        Ok(())
+    }
+
+    pub fn create_or_update_keyspace(
+        &mut self,
+        keyspace: Keyspace,
+    ) -> Result<Outcome, Error> {
+        self.apply(CreateOrUpdateKeyspace { keyspace })
+    }
+
+    pub fn read_keyspace(
+        &self,
+        keyspace_name: String,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => {
+                let keyspace = keyspace.to_owned();
+                Ok(KeyspaceFound(keyspace))
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    /// Lists every keyspace, ordered by name, with its stats.
+    pub fn list_keyspaces(&self) -> Result<Outcome, Error> {
+        let accesses = self.accesses.lock().unwrap();
+        let mut keyspaces: Vec<(Metadata, KeyspaceStats)> = self
+            .keyspaces
+            .iter()
+            .map(|(keyspace_name, keyspace)| {
+                let mut stats = keyspace.stats();
+                stats.evictions = accesses.evictions(keyspace_name);
+                (keyspace.metadata.to_owned(), stats)
+            })
+            .collect();
+
+        keyspaces.sort_by(|(a, _), (b, _)| a.get("name").cmp(&b.get("name")));
+
+        Ok(KeyspacesListed(keyspaces))
+    }
+
+    pub fn create_or_update_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object: Object,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        self.apply(CreateOrUpdateKeyspaceObject {
+            keyspace_name,
+            object,
+            preconditions,
+        })
+    }
+
+    pub fn patch_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        patch: Patch,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        self.apply(PatchKeyspaceObject {
+            keyspace_name,
+            object_id,
+            patch,
+            preconditions,
+        })
+    }
+
+    /// Adds `delta` to the number at `path` in the object, creating the
+    /// object or the number if they're missing.
+    pub fn increment_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        path: String,
+        delta: Number,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        self.apply(IncrementKeyspaceObject {
+            keyspace_name,
+            object_id,
+            path,
+            delta,
+            preconditions,
+        })
+    }
+
+    pub fn update_keyspace_settings(
+        &mut self,
+        keyspace_name: String,
+        settings: KeyspaceSettings,
+    ) -> Result<Outcome, Error> {
+        self.apply(UpdateKeyspaceSettings {
+            keyspace_name,
+            settings,
+        })
+    }
+
+    /// Adds a secondary index on the objects of the keyspace, or replaces the
+    /// one with the same name.
+    pub fn create_keyspace_index(
+        &mut self,
+        keyspace_name: String,
+        index_name: String,
+        index: Index,
+    ) -> Result<Outcome, Error> {
+        self.apply(CreateKeyspaceIndex {
+            keyspace_name,
+            index_name,
+            index,
+        })
+    }
+
+    pub fn delete_keyspace_index(
+        &mut self,
+        keyspace_name: String,
+        index_name: String,
+    ) -> Result<Outcome, Error> {
+        self.apply(DeleteKeyspaceIndex {
+            keyspace_name,
+            index_name,
+        })
+    }
+
+    /// Deletes every object in the keyspace, keeping the keyspace itself.
+    pub fn truncate_keyspace(
+        &mut self,
+        keyspace_name: String,
+    ) -> Result<Outcome, Error> {
+        self.apply(TruncateKeyspace { keyspace_name })
+    }
+
+    pub fn delete_keyspace(
+        &mut self,
+        keyspace_name: String,
+    ) -> Result<Outcome, Error> {
+        self.apply(DeleteKeyspace { keyspace_name })
+    }
+
+    pub fn delete_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        self.apply(DeleteKeyspaceObject {
+            keyspace_name,
+            object_id,
+            preconditions,
+        })
+    }
+
+    /// Applies all `operations` as a single operation, or none of them if any
+    /// fails.
+    pub fn commit_transaction(
+        &mut self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<Outcome, Error> {
+        self.apply(Transaction { operations })
+    }
+
+    /// Applies each of `operations` on its own, whatever the outcomes of the
+    /// others, but journals them together as a single operation.
+    pub fn apply_batch(
+        &mut self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<Outcome, Error> {
+        self.apply(Batch { operations })
+    }
+
+    pub fn read_keyspace_object(
+        &self,
+        keyspace_name: String,
+        object_id: String,
+    ) -> Result<Outcome, Error> {
+        let now = now_millis();
+
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => match keyspace.objects.get(&object_id)? {
+                Some(object) if !object.is_expired(now) => {
+                    self.accesses
+                        .lock()
+                        .unwrap()
+                        .touch(&keyspace_name, &object_id);
+                    Ok(KeyspaceObjectFound(object))
+                }
+                _ => Ok(KeyspaceObjectNotFound(object_id)),
+            },
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    /// Reads the object with ID `object_id` as it was as of `as_of`, from the
+    /// keyspace's history. Objects that had expired by then aren't found.
+    pub fn read_keyspace_object_as_of(
+        &self,
+        keyspace_name: String,
+        object_id: String,
+        as_of: AsOf,
+    ) -> Result<Outcome, Error> {
+        let keyspace = match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => keyspace,
+            None => return Ok(KeyspaceNotFound(keyspace_name)),
+        };
+
+        let revision = match keyspace.revision_as_of(&object_id, as_of) {
+            Some(revision) => revision,
+            None => return Ok(KeyspaceObjectNotFound(object_id)),
+        };
+        let at = match as_of {
+            AsOf::Sequence(_sequence) => revision.timestamp,
+            AsOf::Timestamp(timestamp) => timestamp,
+        };
+
+        match revision.object {
+            Some(ref object) if !object.is_expired(at) => {
+                Ok(KeyspaceObjectFound(object.to_owned()))
+            }
+            _ => Ok(KeyspaceObjectNotFound(object_id)),
+        }
+    }
+
+    /// Reads the retained revisions of the object with ID `object_id`,
+    /// oldest first.
+    pub fn read_keyspace_object_history(
+        &self,
+        keyspace_name: String,
+        object_id: String,
+    ) -> Result<Outcome, Error> {
+        let keyspace = match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => keyspace,
+            None => return Ok(KeyspaceNotFound(keyspace_name)),
+        };
+
+        let revisions = keyspace.revisions(&object_id);
+
+        if revisions.is_empty() {
+            Ok(KeyspaceObjectNotFound(object_id))
+        } else {
+            Ok(KeyspaceObjectHistoryFound(revisions.to_vec()))
+        }
+    }
+
+    /// Reads the objects with the given IDs, returning the ones found in the
+    /// order they were asked for and the IDs of the missing ones.
+    pub fn read_keyspace_objects(
+        &self,
+        keyspace_name: String,
+        object_ids: Vec<String>,
+    ) -> Result<Outcome, Error> {
+        let keyspace = match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => keyspace,
+            None => return Ok(KeyspaceNotFound(keyspace_name)),
+        };
+        let now = now_millis();
+        let mut accesses = self.accesses.lock().unwrap();
+        let mut objects = Vec::new();
+        let mut missing_object_ids = Vec::new();
+
+        for object_id in object_ids {
+            match keyspace.objects.get(&object_id)? {
+                Some(object) if !object.is_expired(now) => {
+                    accesses.touch(&keyspace_name, &object_id);
+                    objects.push(object)
+                }
+                _ => missing_object_ids.push(object_id),
+            }
+        }
+
+        Ok(KeyspaceObjectsRead(objects, missing_object_ids))
+    }
+
+    pub fn scan_keyspace_objects(
+        &self,
+        keyspace_name: String,
+        range: &Range,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => {
+                keyspace.scan_objects(range, after, limit, now_millis())
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    pub fn read_keyspace_index(
+        &self,
+        keyspace_name: String,
+        index_name: String,
+        value: &Value,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => {
+                keyspace.lookup_index(index_name, value, now_millis())
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    pub fn query_keyspace_objects(
+        &self,
+        keyspace_name: String,
+        query: &Query,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => keyspace.query_objects(query, now_millis()),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    pub fn aggregate_keyspace_objects(
+        &self,
+        keyspace_name: String,
+        aggregation: &AggregationQuery,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.get(&keyspace_name) {
+            Some(keyspace) => {
+                keyspace.aggregate_objects(aggregation, now_millis())
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    /// Subscribes to the entries changing the keyspace named `keyspace_name`
+    /// journaled after `since`, which has to be still in the log, or to the
+    /// ones journaled from now on if it's `None`. Subscribers are only sent
+    /// the writes of transactions and batches to their keyspace.
+    pub fn subscribe(
+        &mut self,
+        keyspace_name: &str,
+        since: Option<u64>,
+    ) -> Result<Subscription, Error> {
+        let since = since.unwrap_or(self.sequence);
+        let first_sequence = self
+            .log
+            .first()
+            .map(|entry| entry.sequence)
+            .unwrap_or(self.sequence + 1);
+
+        if since.saturating_add(1) < first_sequence {
+            return Err(Error::SequenceNotRetained(first_sequence - 1));
+        }
+
+        let entries = self
+            .log
+            .iter()
+            .filter(|entry| entry.sequence > since)
+            .filter_map(|entry| keyspace_entry(entry, keyspace_name))
+            .collect();
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+
+        self.subscribers.push((keyspace_name.to_owned(), sender));
+
+        Ok(Subscription { entries, receiver })
+    }
+
+    /// Deletes every object expired at `now`, returning how many were.
+    ///
+    /// Expired objects are already missing to reads and writes, so this only
+    /// frees the space they take. They're deleted on the condition that they
+    /// don't exist, which only holds while they're still expired when the
+    /// delete is applied.
+    pub fn reap_expired_objects(&mut self, now: u64) -> Result<usize, Error> {
+        let mut expired_objects = Vec::new();
+
+        for (keyspace_name, keyspace) in self.keyspaces.iter() {
+            for object_id in keyspace.expired_object_ids(now) {
+                expired_objects.push((keyspace_name.to_owned(), object_id));
+            }
+        }
+
+        let mut reaped = 0;
+
+        for (keyspace_name, object_id) in expired_objects {
+            if let KeyspaceObjectExpired(_) = self.delete_keyspace_object(
+                keyspace_name,
+                object_id,
+                vec![DoesNotExist],
+            )? {
+                reaped += 1;
+            }
+        }
+
+        Ok(reaped)
+    }
+
+    /// Drops the revisions older than each keyspace's history retention at
+    /// `now`. Writes prune the history of the objects they touch, so this is
+    /// only needed for objects that aren't written to anymore.
+    ///
+    /// History is rebuilt when the write-ahead log is replayed, so pruning it
+    /// isn't journaled.
+    pub fn prune_history(&mut self, now: u64) {
+        for keyspace in self.keyspaces.iter_mut() {
+            keyspace.prune_history(now);
+        }
+    }
+
+    // Only operations that change keyspaces go through here: reads are served
+    // directly from `keyspaces` and never journaled.
+    fn apply(&mut self, operation: Operation) -> Result<Outcome, Error> {
+        if let Some(outcome) = self.make_room(&operation)? {
+            return Ok(outcome);
+        }
+
+        let entry = Entry {
+            sequence: self.sequence + 1,
+            timestamp: now_millis(),
+            operation,
+        };
+        let outcome = self.commit(&entry)?;
+
+        if !outcome.changes_state() {
+            return Ok(outcome);
+        }
+
+        self.push_to_log(entry.clone());
+
+        if let Err(error) = self.record_accesses(&entry.operation, &outcome) {
+            warn!("Failed to track uses of objects: {:?}", error);
+        }
+
+        // Writes are already journaled and applied, and stay in memory if
+        // they can't be flushed.
+        if let Err(error) = self.flush_over_memory_budget() {
+            warn!("Failed to flush objects to disk: {:?}", error);
+        }
+
+        self.publish(&entry);
+
+        Ok(outcome)
+    }
+
+    // Entries are applied all or nothing: keyspaces are persistent data
+    // structures, so holding on to them from before the entry to go back to
+    // when it fails to be applied or journaled is cheap. Entries that don't
+    // change anything aren't journaled, and don't take up a sequence.
+    fn commit(&mut self, entry: &Entry) -> Result<Outcome, Error> {
+        let keyspaces = self.keyspaces.clone();
+        let (sequence, timestamp) = (self.sequence, self.timestamp);
+
+        self.sequence = entry.sequence;
+        self.timestamp = entry.timestamp;
+
+        let outcome = self
+            .execute(entry.operation.to_owned())
+            .and_then(|outcome| {
+                if outcome.changes_state() {
+                    self.journal(entry)?;
+                }
+
+                Ok(outcome)
+            });
+        let changes_state = match outcome {
+            Ok(ref outcome) => outcome.changes_state(),
+            Err(_) => false,
+        };
+
+        if !changes_state {
+            self.keyspaces = keyspaces;
+            self.sequence = sequence;
+            self.timestamp = timestamp;
+        }
+
+        outcome
+    }
+
+    fn push_to_log(&mut self, entry: Entry) {
+        self.log_size += entry.size();
+        self.log.push(entry);
+    }
+
+    // Operations are written to the write-ahead log once they're applied,
+    // but before they're made visible, so an operation that fails to be
+    // journaled never is.
+    fn journal(&mut self, entry: &Entry) -> Result<(), Error> {
+        if let Some(ref mut wal) = self.wal {
+            wal.append(entry)?;
+        }
+
+        Ok(())
+    }
+
+    // Subscribers are only sent the part of entries that changes their
+    // keyspace, if any. Subscribers that went away, or that have too many
+    // entries waiting, are dropped the first time an entry can't be sent to
+    // them.
+    fn publish(&mut self, entry: &Entry) {
+        let subscribers = self
+            .subscribers
+            .drain(..)
+            .filter_map(|(keyspace_name, mut sender)| {
+                let is_sent = match keyspace_entry(entry, &keyspace_name) {
+                    Some(entry) => sender.try_send(entry).is_ok(),
+                    None => true,
+                };
+
+                if is_sent {
+                    Some((keyspace_name, sender))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        self.subscribers = subscribers;
+    }
+
+    fn execute(&mut self, operation: Operation) -> Result<Outcome, Error> {
+        match operation {
+            CreateOrUpdateKeyspace { keyspace } => {
+                self._create_or_update_keyspace(keyspace)
+            }
+            CreateOrUpdateKeyspaceObject {
+                keyspace_name,
+                object,
+                preconditions,
+            } => self._create_or_update_keyspace_object(
+                keyspace_name,
+                object,
+                preconditions,
+            ),
+            PatchKeyspaceObject {
+                keyspace_name,
+                object_id,
+                patch,
+                preconditions,
+            } => self._patch_keyspace_object(
+                keyspace_name,
+                object_id,
+                patch,
+                preconditions,
+            ),
+            IncrementKeyspaceObject {
+                keyspace_name,
+                object_id,
+                path,
+                delta,
+                preconditions,
+            } => self._increment_keyspace_object(
+                keyspace_name,
+                object_id,
+                path,
+                delta,
+                preconditions,
+            ),
+            UpdateKeyspaceSettings {
+                keyspace_name,
+                settings,
+            } => self._update_keyspace_settings(keyspace_name, settings),
+            TruncateKeyspace { keyspace_name } => {
+                self._truncate_keyspace(keyspace_name)
+            }
+            CreateKeyspaceIndex {
+                keyspace_name,
+                index_name,
+                index,
+            } => self._create_keyspace_index(keyspace_name, index_name, index),
+            DeleteKeyspaceIndex {
+                keyspace_name,
+                index_name,
+            } => self._delete_keyspace_index(keyspace_name, index_name),
+            DeleteKeyspace { keyspace_name } => {
+                self._delete_keyspace(keyspace_name)
+            }
+            DeleteKeyspaceObject {
+                keyspace_name,
+                object_id,
+                preconditions,
+            } => self._delete_keyspace_object(
+                keyspace_name,
+                object_id,
+                preconditions,
+            ),
+            Transaction { operations } => self._transaction(operations),
+            Batch { operations } => self._batch(operations),
+        }
+    }
+
+    // Updating a keyspace merges the new metadata into the existing one,
+    // keeping its ID and objects. Objects are only ever cleared explicitly,
+    // by truncating the keyspace.
+    fn _create_or_update_keyspace(
+        &mut self,
+        mut keyspace: Keyspace,
+    ) -> Result<Outcome, Error> {
+        let keyspace_name = match keyspace.metadata.get("name") {
+            Some(keyspace_name) => keyspace_name.to_owned(),
+            None => return Err(Error::KeyspaceWithoutName),
+        };
+        let now = self.timestamp;
+
+        match self.keyspaces.entry(keyspace_name) {
+            Occupied(mut entry) => {
+                let existing_keyspace = entry.get_mut();
+
+                for (key, value) in keyspace.metadata.iter() {
+                    if key != "id" && key != "created_at" {
+                        existing_keyspace
+                            .metadata
+                            .insert(key.to_owned(), value.to_owned());
+                    }
+                }
+
+                existing_keyspace.set_updated_at(now);
+
+                Ok(KeyspaceUpdated(existing_keyspace.to_owned()))
+            }
+            Vacant(entry) => {
+                keyspace.objects = self
+                    .engine
+                    .unwrap_or(Engine::Memory)
+                    .objects(&self.engine_directory);
+                keyspace.set_created_at(now);
+                keyspace.set_updated_at(now);
+                entry.insert(keyspace.clone());
+                Ok(KeyspaceCreated(keyspace))
+            }
+        }
+    }
+
+    // Objects are versioned with the sequence of the operation that last
+    // wrote them, so versions are never reused even across deletes.
+    fn _create_or_update_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object: Object,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        let version = self.sequence;
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.create_or_update_object(
+                object,
+                &preconditions,
+                version,
+                now,
+            ),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _patch_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        patch: Patch,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        let version = self.sequence;
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.patch_object(
+                object_id,
+                &patch,
+                &preconditions,
+                version,
+                now,
+            ),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _increment_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        path: String,
+        delta: Number,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        let version = self.sequence;
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.increment_object(
+                object_id,
+                &path,
+                &delta,
+                &preconditions,
+                version,
+                now,
+            ),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _update_keyspace_settings(
+        &mut self,
+        keyspace_name: String,
+        settings: KeyspaceSettings,
+    ) -> Result<Outcome, Error> {
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => {
+                keyspace.set_settings(&settings);
+                keyspace.prune_history(now);
+                keyspace.set_updated_at(now);
+                Ok(KeyspaceUpdated(keyspace.to_owned()))
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _truncate_keyspace(
+        &mut self,
+        keyspace_name: String,
+    ) -> Result<Outcome, Error> {
+        let (sequence, now) = (self.sequence, self.timestamp);
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => {
+                let mut object_ids = Vec::new();
+
+                if keyspace.settings().history_retention.is_some() {
+                    for entry in keyspace.objects.scan(Range::default()) {
+                        object_ids.push(entry?.0);
+                    }
+                }
+
+                for object_id in object_ids {
+                    keyspace.record_revision(&object_id, None, sequence, now);
+                }
+
+                keyspace.objects.clear();
+                keyspace.expirations = OrdSet::new();
+                keyspace.rebuild_indexes()?;
+                keyspace.set_updated_at(now);
+                Ok(KeyspaceTruncated(keyspace.to_owned()))
+            }
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _create_keyspace_index(
+        &mut self,
+        keyspace_name: String,
+        index_name: String,
+        index: Index,
+    ) -> Result<Outcome, Error> {
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.create_index(index_name, index, now),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _delete_keyspace_index(
+        &mut self,
+        keyspace_name: String,
+        index_name: String,
+    ) -> Result<Outcome, Error> {
+        let now = self.timestamp;
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.delete_index(index_name, now),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _delete_keyspace(
+        &mut self,
+        keyspace_name: String,
+    ) -> Result<Outcome, Error> {
+        match self.keyspaces.remove(&keyspace_name) {
+            Some(keyspace) => Ok(KeyspaceDeleted(keyspace)),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    fn _delete_keyspace_object(
+        &mut self,
+        keyspace_name: String,
+        object_id: String,
+        preconditions: Vec<Precondition>,
+    ) -> Result<Outcome, Error> {
+        let (sequence, now) = (self.sequence, self.timestamp);
+
+        match self.keyspaces.get_mut(&keyspace_name) {
+            Some(keyspace) => keyspace.delete_object(
+                object_id,
+                &preconditions,
+                sequence,
+                now,
+            ),
+            None => Ok(KeyspaceNotFound(keyspace_name)),
+        }
+    }
+
+    // Keyspaces are persistent data structures, so holding on to them from
+    // before the transaction to roll back to is cheap.
+    fn _transaction(
+        &mut self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<Outcome, Error> {
+        let keyspaces = self.keyspaces.clone();
+        let mut outcomes = Vec::new();
+
+        for (index, operation) in operations.into_iter().enumerate() {
+            let outcome = match self._transaction_operation(operation) {
+                Ok(outcome) => outcome,
+                Err(error) => {
+                    self.keyspaces = keyspaces;
+                    return Err(error);
+                }
+            };
+
+            match outcome {
+                KeyspaceNotFound(_)
+                | KeyspaceObjectPreconditionFailed(_)
+                | KeyspaceObjectPatchFailed(_)
+                | KeyspaceObjectTooLarge(_)
+                | KeyspaceFull(_)
+                | KeyspaceIndexConflict(_) => {
+                    self.keyspaces = keyspaces;
+                    return Ok(TransactionAborted(index, Box::new(outcome)));
+                }
+                outcome => outcomes.push(outcome),
+            }
+        }
+
+        Ok(TransactionCommitted(outcomes))
+    }
+
+    fn _batch(
+        &mut self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<Outcome, Error> {
+        let mut outcomes = Vec::new();
+
+        for operation in operations {
+            outcomes.push(self._transaction_operation(operation)?);
+        }
+
+        Ok(BatchApplied(outcomes))
+    }
+
+    fn _transaction_operation(
+        &mut self,
+        operation: TransactionOperation,
+    ) -> Result<Outcome, Error> {
+        match operation {
+            TransactionOperation::Check {
+                keyspace_name,
+                object_id,
+                preconditions,
+            } => match self.keyspaces.get(&keyspace_name) {
+                Some(keyspace) => keyspace.check_object(
+                    object_id,
+                    &preconditions,
+                    self.timestamp,
+                ),
+                None => Ok(KeyspaceNotFound(keyspace_name)),
+            },
+            TransactionOperation::CreateOrUpdate {
+                keyspace_name,
+                object,
+                preconditions,
+            } => self._create_or_update_keyspace_object(
+                keyspace_name,
+                object,
+                preconditions,
+            ),
+            TransactionOperation::Delete {
+                keyspace_name,
+                object_id,
+                preconditions,
+            } => self._delete_keyspace_object(
+                keyspace_name,
+                object_id,
+                preconditions,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use futures::Stream;
+    use im::hashmap::HashMap;
+    use serde_json::{self, Number, Value};
+
+    use aggregation::AggregationQuery;
+    use eviction::{EvictionPolicy, Evictions};
+    use index::Index;
+    use object::ObjectData;
+    use query::Query;
+    use storage::*;
+    use utils::make_id_string;
+
+    fn logged_operations(storage: &Storage) -> Vec<Operation> {
+        storage
+            .log
+            .iter()
+            .map(|entry| entry.operation.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_new() {
+        let storage = Storage::new();
+        assert_eq!(storage.log, Vec::new());
+        assert_eq!(storage.keyspaces, HashMap::new());
+    }
+
+    #[test]
+    fn test_operations() {
+        // Keyspace create:
+        // 1. has successful outcome
+        // 2. appends operation to log
+        // 3. inserts keyspace into keyspaces
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+        let mut keyspace = Keyspace::make(keyspace_name);
+        let create_or_update_keyspace = CreateOrUpdateKeyspace {
+            keyspace: keyspace.to_owned(),
+        };
+
+        let outcome =
+            storage.apply(create_or_update_keyspace.clone()).unwrap();
+
+        keyspace.set_created_at(storage.timestamp);
+        keyspace.set_updated_at(storage.timestamp);
+
+        let expected_outcome = KeyspaceCreated(keyspace.clone());
+        let expected_log = vec![create_or_update_keyspace.clone()];
+        let mut expected_keyspaces: Bag<Keyspace> = HashMap::new();
+        expected_keyspaces
+            .insert(keyspace_name.to_owned(), keyspace.to_owned());
+
+        assert_eq!(outcome, expected_outcome);
+        assert_eq!(logged_operations(&storage), expected_log);
+        assert_eq!(storage.keyspaces, expected_keyspaces);
+
+        // Keyspace object create:
+        // 1. has successful outcome
+        // 2. appends operation to log
+        // 3. inserts object into keyspace
+
+        let mut keyspace = keyspace.clone();
+        let object_id = "1";
+        let object = Object::make(object_id, HashMap::new());
+        let mut versioned_object = object.clone();
+        versioned_object.set_version(2);
+
+        keyspace
+            .objects
+            .insert(object_id, versioned_object.clone())
+            .unwrap();
+
+        let create_or_update_keyspace_object = CreateOrUpdateKeyspaceObject {
+            keyspace_name: keyspace_name.to_owned(),
+            object: object.clone(),
+            preconditions: vec![],
+        };
+
+        let outcome = storage
             .apply(create_or_update_keyspace_object.clone())
             .unwrap();
 
-        assert_eq!(outcome, expected_outcome);
-        assert_eq!(storage.log, expected_log);
-        assert_eq!(storage.keyspaces, expected_keyspaces);
+        keyspace.set_updated_at(storage.timestamp);
+
+        let expected_outcome = KeyspaceObjectCreated(versioned_object.clone());
+        let expected_log = vec![
+            create_or_update_keyspace.clone(),
+            create_or_update_keyspace_object.clone(),
+        ];
+        let mut expected_keyspaces: Bag<Keyspace> = HashMap::new();
+        expected_keyspaces
+            .insert(keyspace_name.to_owned(), keyspace.to_owned());
+
+        assert_eq!(outcome, expected_outcome);
+        assert_eq!(logged_operations(&storage), expected_log);
+        assert_eq!(storage.keyspaces, expected_keyspaces);
+
+        let expected_log = vec![
+            create_or_update_keyspace.clone(),
+            create_or_update_keyspace_object.clone(),
+        ];
+
+        // Keyspace read:
+        // 1. has successful outcome
+        // 2. doesn't append to log
+        // 3. doesn't change keyspaces
+
+        let expected_outcome = KeyspaceFound(keyspace.clone());
+
+        let outcome =
+            storage.read_keyspace(keyspace_name.to_owned()).unwrap();
+
+        assert_eq!(outcome, expected_outcome);
+        assert_eq!(logged_operations(&storage), expected_log);
+        assert_eq!(storage.keyspaces, expected_keyspaces);
+
+        // Keyspace object read:
+        // 1. has successful outcome
+        // 2. doesn't append to log
+        // 3. doesn't change keyspaces
+
+        let expected_outcome = KeyspaceObjectFound(versioned_object.clone());
+
+        let outcome = storage
+            .read_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+            )
+            .unwrap();
+
+        assert_eq!(outcome, expected_outcome);
+        assert_eq!(logged_operations(&storage), expected_log);
+        assert_eq!(storage.keyspaces, expected_keyspaces);
+
+        // Missing keyspace object read:
+        // 1. has not found outcome
+        // 2. doesn't append to log
+
+        let expected_outcome = KeyspaceObjectNotFound("2".to_owned());
+
+        let outcome = storage
+            .read_keyspace_object(keyspace_name.to_owned(), "2".to_owned())
+            .unwrap();
+
+        assert_eq!(outcome, expected_outcome);
+        assert_eq!(logged_operations(&storage), expected_log);
+        assert_eq!(storage.sequence(), 2);
+    }
+
+    #[test]
+    fn test_delete() {
+        // Keyspace object delete:
+        // 1. has deleted outcome, or not found if it doesn't exist
+        // 2. appends operation to log
+        // 3. removes object from keyspace
+        //
+        // Keyspace delete:
+        // 1. has deleted outcome, or not found if it doesn't exist
+        // 2. appends operation to log
+        // 3. removes keyspace
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+        let keyspace = Keyspace::make(keyspace_name);
+        let object_id = "1";
+        let object = Object::make(object_id, HashMap::new());
+
+        let mut versioned_object = object.clone();
+        versioned_object.set_version(2);
+
+        storage.create_or_update_keyspace(keyspace.clone()).unwrap();
+        storage
+            .create_or_update_keyspace_object(
+                keyspace_name.to_owned(),
+                object.clone(),
+                vec![],
+            )
+            .unwrap();
+
+        let outcome = storage
+            .delete_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+                vec![],
+            )
+            .unwrap();
+
+        assert_eq!(outcome, KeyspaceObjectDeleted(versioned_object));
+        assert_eq!(storage.log.len(), 3);
+        assert_eq!(
+            storage.read_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+            ).unwrap(),
+            KeyspaceObjectNotFound(object_id.to_owned())
+        );
+
+        let outcome = storage
+            .delete_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+                vec![],
+            )
+            .unwrap();
+
+        assert_eq!(outcome, KeyspaceObjectNotFound(object_id.to_owned()));
+
+        let outcome =
+            storage.delete_keyspace(keyspace_name.to_owned()).unwrap();
+
+        match outcome {
+            KeyspaceDeleted(deleted_keyspace) => assert_eq!(
+                deleted_keyspace.metadata["id"],
+                keyspace.metadata["id"]
+            ),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert_eq!(storage.log.len(), 4);
+        assert_eq!(storage.keyspaces, HashMap::new());
+
+        let outcome =
+            storage.delete_keyspace(keyspace_name.to_owned()).unwrap();
+
+        assert_eq!(outcome, KeyspaceNotFound(keyspace_name.to_owned()));
+    }
+
+    #[test]
+    fn test_update_and_truncate_keyspace() {
+        // Keyspace update:
+        // 1. merges metadata, keeping the keyspace's ID
+        // 2. keeps the keyspace's objects
+        //
+        // Keyspace truncate:
+        // 1. removes all objects, keeping the keyspace
+        // 2. appends operation to log
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+        let keyspace = Keyspace::make(keyspace_name);
+        let keyspace_id = keyspace.metadata["id"].to_owned();
+
+        storage.create_or_update_keyspace(keyspace).unwrap();
+        storage
+            .create_or_update_keyspace_object(
+                keyspace_name.to_owned(),
+                Object::make("1", HashMap::new()),
+                vec![],
+            )
+            .unwrap();
+
+        let mut keyspace = Keyspace::make(keyspace_name);
+        keyspace
+            .metadata
+            .insert("owner".to_owned(), "accounts".to_owned());
+
+        match storage.create_or_update_keyspace(keyspace).unwrap() {
+            KeyspaceUpdated(keyspace) => {
+                assert_eq!(keyspace.metadata["id"], keyspace_id);
+                assert_eq!(keyspace.metadata["owner"], "accounts");
+                assert_eq!(keyspace.objects.len(), 1);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        match storage.truncate_keyspace(keyspace_name.to_owned()).unwrap() {
+            KeyspaceTruncated(keyspace) => {
+                assert_eq!(keyspace.metadata["id"], keyspace_id);
+                assert_eq!(keyspace.objects.len(), 0);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(storage.log.len(), 4);
+        assert_eq!(
+            storage.truncate_keyspace("places".to_owned()).unwrap(),
+            KeyspaceNotFound("places".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_list_keyspaces() {
+        // Keyspace listing:
+        // 1. lists keyspaces by name
+        // 2. counts and sizes their objects
+        // 3. tracks when they were created and last changed
+
+        let mut storage = Storage::new();
+
+        storage
+            .create_or_update_keyspace(Keyspace::make("places"))
+            .unwrap();
+        storage
+            .create_or_update_keyspace(Keyspace::make("people"))
+            .unwrap();
+
+        let created_at = storage.timestamp;
+
+        storage
+            .create_or_update_keyspace_object(
+                "people".to_owned(),
+                Object::make("1", HashMap::new()),
+                vec![],
+            )
+            .unwrap();
+
+        let updated_at = storage.timestamp;
+
+        match storage.list_keyspaces().unwrap() {
+            KeyspacesListed(keyspaces) => {
+                let names: Vec<&str> = keyspaces
+                    .iter()
+                    .map(|(metadata, _)| metadata["name"].as_str())
+                    .collect();
+
+                assert_eq!(names, vec!["people", "places"]);
+                assert_eq!(
+                    keyspaces[0].1,
+                    KeyspaceStats {
+                        object_count: 1,
+                        size: r#"{"id":"1"}"#.len(),
+                        evictions: Evictions::default(),
+                        created_at,
+                        updated_at,
+                    }
+                );
+                assert_eq!(keyspaces[1].1.object_count, 0);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_preconditions() {
+        // Conditional keyspace object writes:
+        // 1. version objects with the sequence of their last write
+        // 2. are applied only when all preconditions hold
+        // 3. have precondition failed outcome otherwise
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+        let object_id = "1";
+        let object = Object::make(object_id, HashMap::new());
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+
+        let mut write = |preconditions| {
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object.clone(),
+                    preconditions,
+                )
+                .unwrap()
+        };
+        let failed = KeyspaceObjectPreconditionFailed(object_id.to_owned());
+
+        assert_eq!(write(vec![Exists]), failed);
+
+        match write(vec![DoesNotExist]) {
+            KeyspaceObjectCreated(object) => assert_eq!(object.version(), 2),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(write(vec![DoesNotExist]), failed);
+        assert_eq!(write(vec![VersionMatches(vec![1, 3])]), failed);
+        assert_eq!(write(vec![VersionDoesNotMatch(vec![2])]), failed);
+
+        match write(vec![Exists, VersionMatches(vec![2, 3])]) {
+            KeyspaceObjectUpdated(object) => assert_eq!(object.version(), 3),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        let outcome = storage
+            .delete_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+                vec![VersionMatches(vec![2])],
+            )
+            .unwrap();
+
+        assert_eq!(outcome, failed);
+
+        match storage
+            .delete_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+                vec![VersionMatches(vec![3])],
+            )
+            .unwrap()
+        {
+            KeyspaceObjectDeleted(object) => assert_eq!(object.version(), 3),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_patch() {
+        // Keyspace object patch:
+        // 1. applies merge patches and JSON patches to object data
+        // 2. keeps the object ID and bumps the version
+        // 3. leaves the object untouched if the patch fails
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+        let object_id = "1";
+        let object_data: ObjectData = serde_json::from_value(json!({
+            "name": "John Doe",
+            "tags": ["a"]
+        })).unwrap();
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+        storage
+            .create_or_update_keyspace_object(
+                keyspace_name.to_owned(),
+                Object::make(object_id, object_data),
+                vec![],
+            )
+            .unwrap();
+
+        let mut apply_patch = |patch| {
+            storage
+                .patch_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object_id.to_owned(),
+                    patch,
+                    vec![],
+                )
+                .unwrap()
+        };
+
+        match apply_patch(Patch::MergePatch(json!({
+            "name": null,
+            "id": "2"
+        }))) {
+            KeyspaceObjectUpdated(object) => {
+                assert_eq!(
+                    json!(object.data),
+                    json!({ "id": "1", "tags": ["a"] })
+                );
+                assert_eq!(object.version(), 3);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        let operations = serde_json::from_value(json!([
+            { "op": "add", "path": "/tags/-", "value": "b" },
+            { "op": "test", "path": "/tags/0", "value": "b" }
+        ])).unwrap();
+
+        assert_eq!(
+            apply_patch(Patch::JsonPatch(operations)),
+            KeyspaceObjectPatchFailed(patch::Error::TestFailed(
+                "/tags/0".to_owned()
+            ))
+        );
+
+        let operations = serde_json::from_value(json!([
+            { "op": "replace", "path": "", "value": [] }
+        ])).unwrap();
+
+        assert_eq!(
+            apply_patch(Patch::JsonPatch(operations)),
+            KeyspaceObjectPatchFailed(patch::Error::InvalidDocument)
+        );
+
+        match storage
+            .read_keyspace_object(
+                keyspace_name.to_owned(),
+                object_id.to_owned(),
+            )
+            .unwrap()
+        {
+            KeyspaceObjectFound(object) => {
+                assert_eq!(
+                    json!(object.data),
+                    json!({ "id": "1", "tags": ["a"] })
+                );
+                assert_eq!(object.version(), 3);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(
+            storage
+                .patch_keyspace_object(
+                    keyspace_name.to_owned(),
+                    "2".to_owned(),
+                    Patch::MergePatch(json!({})),
+                    vec![],
+                )
+                .unwrap(),
+            KeyspaceObjectNotFound("2".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_increment() {
+        // Keyspace object increment:
+        // 1. creates the object and the number when they're missing
+        // 2. adds to the number, bumping the version, and is journaled
+        // 3. fails on anything but numbers and leaves the object untouched
+        // 4. starts over from expired objects, giving them the default TTL
+
+        fn increment(
+            storage: &mut Storage,
+            keyspace_name: &str,
+            path: &str,
+            delta: Value,
+            preconditions: Vec<Precondition>,
+        ) -> Outcome {
+            let delta: Number = serde_json::from_value(delta).unwrap();
+
+            storage
+                .increment_keyspace_object(
+                    keyspace_name.to_owned(),
+                    "1".to_owned(),
+                    path.to_owned(),
+                    delta,
+                    preconditions,
+                )
+                .unwrap()
+        }
+
+        let mut storage = Storage::new();
+        storage
+            .create_or_update_keyspace(Keyspace::make("counters"))
+            .unwrap();
+
+        match increment(&mut storage, "counters", "/views", json!(1), vec![]) {
+            KeyspaceObjectCreated(object) => {
+                assert_eq!(
+                    json!(object.data),
+                    json!({ "id": "1", "views": 1 })
+                );
+                assert_eq!(object.version(), 2);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        match increment(&mut storage, "counters", "/views", json!(2), vec![]) {
+            KeyspaceObjectUpdated(object) => {
+                assert_eq!(
+                    json!(object.data),
+                    json!({ "id": "1", "views": 3 })
+                );
+                assert_eq!(object.version(), 3);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(
+            logged_operations(&storage)[2],
+            IncrementKeyspaceObject {
+                keyspace_name: "counters".to_owned(),
+                object_id: "1".to_owned(),
+                path: "/views".to_owned(),
+                delta: Number::from(2),
+                preconditions: vec![],
+            }
+        );
+        assert_eq!(
+            increment(&mut storage, "counters", "/id", json!(1), vec![]),
+            KeyspaceObjectPatchFailed(patch::Error::NotANumber(
+                "/id".to_owned()
+            ))
+        );
+        assert_eq!(
+            increment(
+                &mut storage,
+                "counters",
+                "/views",
+                json!(1),
+                vec![VersionMatches(vec![2])]
+            ),
+            KeyspaceObjectPreconditionFailed("1".to_owned())
+        );
+        assert_eq!(
+            increment(&mut storage, "places", "/views", json!(1), vec![]),
+            KeyspaceNotFound("places".to_owned())
+        );
+
+        match storage
+            .read_keyspace_object("counters".to_owned(), "1".to_owned())
+            .unwrap()
+        {
+            KeyspaceObjectFound(object) => {
+                assert_eq!(json!(object.data), json!({ "id": "1", "views": 3 }))
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        let data = json!({ "views": 10 });
+        let mut expired_object =
+            Object::make("1", serde_json::from_value(data).unwrap());
+        expired_object.set_expires_at(1);
+
+        storage
+            .create_or_update_keyspace_object(
+                "counters".to_owned(),
+                expired_object,
+                vec![],
+            )
+            .unwrap();
+        storage
+            .update_keyspace_settings(
+                "counters".to_owned(),
+                KeyspaceSettings {
+                    default_ttl: Some(60),
+                    ..KeyspaceSettings::default()
+                },
+            )
+            .unwrap();
+
+        match increment(&mut storage, "counters", "/views", json!(1), vec![]) {
+            KeyspaceObjectCreated(object) => {
+                assert_eq!(
+                    json!(object.data),
+                    json!({ "id": "1", "views": 1 })
+                );
+                assert!(object.expires_at().unwrap() > now_millis() + 50_000);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_transaction() {
+        // Transactions:
+        // 1. apply writes across keyspaces as a single logged operation
+        // 2. version every object they write with the same sequence
+        // 3. leave keyspaces untouched if any operation fails
+
+        let mut storage = Storage::new();
+        let person = Object::make("1", HashMap::new());
+        let place = Object::make("2", HashMap::new());
+
+        storage
+            .create_or_update_keyspace(Keyspace::make("people"))
+            .unwrap();
+        storage
+            .create_or_update_keyspace(Keyspace::make("places"))
+            .unwrap();
+        storage
+            .create_or_update_keyspace_object(
+                "places".to_owned(),
+                place.clone(),
+                vec![],
+            )
+            .unwrap();
+
+        let outcome = storage
+            .commit_transaction(vec![
+                TransactionOperation::Check {
+                    keyspace_name: "places".to_owned(),
+                    object_id: "2".to_owned(),
+                    preconditions: vec![VersionMatches(vec![3])],
+                },
+                TransactionOperation::CreateOrUpdate {
+                    keyspace_name: "people".to_owned(),
+                    object: person.clone(),
+                    preconditions: vec![DoesNotExist],
+                },
+                TransactionOperation::Delete {
+                    keyspace_name: "places".to_owned(),
+                    object_id: "2".to_owned(),
+                    preconditions: vec![],
+                },
+            ])
+            .unwrap();
+
+        let mut versioned_place = place.clone();
+        versioned_place.set_version(3);
+        let mut versioned_person = person.clone();
+        versioned_person.set_version(4);
+
+        assert_eq!(
+            outcome,
+            TransactionCommitted(vec![
+                KeyspaceObjectFound(versioned_place.clone()),
+                KeyspaceObjectCreated(versioned_person.clone()),
+                KeyspaceObjectDeleted(versioned_place.clone()),
+            ])
+        );
+        assert_eq!(storage.log.len(), 4);
+
+        let keyspaces = storage.keyspaces.clone();
+        let outcome = storage
+            .commit_transaction(vec![
+                TransactionOperation::CreateOrUpdate {
+                    keyspace_name: "places".to_owned(),
+                    object: place.clone(),
+                    preconditions: vec![],
+                },
+                TransactionOperation::Delete {
+                    keyspace_name: "people".to_owned(),
+                    object_id: "1".to_owned(),
+                    preconditions: vec![VersionMatches(vec![3])],
+                },
+            ])
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            TransactionAborted(
+                1,
+                Box::new(KeyspaceObjectPreconditionFailed("1".to_owned()))
+            )
+        );
+        assert_eq!(storage.keyspaces, keyspaces);
+        assert_eq!(storage.sequence(), 4);
+    }
+
+    #[test]
+    fn test_batch() {
+        // Batches:
+        // 1. apply each operation on its own, whatever the others' outcomes
+        // 2. are logged as a single operation
+
+        let mut storage = Storage::new();
+        let person = Object::make("1", HashMap::new());
+
+        storage
+            .create_or_update_keyspace(Keyspace::make("people"))
+            .unwrap();
+
+        let outcome = storage
+            .apply_batch(vec![
+                TransactionOperation::CreateOrUpdate {
+                    keyspace_name: "people".to_owned(),
+                    object: person.clone(),
+                    preconditions: vec![],
+                },
+                TransactionOperation::Delete {
+                    keyspace_name: "people".to_owned(),
+                    object_id: "2".to_owned(),
+                    preconditions: vec![],
+                },
+                TransactionOperation::CreateOrUpdate {
+                    keyspace_name: "places".to_owned(),
+                    object: person.clone(),
+                    preconditions: vec![],
+                },
+            ])
+            .unwrap();
+
+        let mut versioned_person = person.clone();
+        versioned_person.set_version(2);
+
+        assert_eq!(
+            outcome,
+            BatchApplied(vec![
+                KeyspaceObjectCreated(versioned_person.clone()),
+                KeyspaceObjectNotFound("2".to_owned()),
+                KeyspaceNotFound("places".to_owned()),
+            ])
+        );
+        assert_eq!(storage.log.len(), 2);
+        assert_eq!(
+            storage
+                .read_keyspace_object("people".to_owned(), "1".to_owned())
+                .unwrap(),
+            KeyspaceObjectFound(versioned_person)
+        );
+    }
+
+    #[test]
+    fn test_read_objects() {
+        // Keyspace objects read:
+        // 1. returns found objects in the order they were asked for
+        // 2. returns the IDs of missing objects
+        // 3. doesn't append to log
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+
+        for object_id in ["1", "2", "3"].iter() {
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    Object::make(object_id, HashMap::new()),
+                    vec![],
+                )
+                .unwrap();
+        }
+
+        let object_ids =
+            vec!["3".to_owned(), "4".to_owned(), "1".to_owned()];
+
+        match storage
+            .read_keyspace_objects(keyspace_name.to_owned(), object_ids)
+            .unwrap()
+        {
+            KeyspaceObjectsRead(objects, missing_object_ids) => {
+                let object_ids: Vec<&str> = objects
+                    .iter()
+                    .map(|object| object.metadata["id"].as_str())
+                    .collect();
+
+                assert_eq!(object_ids, vec!["3", "1"]);
+                assert_eq!(missing_object_ids, vec!["4".to_owned()]);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(storage.log.len(), 4);
+        assert_eq!(
+            storage
+                .read_keyspace_objects("places".to_owned(), vec![])
+                .unwrap(),
+            KeyspaceNotFound("places".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_expiration() {
+        // Expired keyspace objects:
+        // 1. aren't returned by reads
+        // 2. are missing to writes and preconditions
+        // 3. are deleted by the reaper, which journals the deletes
+        // 4. keep their expiration when patched
+
+        let mut storage = Storage::new();
+        let keyspace_name = "sessions";
+        let mut expired_object = Object::make("1", HashMap::new());
+        expired_object.set_expires_at(1);
+        let mut live_object = Object::make("2", HashMap::new());
+        live_object.set_expires_at(now_millis() + 60_000);
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+
+        for object in [expired_object, live_object.clone()].iter() {
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object.clone(),
+                    vec![],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            storage
+                .read_keyspace_object(keyspace_name.to_owned(), "1".to_owned())
+                .unwrap(),
+            KeyspaceObjectNotFound("1".to_owned())
+        );
+
+        match storage
+            .scan_keyspace_objects(
+                keyspace_name.to_owned(),
+                &Range::default(),
+                None,
+                10,
+            )
+            .unwrap()
+        {
+            KeyspaceObjectsFound(objects, None) => {
+                assert_eq!(objects.len(), 1);
+                assert_eq!(objects[0].metadata["id"], "2");
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(
+            storage
+                .patch_keyspace_object(
+                    keyspace_name.to_owned(),
+                    "1".to_owned(),
+                    Patch::MergePatch(json!({ "name": "session" })),
+                    vec![],
+                )
+                .unwrap(),
+            KeyspaceObjectNotFound("1".to_owned())
+        );
+        assert_eq!(
+            storage
+                .commit_transaction(vec![TransactionOperation::Check {
+                    keyspace_name: keyspace_name.to_owned(),
+                    object_id: "1".to_owned(),
+                    preconditions: vec![DoesNotExist],
+                }])
+                .unwrap(),
+            TransactionCommitted(vec![KeyspaceObjectNotFound("1".to_owned())])
+        );
+
+        assert_eq!(storage.reap_expired_objects(now_millis()).unwrap(), 1);
+        assert_eq!(storage.reap_expired_objects(now_millis()).unwrap(), 0);
+        assert_eq!(storage.log.len(), 4);
+        assert_eq!(storage.keyspaces[keyspace_name].objects.len(), 1);
+        assert_eq!(
+            storage.keyspaces[keyspace_name]
+                .expirations
+                .iter()
+                .map(|(_, object_id)| object_id.to_owned())
+                .collect::<Vec<String>>(),
+            vec!["2".to_owned()]
+        );
+
+        match storage
+            .patch_keyspace_object(
+                keyspace_name.to_owned(),
+                "2".to_owned(),
+                Patch::MergePatch(json!({ "name": "session" })),
+                vec![],
+            )
+            .unwrap()
+        {
+            KeyspaceObjectUpdated(object) => {
+                assert_eq!(object.expires_at(), live_object.expires_at())
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_keyspace_settings() {
+        // Keyspace settings:
+        // 1. give objects written without an expiration the default TTL
+        // 2. reject objects over the size limit and past the count limit
+        // 3. can be updated without touching the keyspace's objects
+
+        let mut storage = Storage::new();
+        let keyspace_name = "sessions";
+        let mut keyspace = Keyspace::make(keyspace_name);
+        keyspace.set_settings(&KeyspaceSettings {
+            default_ttl: Some(60),
+            max_object_size: Some(64),
+            max_object_count: Some(1),
+            require_client_ids: true,
+            history_retention: None,
+            eviction_policy: None,
+        });
+
+        storage.create_or_update_keyspace(keyspace).unwrap();
+
+        let mut write = |object_id: &str, data: Value| {
+            let data: ObjectData = serde_json::from_value(data).unwrap();
+
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    Object::make(object_id, data),
+                    vec![],
+                )
+                .unwrap()
+        };
+
+        match write("1", json!({ "user": "mary" })) {
+            KeyspaceObjectCreated(object) => {
+                let expires_at = object.expires_at().unwrap();
+                assert!(expires_at > now_millis() + 50_000);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(
+            write("1", json!({ "user": "x".repeat(64) })),
+            KeyspaceObjectTooLarge("1".to_owned())
+        );
+        assert_eq!(
+            write("2", json!({ "user": "john" })),
+            KeyspaceFull("2".to_owned())
+        );
+
+        let settings = KeyspaceSettings::default();
+
+        match storage
+            .update_keyspace_settings(keyspace_name.to_owned(), settings)
+            .unwrap()
+        {
+            KeyspaceUpdated(keyspace) => {
+                assert_eq!(keyspace.settings(), KeyspaceSettings::default());
+                assert_eq!(keyspace.objects.len(), 1);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        assert_eq!(
+            storage
+                .update_keyspace_settings(
+                    "places".to_owned(),
+                    KeyspaceSettings::default(),
+                )
+                .unwrap(),
+            KeyspaceNotFound("places".to_owned())
+        );
+
+        // TTLs that can't be turned into milliseconds are refused.
+        assert!(
+            serde_json::from_value::<KeyspaceSettings>(json!({
+                "default_ttl": 18446744073709551615u64
+            })).is_err()
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        // Writes past the memory limit:
+        // 1. are rejected without being journaled in keyspaces that don't
+        //    evict objects, until deletes or snapshots make room
+        // 2. evict the least recently or least frequently used objects of
+        //    keyspaces that do, counting them in the keyspace's stats
+        // 3. are rejected without evicting anything if they can't fit
+        // 4. don't evict anything if they fail anyway
+        // 5. count the log and the history of keyspaces towards the limit
+
+        fn object(object_id: &str, name: &str) -> Object {
+            let data = json!({ "name": name, "bio": "x".repeat(1000) });
+            Object::make(object_id, serde_json::from_value(data).unwrap())
+        }
+
+        fn write(storage: &mut Storage, keyspace_name: &str, object: Object) {
+            match storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object,
+                    vec![],
+                )
+                .unwrap()
+            {
+                KeyspaceObjectCreated(_) | KeyspaceObjectUpdated(_) => (),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+        }
+
+        fn make_storage(policy: Option<EvictionPolicy>) -> Storage {
+            let mut storage = Storage::new();
+            let mut keyspace = Keyspace::make("cache");
+            keyspace.set_settings(&KeyspaceSettings {
+                eviction_policy: policy,
+                ..KeyspaceSettings::default()
+            });
+            storage.create_or_update_keyspace(keyspace).unwrap();
+
+            for object_id in &["1", "2", "3"] {
+                write(&mut storage, "cache", object(object_id, "mary"));
+            }
+
+            // Room for more entries in the log, but not for another object
+            // along with its entry.
+            storage.memory_limit =
+                storage.memory_size() + 2 * object("4", "mary").size();
+            storage
+        }
+
+        fn object_ids(storage: &Storage) -> Vec<String> {
+            let keyspace = storage.keyspaces.get("cache").unwrap();
+            keyspace
+                .objects
+                .scan(Range::default())
+                .map(|entry| entry.unwrap().0)
+                .collect()
+        }
+
+        let mut storage = make_storage(None);
+        let log_length = storage.log.len();
+
+        assert_eq!(
+            storage
+                .create_or_update_keyspace_object(
+                    "cache".to_owned(),
+                    object("4", "mary"),
+                    vec![],
+                )
+                .unwrap(),
+            MemoryLimitReached("cache".to_owned())
+        );
+        assert_eq!(storage.log.len(), log_length);
+
+        storage
+            .delete_keyspace_object("cache".to_owned(), "3".to_owned(), vec![])
+            .unwrap();
+        write(&mut storage, "cache", object("4", "mary"));
+
+        assert_eq!(object_ids(&storage), vec!["1", "2", "4"]);
 
-        // First keyspace read (through Storage::apply):
-        // 1. has successful outcome
-        // 2. appends operation to log
-        // 3. doesn't change keyspaces
+        let mut storage = make_storage(None);
+        let snapshot = storage.begin_snapshot().unwrap().unwrap();
+        storage.finish_snapshot(&snapshot).unwrap();
+        write(&mut storage, "cache", object("4", "mary"));
 
-        let read_keyspace = ReadKeyspace {
-            keyspace_name: keyspace_name.to_owned(),
+        assert_eq!(object_ids(&storage), vec!["1", "2", "3", "4"]);
+
+        let mut storage = make_storage(Some(EvictionPolicy::Lru));
+        storage
+            .read_keyspace_object("cache".to_owned(), "1".to_owned())
+            .unwrap();
+        write(&mut storage, "cache", object("4", "mary"));
+
+        assert_eq!(object_ids(&storage), vec!["1", "3", "4"]);
+        assert_eq!(
+            logged_operations(&storage)[4],
+            DeleteKeyspaceObject {
+                keyspace_name: "cache".to_owned(),
+                object_id: "2".to_owned(),
+                preconditions: vec![VersionMatches(vec![3])],
+            }
+        );
+
+        match storage.list_keyspaces().unwrap() {
+            KeyspacesListed(keyspaces) => assert_eq!(
+                keyspaces[0].1.evictions,
+                Evictions {
+                    object_count: 1,
+                    size: object("2", "mary").size(),
+                }
+            ),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        let log_length = storage.log.len();
+
+        assert_eq!(
+            storage
+                .create_or_update_keyspace_object(
+                    "cache".to_owned(),
+                    object("5", "mary"),
+                    vec![Exists],
+                )
+                .unwrap(),
+            KeyspaceObjectPreconditionFailed("5".to_owned())
+        );
+        assert_eq!(object_ids(&storage), vec!["1", "3", "4"]);
+        assert_eq!(storage.log.len(), log_length);
+
+        let mut storage = make_storage(Some(EvictionPolicy::Lfu));
+        storage
+            .read_keyspace_objects(
+                "cache".to_owned(),
+                vec!["1", "1", "2", "3", "3"]
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+            )
+            .unwrap();
+        write(&mut storage, "cache", object("4", "mary"));
+
+        assert_eq!(object_ids(&storage), vec!["1", "3", "4"]);
+
+        assert_eq!(
+            storage
+                .commit_transaction(vec![
+                    TransactionOperation::Delete {
+                        keyspace_name: "cache".to_owned(),
+                        object_id: "1".to_owned(),
+                        preconditions: vec![],
+                    },
+                    TransactionOperation::CreateOrUpdate {
+                        keyspace_name: "cache".to_owned(),
+                        object: object("5", &"x".repeat(10_000)),
+                        preconditions: vec![],
+                    },
+                ])
+                .unwrap(),
+            TransactionAborted(
+                1,
+                Box::new(MemoryLimitReached("cache".to_owned()))
+            )
+        );
+        assert_eq!(object_ids(&storage), vec!["1", "3", "4"]);
+
+        let mut storage = Storage::new();
+        let mut keyspace = Keyspace::make("people");
+        keyspace.set_settings(&KeyspaceSettings {
+            history_retention: Some(60),
+            ..KeyspaceSettings::default()
+        });
+        storage.create_or_update_keyspace(keyspace).unwrap();
+        write(&mut storage, "people", object("1", "mary"));
+        write(&mut storage, "people", object("1", "john"));
+
+        assert_eq!(
+            storage.memory_size(),
+            object("1", "john").size()
+                + object("1", "mary").size()
+                + object("1", "john").size()
+                + storage.log.iter().map(Entry::size).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_indexes() {
+        // Keyspace indexes:
+        // 1. are built from existing objects when created
+        // 2. follow object writes, patches and deletes
+        // 3. reject writes and index creations violating uniqueness
+        // 4. are rebuilt when storage is reopened
+        // 5. don't count expired objects as conflicting, even before they're
+        //    reaped
+
+        fn write(
+            storage: &mut Storage,
+            object_id: &str,
+            data: Value,
+        ) -> Outcome {
+            let data: ObjectData = serde_json::from_value(data).unwrap();
+
+            storage
+                .create_or_update_keyspace_object(
+                    "people".to_owned(),
+                    Object::make(object_id, data),
+                    vec![],
+                )
+                .unwrap()
+        }
+
+        fn create_index(
+            storage: &mut Storage,
+            path: &str,
+            unique: bool,
+        ) -> Outcome {
+            storage
+                .create_keyspace_index(
+                    "people".to_owned(),
+                    path[1..].to_owned(),
+                    Index::make(path, unique),
+                )
+                .unwrap()
+        }
+
+        fn lookup(
+            storage: &Storage,
+            index_name: &str,
+            value: Value,
+        ) -> Vec<String> {
+            match storage
+                .read_keyspace_index(
+                    "people".to_owned(),
+                    index_name.to_owned(),
+                    &value,
+                )
+                .unwrap()
+            {
+                KeyspaceObjectsFound(objects, None) => objects
+                    .iter()
+                    .map(|object| object.metadata["id"].to_owned())
+                    .collect(),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+        }
+
+        let directory = env::temp_dir()
+            .join(format!("noronha-storage-{}", make_id_string()));
+        let wal_directory = directory.join("wal");
+        let snapshot_directory = directory.join("snapshots");
+        let engine_directory = directory.join("objects");
+        let open = || {
+            Storage::open(
+                &wal_directory,
+                &snapshot_directory,
+                &engine_directory,
+                FsyncPolicy::Always,
+                Engine::Memory,
+                0,
+                0,
+            ).unwrap()
         };
 
-        let expected_outcome = KeyspaceFound(keyspace.clone());
-        let expected_log = vec![
-            create_or_update_keyspace.clone(),
-            create_or_update_keyspace_object.clone(),
-            read_keyspace.clone(),
-        ];
+        {
+            let mut storage = open();
 
-        let outcome = storage.apply(read_keyspace.clone()).unwrap();
+            storage
+                .create_or_update_keyspace(Keyspace::make("people"))
+                .unwrap();
+            write(
+                &mut storage,
+                "1",
+                 json!({ "email": "a@x", "city": "Porto" }),
+            );
+            write(
+                &mut storage,
+                "2",
+                 json!({ "email": "b@x", "city": "Porto" }),
+            );
 
-        assert_eq!(outcome, expected_outcome);
-        assert_eq!(storage.log, expected_log);
-        assert_eq!(storage.keyspaces, expected_keyspaces);
+            assert_eq!(
+                create_index(&mut storage, "/city", true),
+                KeyspaceIndexConflict("city".to_owned())
+            );
+            match create_index(&mut storage, "/city", false) {
+                KeyspaceIndexCreated(_index_name, _index) => (),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+            match create_index(&mut storage, "/email", true) {
+                KeyspaceIndexCreated(_index_name, _index) => (),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
 
-        // Second keyspace read (through Storage::read_keyspace):
-        // 1. has successful outcome
-        // 2. appends operation to log
-        // 3. doesn't change keyspaces
+            assert_eq!(
+                lookup(&storage, "city", json!("Porto")),
+                vec!["1", "2"]
+            );
+            assert_eq!(lookup(&storage, "email", json!("b@x")), vec!["2"]);
 
-        let expected_log = vec![
-            create_or_update_keyspace.clone(),
-            create_or_update_keyspace_object.clone(),
-            read_keyspace.clone(),
-            read_keyspace.clone(),
-        ];
+            assert_eq!(
+                write(&mut storage, "3", json!({ "email": "a@x" })),
+                KeyspaceIndexConflict("email".to_owned())
+            );
 
-        let outcome =
+            write(
+                &mut storage,
+                "1",
+                 json!({ "email": "c@x", "city": "Faro" }),
+            );
+            storage
+                .patch_keyspace_object(
+                    "people".to_owned(),
+                    "2".to_owned(),
+                    Patch::MergePatch(json!({ "city": "Faro" })),
+                    vec![],
+                )
+                .unwrap();
+
+            assert!(lookup(&storage, "city", json!("Porto")).is_empty());
+            assert!(lookup(&storage, "email", json!("a@x")).is_empty());
+            assert_eq!(
+                lookup(&storage, "city", json!("Faro")),
+                vec!["1", "2"]
+            );
+
+            storage
+                .delete_keyspace_object(
+                    "people".to_owned(),
+                    "2".to_owned(),
+                    vec![],
+                )
+                .unwrap();
+
+            assert_eq!(lookup(&storage, "city", json!("Faro")), vec!["1"]);
+
+            let snapshot = storage.begin_snapshot().unwrap().unwrap();
+            snapshot.write(&snapshot_directory).unwrap();
+            storage.finish_snapshot(&snapshot).unwrap();
+
+            write(
+                &mut storage,
+                "4",
+                 json!({ "email": "d@x", "city": "Faro" }),
+            );
+        }
+
+        let mut storage = open();
+
+        assert_eq!(lookup(&storage, "city", json!("Faro")), vec!["1", "4"]);
+        assert_eq!(lookup(&storage, "email", json!("c@x")), vec!["1"]);
+
+        match storage
+            .delete_keyspace_index("people".to_owned(), "city".to_owned())
+            .unwrap()
+        {
+            KeyspaceIndexDeleted(_index_name, index) => {
+                assert_eq!(index.path, "/city")
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert_eq!(
+            storage
+                .read_keyspace_index(
+                    "people".to_owned(),
+                    "city".to_owned(),
+                    &json!("Faro"),
+                )
+                .unwrap(),
+            KeyspaceIndexNotFound("city".to_owned())
+        );
+
+        let data = json!({ "email": "e@x" });
+        let mut expired_object =
+            Object::make("5", serde_json::from_value(data).unwrap());
+        expired_object.set_expires_at(1);
+        storage
+            .create_or_update_keyspace_object(
+                "people".to_owned(),
+                expired_object,
+                vec![],
+            )
+            .unwrap();
+
+        match write(&mut storage, "6", json!({ "email": "e@x" })) {
+            KeyspaceObjectCreated(_object) => (),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        match create_index(&mut storage, "/email", true) {
+            KeyspaceIndexUpdated(_index_name, _index) => (),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_query() {
+        // Keyspace object query:
+        // 1. narrows objects down with an index when the filter allows it
+        // 2. scans every object otherwise
+        // 3. skips expired objects and applies the limit after sorting
+        //
+        // Keyspace object aggregation:
+        // 1. folds the objects a query with the same filter would return
+
+        let mut storage = Storage::new();
+        let keyspace_name = "people";
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+        storage
+            .create_keyspace_index(
+                keyspace_name.to_owned(),
+                "city".to_owned(),
+                Index::make("/city", false),
+            )
+            .unwrap();
+
+        for &(object_id, city, age) in
+            &[("1", "Porto", 42), ("2", "Faro", 17), ("3", "Porto", 30)]
+        {
+            let data: ObjectData =
+                serde_json::from_value(json!({ "city": city, "age": age }))
+                    .unwrap();
+            let mut object = Object::make(object_id, data);
+
+            if object_id == "3" {
+                object.set_expires_at(now_millis() - 1);
+            }
+
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object,
+                    vec![],
+                )
+                .unwrap();
+        }
+
+        let query = |query: Value| {
+            let query: Query = serde_json::from_value(query).unwrap();
+
+            match storage
+                .query_keyspace_objects(keyspace_name.to_owned(), &query)
+                .unwrap()
+            {
+                KeyspaceObjectsQueried(objects, index_name) => (
+                    objects
+                        .iter()
+                        .map(|object| object["id"].to_owned())
+                        .collect::<Vec<Value>>(),
+                    index_name,
+                ),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+        };
+
+        assert_eq!(
+            query(json!({
+                "filter": {
+                    "and": [
+                        { "range": { "path": "/age", "gt": 18 } },
+                        { "eq": { "path": "/city", "value": "Porto" } }
+                    ]
+                }
+            })),
+            (vec![json!("1")], Some("city".to_owned()))
+        );
+        assert_eq!(
+            query(json!({
+                "filter": { "range": { "path": "/age", "lt": 50 } },
+                "sort": [{ "path": "/age" }],
+                "limit": 1
+            })),
+            (vec![json!("2")], None)
+        );
+        assert_eq!(
+            storage
+                .query_keyspace_objects("places".to_owned(), &Query::default())
+                .unwrap(),
+            KeyspaceNotFound("places".to_owned())
+        );
+
+        let aggregation: AggregationQuery = serde_json::from_value(json!({
+            "filter": { "eq": { "path": "/city", "value": "Porto" } },
+            "aggregations": { "people": "count", "age": { "sum": "/age" } }
+        })).unwrap();
+
+        match storage
+            .aggregate_keyspace_objects(keyspace_name.to_owned(), &aggregation)
+            .unwrap()
+        {
+            KeyspaceObjectsAggregated(groups, index_name) => {
+                assert_eq!(
+                    json!(groups[0].aggregations),
+                    json!({ "people": 1, "age": 42 })
+                );
+                assert_eq!(index_name, Some("city".to_owned()));
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        // Keyspace object scan:
+        // 1. returns objects within range in ID order, or reverse ID order
+        // 2. returns a cursor while there are more objects
+        // 3. doesn't append to log
+
+        let mut storage = Storage::new();
+        let keyspace_name = "events";
+
+        storage
+            .create_or_update_keyspace(Keyspace::make(keyspace_name))
+            .unwrap();
+
+        let object_ids = ["b-2", "a-1", "b-1", "c-1", "a-2", "b-3"];
+
+        for object_id in object_ids.iter() {
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    Object::make(object_id, HashMap::new()),
+                    vec![],
+                )
+                .unwrap();
+        }
+
+        let scan = |range: &Range, after: Option<&str>, limit| {
+            match storage
+                .scan_keyspace_objects(
+                    keyspace_name.to_owned(),
+                    range,
+                    after.map(str::to_owned),
+                    limit,
+                )
+                .unwrap()
+            {
+                KeyspaceObjectsFound(objects, next_cursor) => {
+                    let object_ids: Vec<String> = objects
+                        .iter()
+                        .map(|object| object.metadata["id"].to_owned())
+                        .collect();
+                    (object_ids, next_cursor)
+                }
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+        };
+        let ids = |object_ids: &[&str]| -> Vec<String> {
+            object_ids.iter().map(|id| id.to_string()).collect()
+        };
+
+        let everything = Range::default();
+
+        assert_eq!(
+            scan(&everything, None, 4),
+            (ids(&["a-1", "a-2", "b-1", "b-2"]), Some("b-2".to_owned()))
+        );
+        assert_eq!(
+            scan(&everything, Some("b-2"), 4),
+            (ids(&["b-3", "c-1"]), None)
+        );
+
+        let prefix = Range {
+            prefix: Some("b-".to_owned()),
+            ..Range::default()
+        };
+
+        assert_eq!(
+            scan(&prefix, None, 2),
+            (ids(&["b-1", "b-2"]), Some("b-2".to_owned()))
+        );
+        assert_eq!(scan(&prefix, Some("b-2"), 2), (ids(&["b-3"]), None));
+
+        let reverse_prefix = Range {
+            reverse: true,
+            ..prefix.clone()
+        };
+
+        assert_eq!(
+            scan(&reverse_prefix, None, 2),
+            (ids(&["b-3", "b-2"]), Some("b-2".to_owned()))
+        );
+        assert_eq!(
+            scan(&reverse_prefix, Some("b-2"), 2),
+            (ids(&["b-1"]), None)
+        );
+
+        let start_end = Range {
+            start: Some("a-2".to_owned()),
+            end: Some("b-3".to_owned()),
+            ..Range::default()
+        };
+
+        assert_eq!(
+            scan(&start_end, None, 10),
+            (ids(&["a-2", "b-1", "b-2"]), None)
+        );
+        assert_eq!(
+            scan(
+                &Range {
+                    reverse: true,
+                    ..start_end.clone()
+                },
+                None,
+                10
+            ),
+            (ids(&["b-2", "b-1", "a-2"]), None)
+        );
+
+        assert_eq!(storage.log.len(), 7);
+        assert_eq!(
+            storage
+                .scan_keyspace_objects(
+                    "places".to_owned(),
+                    &everything,
+                    None,
+                    2
+                )
+                .unwrap(),
+            KeyspaceNotFound("places".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        // Object history:
+        // 1. is only kept for keyspaces with a history retention
+        // 2. records writes and deletes with their sequences and timestamps
+        // 3. serves reads as of a sequence or a timestamp
+        // 4. is pruned of revisions older than the retention
+
+        let mut storage = Storage::new();
+        let mut keyspace = Keyspace::make("people");
+        keyspace.set_settings(&KeyspaceSettings {
+            history_retention: Some(60),
+            ..KeyspaceSettings::default()
+        });
+
+        storage.create_or_update_keyspace(keyspace).unwrap();
+        storage
+            .create_or_update_keyspace(Keyspace::make("places"))
+            .unwrap();
+
+        for &(keyspace_name, ref data) in &[
+            ("people", json!({ "name": "Mary" })),
+            ("people", json!({ "name": "Mary Jane" })),
+            ("places", json!({ "name": "Lisbon" })),
+        ] {
+            let data: ObjectData =
+                serde_json::from_value(data.to_owned()).unwrap();
+
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    Object::make("1", data),
+                    vec![],
+                )
+                .unwrap();
+        }
+
+        storage
+            .delete_keyspace_object("people".to_owned(), "1".to_owned(), vec![])
+            .unwrap();
+
+        let revisions = match storage
+            .read_keyspace_object_history("people".to_owned(), "1".to_owned())
+            .unwrap()
+        {
+            KeyspaceObjectHistoryFound(revisions) => revisions,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+
+        assert_eq!(
+            revisions
+                .iter()
+                .map(|revision| (revision.sequence, revision.object.is_some()))
+                .collect::<Vec<(u64, bool)>>(),
+            vec![(3, true), (4, true), (6, false)]
+        );
+        assert_eq!(
+            storage
+                .read_keyspace_object_history(
+                    "places".to_owned(),
+                    "1".to_owned()
+                )
+                .unwrap(),
+            KeyspaceObjectNotFound("1".to_owned())
+        );
+
+        let read_as_of = |as_of: AsOf| match storage
+            .read_keyspace_object_as_of(
+                "people".to_owned(),
+                "1".to_owned(),
+                as_of,
+            )
+            .unwrap()
+        {
+            KeyspaceObjectFound(object) => Some(json!(object.data["name"])),
+            KeyspaceObjectNotFound(_object_id) => None,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+
+        assert_eq!(read_as_of(AsOf::Sequence(2)), None);
+        assert_eq!(read_as_of(AsOf::Sequence(3)), Some(json!("Mary")));
+        assert_eq!(read_as_of(AsOf::Sequence(5)), Some(json!("Mary Jane")));
+        assert_eq!(read_as_of(AsOf::Sequence(6)), None);
+        assert_eq!(
+            read_as_of(AsOf::Timestamp(revisions[0].timestamp - 1)),
+            None
+        );
+        assert_eq!(read_as_of(AsOf::Timestamp(now_millis())), None);
+
+        storage.prune_history(now_millis() + 120_000);
+
+        assert_eq!(
+            storage
+                .read_keyspace_object_history(
+                    "people".to_owned(),
+                    "1".to_owned()
+                )
+                .unwrap(),
+            KeyspaceObjectNotFound("1".to_owned())
+        );
+
+        // Retentions that can't be turned into milliseconds are refused.
+        assert!(
+            serde_json::from_value::<KeyspaceSettings>(json!({
+                "history_retention": 18446744073709551615u64
+            })).is_err()
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        // Subscribing to changes:
+        // 1. returns the log entries changing the keyspace after the given
+        //    sequence
+        // 2. publishes the ones journaled afterwards to subscribers
+        // 3. drops subscribers that are gone or too far behind
+        // 4. fails for sequences covered by the latest snapshot
+        // 5. leaves out writes that don't change anything
+        // 6. only sends the writes of transactions to the keyspace
+        //    subscribed to
+        // 7. returns no entries for sequences past the latest one
+
+        let mut storage = Storage::new();
+
+        storage
+            .create_or_update_keyspace(Keyspace::make("people"))
+            .unwrap();
+        storage
+            .create_or_update_keyspace(Keyspace::make("places"))
+            .unwrap();
+
+        let subscription = storage.subscribe("places", Some(0)).unwrap();
+
+        assert_eq!(
+            subscription
+                .entries
+                .iter()
+                .map(|entry| entry.sequence)
+                .collect::<Vec<u64>>(),
+            vec![2]
+        );
+
+        storage.truncate_keyspace("people".to_owned()).unwrap();
+        drop(storage.subscribe("people", None).unwrap());
+        storage.delete_keyspace("places".to_owned()).unwrap();
+        storage.truncate_keyspace("people".to_owned()).unwrap();
+
+        let entries: Vec<Entry> =
+            subscription.receiver.take(1).wait().map(Result::unwrap).collect();
+
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.sequence)
+                .collect::<Vec<u64>>(),
+            vec![4]
+        );
+        assert_eq!(storage.subscribers.len(), 1);
+
+        let subscription = storage.subscribe("people", None).unwrap();
+
+        for _ in 0..SUBSCRIPTION_CAPACITY + 2 {
+            storage.truncate_keyspace("people".to_owned()).unwrap();
+        }
+
+        // Only the subscriber to places, which hasn't changed since, is left.
+        assert_eq!(storage.subscribers.len(), 1);
+        assert!(
+            subscription.receiver.wait().count() <= SUBSCRIPTION_CAPACITY + 1
+        );
+
+        let snapshot = storage.begin_snapshot().unwrap().unwrap();
+        storage.finish_snapshot(&snapshot).unwrap();
+        storage.truncate_keyspace("people".to_owned()).unwrap();
+
+        assert_eq!(storage.log.len(), 1);
+
+        match storage.subscribe("people", Some(snapshot.sequence - 1)) {
+            Err(Error::SequenceNotRetained(sequence)) => {
+                assert_eq!(sequence, snapshot.sequence)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let subscription =
+            storage.subscribe("people", Some(snapshot.sequence)).unwrap();
+
+        assert_eq!(subscription.entries.len(), 1);
+
+        storage
+            .create_or_update_keyspace(Keyspace::make("places"))
+            .unwrap();
+
+        let subscription = storage.subscribe("people", None).unwrap();
+        let sequence = storage.sequence();
+        let person = TransactionOperation::CreateOrUpdate {
+            keyspace_name: "people".to_owned(),
+            object: Object::make("1", HashMap::new()),
+            preconditions: vec![],
+        };
+        let place = TransactionOperation::CreateOrUpdate {
+            keyspace_name: "places".to_owned(),
+            object: Object::make("1", HashMap::new()),
+            preconditions: vec![],
+        };
+
+        assert_eq!(
+            storage
+                .create_or_update_keyspace_object(
+                    "people".to_owned(),
+                    Object::make("1", HashMap::new()),
+                    vec![Exists],
+                )
+                .unwrap(),
+            KeyspaceObjectPreconditionFailed("1".to_owned())
+        );
+        assert_eq!(
+            storage
+                .commit_transaction(vec![TransactionOperation::Delete {
+                    keyspace_name: "people".to_owned(),
+                    object_id: "1".to_owned(),
+                    preconditions: vec![Exists],
+                }])
+                .unwrap(),
+            TransactionAborted(
+                0,
+                Box::new(KeyspaceObjectPreconditionFailed("1".to_owned()))
+            )
+        );
+        storage
+            .commit_transaction(vec![person.clone(), place.clone()])
+            .unwrap();
+
+        assert_eq!(storage.sequence(), sequence + 1);
+        assert_eq!(
+            storage
+                .subscribe("places", Some(sequence))
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|entry| entry.operation)
+                .collect::<Vec<Operation>>(),
+            vec![Transaction {
+                operations: vec![place],
+            }]
+        );
+        assert!(
+            storage
+                .subscribe("people", Some(18_446_744_073_709_551_615))
+                .unwrap()
+                .entries
+                .is_empty()
+        );
+
+        drop(storage);
+
+        assert_eq!(
+            subscription
+                .receiver
+                .wait()
+                .map(|entry| entry.unwrap().operation)
+                .collect::<Vec<Operation>>(),
+            vec![Transaction {
+                operations: vec![person],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_open() {
+        // Reopening storage:
+        // 1. replays journaled mutations into keyspaces
+        // 2. round-trips arbitrary JSON object data
+        // 3. resumes the sequence where it left off
+        // 4. replays operations with their original timestamps
+        // 5. fails if a journaled operation can't be applied again
+
+        let directory = env::temp_dir()
+            .join(format!("noronha-storage-{}", make_id_string()));
+        let wal_directory = directory.join("wal");
+        let snapshot_directory = directory.join("snapshots");
+        let engine_directory = directory.join("objects");
+        let keyspace_name = "people";
+        let mut keyspace = Keyspace::make(keyspace_name);
+        let object_id = "1";
+        let object_data: ObjectData = serde_json::from_value(json!({
+            "name": "John Doe",
+            "age": 42,
+            "height": 1.82,
+            "admin": false,
+            "nickname": null,
+            "tags": ["a", 1, [true]],
+            "address": { "city": "Berlin", "coordinates": [52.52, 13.40] }
+        })).unwrap();
+        let object = Object::make(object_id, object_data);
+
+        {
+            let mut storage = Storage::open(
+                &wal_directory,
+                &snapshot_directory,
+                &engine_directory,
+                FsyncPolicy::Always,
+                Engine::Memory,
+                0,
+                0,
+            ).unwrap();
+
+            storage.create_or_update_keyspace(keyspace.clone()).unwrap();
+            keyspace.set_created_at(storage.timestamp);
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    object.clone(),
+                    vec![],
+                )
+                .unwrap();
+            keyspace.set_updated_at(storage.timestamp);
             storage.read_keyspace(keyspace_name.to_owned()).unwrap();
+        }
+
+        let mut keyspace = keyspace.clone();
+        let mut object = object.clone();
+        object.set_version(2);
+        keyspace
+            .objects
+            .insert(object_id, object.clone())
+            .unwrap();
+        let mut expected_keyspaces: Bag<Keyspace> = HashMap::new();
+        expected_keyspaces
+            .insert(keyspace_name.to_owned(), keyspace.to_owned());
+
+        let storage = Storage::open(
+            &wal_directory,
+            &snapshot_directory,
+            &engine_directory,
+            FsyncPolicy::Always,
+            Engine::Memory,
+            0,
+            0,
+        ).unwrap();
 
-        assert_eq!(outcome, expected_outcome);
-        assert_eq!(storage.log, expected_log);
         assert_eq!(storage.keyspaces, expected_keyspaces);
+        assert_eq!(storage.log.len(), 2);
+        assert_eq!(storage.sequence(), 2);
 
-        // Keyspace object read:
-        // 1. has successful outcome
-        // 2. appends operation to log
-        // 3. doesn't change keyspaces
+        let mut object_without_id = object.clone();
+        object_without_id.metadata.remove("id");
 
-        let read_keyspace_object = ReadKeyspaceObject {
-            keyspace_name: keyspace_name.to_owned(),
-            object_id: object_id.to_owned(),
+        {
+            let (mut wal, _entries) =
+                WriteAheadLog::open(&wal_directory, FsyncPolicy::Always)
+                    .unwrap();
+            wal.append(&Entry {
+                sequence: 3,
+                timestamp: now_millis(),
+                operation: CreateOrUpdateKeyspaceObject {
+                    keyspace_name: keyspace_name.to_owned(),
+                    object: object_without_id,
+                    preconditions: vec![],
+                },
+            }).unwrap();
+        }
+
+        assert!(
+            Storage::open(
+                &wal_directory,
+                &snapshot_directory,
+                &engine_directory,
+                FsyncPolicy::Always,
+                Engine::Memory,
+                0,
+                0,
+            ).is_err()
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // Reopening storage after a snapshot:
+        // 1. restores keyspaces from the snapshot and the log tail
+        // 2. only replays operations journaled after the snapshot
+
+        let directory = env::temp_dir()
+            .join(format!("noronha-storage-{}", make_id_string()));
+        let wal_directory = directory.join("wal");
+        let snapshot_directory = directory.join("snapshots");
+        let engine_directory = directory.join("objects");
+        let open = || {
+            Storage::open(
+                &wal_directory,
+                &snapshot_directory,
+                &engine_directory,
+                FsyncPolicy::Always,
+                Engine::Memory,
+                0,
+                0,
+            ).unwrap()
         };
 
-        let expected_outcome = KeyspaceObjectFound(object.clone());
-        let expected_log = vec![
-            create_or_update_keyspace.clone(),
-            create_or_update_keyspace_object.clone(),
-            read_keyspace.clone(),
-            read_keyspace.clone(),
-            read_keyspace_object.clone(),
-        ];
+        {
+            let mut storage = open();
 
-        let outcome = storage.apply(read_keyspace_object.clone()).unwrap();
+            storage
+                .create_or_update_keyspace(Keyspace::make("people"))
+                .unwrap();
+            storage
+                .create_or_update_keyspace(Keyspace::make("places"))
+                .unwrap();
 
-        assert_eq!(outcome, expected_outcome);
-        assert_eq!(storage.log, expected_log);
-        assert_eq!(storage.keyspaces, expected_keyspaces);
+            let snapshot = storage.begin_snapshot().unwrap().unwrap();
+            assert_eq!(snapshot.sequence, 2);
+            snapshot.write(&snapshot_directory).unwrap();
+            storage.finish_snapshot(&snapshot).unwrap();
+
+            assert_eq!(storage.begin_snapshot().unwrap(), None);
+
+            storage
+                .create_or_update_keyspace(Keyspace::make("things"))
+                .unwrap();
+        }
+
+        let storage = open();
+        let mut keyspace_names: Vec<&String> =
+            storage.keyspaces.keys().collect();
+        keyspace_names.sort();
+
+        assert_eq!(keyspace_names, vec!["people", "places", "things"]);
+        assert_eq!(storage.log.len(), 1);
+        assert_eq!(storage.sequence(), 3);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_disk_engine() {
+        // Keyspaces with the disk engine:
+        // 1. keep objects in segments flushed on snapshots, and writes since
+        //    then in the snapshot and the log
+        // 2. read, scan and delete objects across segments and writes
+        // 3. compact segments, and remove the ones no longer used
+
+        let directory = env::temp_dir()
+            .join(format!("noronha-storage-{}", make_id_string()));
+        let wal_directory = directory.join("wal");
+        let snapshot_directory = directory.join("snapshots");
+        let engine_directory = directory.join("objects");
+        let open = || {
+            Storage::open(
+                &wal_directory,
+                &snapshot_directory,
+                &engine_directory,
+                FsyncPolicy::Always,
+                Engine::Disk,
+                0,
+                0,
+            ).unwrap()
+        };
+        let snapshot = |storage: &mut Storage| {
+            let snapshot = storage.begin_snapshot().unwrap().unwrap();
+            snapshot.write(&snapshot_directory).unwrap();
+            storage.finish_snapshot(&snapshot).unwrap();
+        };
+        let segment_count = || fs::read_dir(&engine_directory).unwrap().count();
+        let keyspace_name = "people";
+        let scan = |storage: &Storage, reverse| {
+            let range = Range {
+                reverse,
+                ..Range::default()
+            };
+
+            match storage
+                .scan_keyspace_objects(
+                    keyspace_name.to_owned(),
+                    &range,
+                    None,
+                    10,
+                )
+                .unwrap()
+            {
+                KeyspaceObjectsFound(objects, _) => objects
+                    .iter()
+                    .map(|object| object.metadata["id"].to_owned())
+                    .collect::<Vec<String>>(),
+                outcome => panic!("unexpected outcome {:?}", outcome),
+            }
+        };
+        let write = |storage: &mut Storage, object_id| {
+            storage
+                .create_or_update_keyspace_object(
+                    keyspace_name.to_owned(),
+                    Object::make(object_id, HashMap::new()),
+                    vec![],
+                )
+                .unwrap();
+        };
+
+        {
+            let mut storage = open();
+
+            storage
+                .create_or_update_keyspace(Keyspace::make(keyspace_name))
+                .unwrap();
+
+            for object_id in ["1", "2", "3"].iter() {
+                write(&mut storage, object_id);
+            }
+
+            snapshot(&mut storage);
+            assert_eq!(segment_count(), 1);
+
+            storage
+                .delete_keyspace_object(
+                    keyspace_name.to_owned(),
+                    "2".to_owned(),
+                    vec![],
+                )
+                .unwrap();
+            write(&mut storage, "3");
+            write(&mut storage, "4");
+
+            assert_eq!(scan(&storage, false), vec!["1", "3", "4"]);
+            assert_eq!(scan(&storage, true), vec!["4", "3", "1"]);
+
+            snapshot(&mut storage);
+            assert_eq!(segment_count(), 2);
+
+            write(&mut storage, "5");
+        }
+
+        let mut storage = open();
+
+        assert_eq!(scan(&storage, false), vec!["1", "3", "4", "5"]);
+        assert_eq!(
+            storage
+                .read_keyspace_object(keyspace_name.to_owned(), "2".to_owned())
+                .unwrap(),
+            KeyspaceObjectNotFound("2".to_owned())
+        );
+
+        match storage
+            .read_keyspace_object(keyspace_name.to_owned(), "3".to_owned())
+            .unwrap()
+        {
+            KeyspaceObjectFound(object) => assert_eq!(object.version(), 6),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        storage
+            .delete_keyspace_object(
+                keyspace_name.to_owned(),
+                "1".to_owned(),
+                vec![],
+            )
+            .unwrap();
+        snapshot(&mut storage);
+        write(&mut storage, "6");
+        snapshot(&mut storage);
+        assert_eq!(segment_count(), 4);
+
+        let (compacted_keyspace_name, mut compaction) =
+            storage.begin_compaction().unwrap();
+        assert_eq!(compacted_keyspace_name, keyspace_name);
+        assert!(storage.begin_compaction().is_none());
+
+        compaction.run(0).unwrap();
+        assert!(storage.finish_compaction(keyspace_name, &compaction));
+        assert!(!storage.finish_compaction(keyspace_name, &compaction));
+        assert!(storage.begin_compaction().is_none());
+
+        assert_eq!(scan(&storage, false), vec!["3", "4", "5", "6"]);
+        assert_eq!(scan(&storage, true), vec!["6", "5", "4", "3"]);
+
+        write(&mut storage, "7");
+        snapshot(&mut storage);
+        assert_eq!(segment_count(), 2);
+
+        storage.truncate_keyspace(keyspace_name.to_owned()).unwrap();
+        snapshot(&mut storage);
+
+        assert_eq!(scan(&storage, false), Vec::<String>::new());
+        assert_eq!(segment_count(), 0);
+
+        fs::remove_dir_all(&directory).unwrap();
     }
 }
@@ -1,5 +1,8 @@
 use im::hashmap::HashMap;
+use im::ordmap::OrdMap;
 
 pub type Bag<T> = HashMap<String, T>;
 
+pub type SortedBag<T> = OrdMap<String, T>;
+
 pub type Metadata = Bag<String>;
@@ -1,3 +1,10 @@
+use std::io::Error as IoError;
+use std::os::raw::c_int;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{self as channel, Receiver, Sender};
+use signal_hook;
 use uuid::Uuid;
 
 pub fn make_id() -> Uuid {
@@ -7,3 +14,24 @@ pub fn make_id() -> Uuid {
 pub fn make_id_string() -> String {
     make_id().to_string()
 }
+
+/// Milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+pub fn notify(
+    signals: &[c_int],
+) -> Result<(Sender<c_int>, Receiver<c_int>), IoError> {
+    let (s, r) = channel::bounded(100);
+    let signals = signal_hook::iterator::Signals::new(signals)?;
+    let sender = s.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            sender.send(signal);
+        }
+    });
+    Ok((s, r))
+}
@@ -0,0 +1,471 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_json;
+
+use storage::Operation;
+
+// Every entry is framed as:
+//
+//   [payload length: u32 LE][payload CRC32: u32 LE][payload: JSON]
+//
+// so that a torn write at the end of the file can be detected and discarded
+// on replay.
+const ENTRY_HEADER_LENGTH: u64 = 8;
+
+static SEGMENT_EXTENSION: &str = "log";
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    CorruptedEntry(PathBuf, u64),
+    InvalidSegmentName(PathBuf),
+    InvalidFsyncPolicy(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::IoError(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::JsonError(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    Interval(Duration),
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn make(name: &str, interval: u64) -> Result<Self, Error> {
+        match name {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => {
+                Ok(FsyncPolicy::Interval(Duration::from_millis(interval)))
+            }
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(Error::InvalidFsyncPolicy(name.to_owned())),
+        }
+    }
+}
+
+/// A journaled operation. `timestamp` is when it was first applied, in
+/// milliseconds since the Unix epoch, so that replaying it has the same
+/// outcome.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    pub sequence: u64,
+    #[serde(default)]
+    pub timestamp: u64,
+    pub operation: Operation,
+}
+
+impl Entry {
+    /// The approximate size of the entry as JSON, in bytes.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map(|entry| entry.len()).unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    directory: PathBuf,
+    fsync_policy: FsyncPolicy,
+    segment: File,
+    segment_first_sequence: u64,
+    last_sync: Instant,
+    // Whether entries were appended to the segment since it was last synced.
+    is_dirty: bool,
+}
+
+impl WriteAheadLog {
+    /// Opens the log in `directory`, creating it if needed, and returns it
+    /// along with every entry found on disk in sequence order.
+    pub fn open(
+        directory: &Path,
+        fsync_policy: FsyncPolicy,
+    ) -> Result<(Self, Vec<Entry>), Error> {
+        fs::create_dir_all(directory)?;
+
+        let segment_paths = segment_paths(directory)?;
+        let mut entries = Vec::new();
+
+        for (i, segment_path) in segment_paths.iter().enumerate() {
+            let is_last_segment = i == segment_paths.len() - 1;
+            let (segment_entries, valid_length) = read_segment(segment_path)?;
+            let segment_length = fs::metadata(segment_path)?.len();
+
+            if valid_length < segment_length {
+                if !is_last_segment {
+                    return Err(Error::CorruptedEntry(
+                        segment_path.to_owned(),
+                        valid_length,
+                    ));
+                }
+
+                warn!(
+                    "Discarding torn entry at the end of {:?} (offset {})",
+                    segment_path, valid_length
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(segment_path)?
+                    .set_len(valid_length)?;
+            }
+
+            entries.extend(segment_entries);
+        }
+
+        let segment_first_sequence = match segment_paths.last() {
+            Some(segment_path) => segment_first_sequence(segment_path)?,
+            None => 0,
+        };
+        let segment_path =
+            make_segment_path(directory, segment_first_sequence);
+
+        let wal = Self {
+            directory: directory.to_owned(),
+            fsync_policy,
+            segment: open_segment(&segment_path)?,
+            segment_first_sequence,
+            last_sync: Instant::now(),
+            is_dirty: false,
+        };
+
+        Ok((wal, entries))
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> Result<(), Error> {
+        let payload = serde_json::to_vec(entry)?;
+        let mut record =
+            Vec::with_capacity(ENTRY_HEADER_LENGTH as usize + payload.len());
+
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.write_u32::<LittleEndian>(crc32::checksum_ieee(&payload))?;
+        record.extend_from_slice(&payload);
+
+        self.segment.write_all(&record)?;
+        self.sync()
+    }
+
+    /// Closes the current segment and starts a new one whose first entry will
+    /// have `first_sequence`.
+    pub fn rotate(&mut self, first_sequence: u64) -> Result<(), Error> {
+        if first_sequence == self.segment_first_sequence {
+            return Ok(());
+        }
+
+        self.segment.sync_data()?;
+        self.segment =
+            open_segment(&make_segment_path(&self.directory, first_sequence))?;
+        self.segment_first_sequence = first_sequence;
+        self.last_sync = Instant::now();
+        self.is_dirty = false;
+
+        Ok(())
+    }
+
+    /// Removes every segment whose entries all have a sequence lower than or
+    /// equal to `sequence`. The current segment is never removed.
+    pub fn truncate(&mut self, sequence: u64) -> Result<(), Error> {
+        let segment_paths = segment_paths(&self.directory)?;
+
+        for (segment_path, next_segment_path) in
+            segment_paths.iter().zip(segment_paths.iter().skip(1))
+        {
+            if segment_first_sequence(next_segment_path)? <= sequence + 1 {
+                debug!("Removing write-ahead log segment {:?}", segment_path);
+                fs::remove_file(segment_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the entries appended since the last sync. With the interval
+    /// policy appends only sync once the interval has passed, so this has to
+    /// be called on that interval for the last entries before the log goes
+    /// idle to be synced too.
+    pub fn sync_pending(&mut self) -> Result<(), Error> {
+        if self.is_dirty {
+            self.segment.sync_data()?;
+            self.last_sync = Instant::now();
+            self.is_dirty = false;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.is_dirty = true;
+
+        match self.fsync_policy {
+            FsyncPolicy::Always => self.sync_pending()?,
+            FsyncPolicy::Interval(interval) => {
+                if self.last_sync.elapsed() >= interval {
+                    self.sync_pending()?;
+                }
+            }
+            FsyncPolicy::Never => (),
+        }
+
+        Ok(())
+    }
+}
+
+fn make_segment_path(directory: &Path, first_sequence: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION))
+}
+
+fn segment_first_sequence(path: &Path) -> Result<u64, Error> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .ok_or_else(|| Error::InvalidSegmentName(path.to_owned()))
+}
+
+fn open_segment(path: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+// Segment file names are zero-padded first sequence numbers, so sorting them
+// lexicographically sorts them by sequence.
+fn segment_paths(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION)
+        })
+        .collect();
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+// Returns the entries in the segment at `path` and the length of its valid
+// prefix. Reading stops at the first incomplete or corrupted entry, and at
+// lengths longer than what's left of the segment, which can't be trusted to
+// allocate a payload.
+fn read_segment(path: &Path) -> Result<(Vec<Entry>, u64), Error> {
+    let file = File::open(path)?;
+    let segment_length = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    let mut valid_length = 0;
+
+    loop {
+        let payload_length = match reader.read_u32::<LittleEndian>() {
+            Ok(payload_length) => payload_length,
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(Error::IoError(error)),
+        };
+        let checksum = match reader.read_u32::<LittleEndian>() {
+            Ok(checksum) => checksum,
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(Error::IoError(error)),
+        };
+
+        if ENTRY_HEADER_LENGTH + u64::from(payload_length)
+            > segment_length - valid_length
+        {
+            break;
+        }
+
+        let mut payload = vec![0; payload_length as usize];
+        match reader.read_exact(&mut payload) {
+            Ok(()) => (),
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(Error::IoError(error)),
+        }
+
+        if crc32::checksum_ieee(&payload) != checksum {
+            break;
+        }
+
+        match serde_json::from_slice::<Entry>(&payload) {
+            Ok(entry) => entries.push(entry),
+            Err(_error) => break,
+        }
+
+        valid_length += ENTRY_HEADER_LENGTH + payload_length as u64;
+    }
+
+    Ok((entries, valid_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use keyspace::Keyspace;
+    use storage::Operation::*;
+    use utils::make_id_string;
+    use wal::*;
+
+    fn make_directory() -> PathBuf {
+        env::temp_dir().join(format!("noronha-wal-{}", make_id_string()))
+    }
+
+    fn make_entry(sequence: u64) -> Entry {
+        Entry {
+            sequence,
+            timestamp: sequence,
+            operation: CreateOrUpdateKeyspace {
+                keyspace: Keyspace::make(&format!("keyspace-{}", sequence)),
+            },
+        }
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let directory = make_directory();
+        let entries = vec![make_entry(1), make_entry(2), make_entry(3)];
+
+        {
+            let (mut wal, replayed) =
+                WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+            assert_eq!(replayed, Vec::new());
+
+            for entry in &entries {
+                wal.append(entry).unwrap();
+            }
+        }
+
+        let (_wal, replayed) =
+            WriteAheadLog::open(&directory, FsyncPolicy::Never).unwrap();
+        assert_eq!(replayed, entries);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_torn_entry_is_discarded() {
+        let directory = make_directory();
+        let entries = vec![make_entry(1), make_entry(2)];
+
+        {
+            let (mut wal, _replayed) =
+                WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+
+            for entry in &entries {
+                wal.append(entry).unwrap();
+            }
+        }
+
+        // Simulate a crash halfway through appending a third entry.
+        let segment_path = make_segment_path(&directory, 0);
+        let valid_length = fs::metadata(&segment_path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&segment_path)
+            .unwrap()
+            .write_all(&[42, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let (mut wal, replayed) =
+            WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+        assert_eq!(replayed, entries);
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), valid_length);
+
+        wal.append(&make_entry(3)).unwrap();
+
+        let (_wal, replayed) =
+            WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+        assert_eq!(replayed.len(), 3);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_length_is_discarded() {
+        let directory = make_directory();
+        let entries = vec![make_entry(1), make_entry(2)];
+
+        {
+            let (mut wal, _replayed) =
+                WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+            for entry in &entries {
+                wal.append(entry).unwrap();
+            }
+        }
+
+        // A length header way past the end of the segment.
+        let segment_path = make_segment_path(&directory, 0);
+        let valid_length = fs::metadata(&segment_path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&segment_path)
+            .unwrap()
+            .write_all(&[255, 255, 255, 255, 0, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let (_wal, replayed) =
+            WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+        assert_eq!(replayed, entries);
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), valid_length);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_and_truncate() {
+        let directory = make_directory();
+
+        {
+            let (mut wal, _replayed) =
+                WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+
+            wal.append(&make_entry(1)).unwrap();
+            wal.append(&make_entry(2)).unwrap();
+            wal.rotate(3).unwrap();
+            wal.append(&make_entry(3)).unwrap();
+            wal.rotate(4).unwrap();
+            wal.append(&make_entry(4)).unwrap();
+
+            // Entries 1 and 2 are only in the first segment, entry 3 is in
+            // the second one which can't go until entry 3 is covered.
+            wal.truncate(2).unwrap();
+        }
+
+        let (_wal, replayed) =
+            WriteAheadLog::open(&directory, FsyncPolicy::Always).unwrap();
+        let replayed_sequences: Vec<u64> =
+            replayed.iter().map(|entry| entry.sequence).collect();
+        assert_eq!(replayed_sequences, vec![3, 4]);
+        assert_eq!(
+            segment_paths(&directory).unwrap(),
+            vec![
+                make_segment_path(&directory, 3),
+                make_segment_path(&directory, 4),
+            ]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_policy_make() {
+        assert_eq!(
+            FsyncPolicy::make("always", 0).unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!(FsyncPolicy::make("never", 0).unwrap(), FsyncPolicy::Never);
+        assert_eq!(
+            FsyncPolicy::make("interval", 1000).unwrap(),
+            FsyncPolicy::Interval(Duration::from_millis(1000))
+        );
+        assert!(FsyncPolicy::make("sometimes", 0).is_err());
+    }
+}